@@ -1,8 +1,12 @@
-use std::path::PathBuf;
+use std::{collections::BTreeMap, path::PathBuf};
 
 use garde::Validate;
+use k8s_openapi::{
+    api::core::v1::{ConfigMapKeySelector, ResourceRequirements},
+    apimachinery::pkg::apis::meta::v1::Condition,
+};
 use kube::CustomResource;
-use schemars::JsonSchema;
+use schemars::{JsonSchema, Schema, SchemaGenerator, json_schema};
 use serde::{Deserialize, Serialize};
 
 #[derive(CustomResource, Deserialize, Serialize, Clone, Debug, Validate, JsonSchema)]
@@ -14,6 +18,10 @@ pub struct ComputerSpec {
     #[garde(skip)]
     #[serde(flatten)]
     pub state: ComputerInternalState,
+    /// Take the computer down gracefully, giving it this many seconds to finish its work
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub drain_secs: Option<u32>,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
@@ -22,16 +30,65 @@ pub struct ComputerStatus {
     pub state: ComputerInternalState,
     pub online: bool,
     pub last_heartbeat_unix_sec: Option<i64>,
+    /// Generation of the spec the computer last reported having applied
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub observed_generation: Option<i64>,
+    /// Version of the client script the computer last reported running
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// Free-form data reported by the computer, e.g. fuel level or the running script
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(schema_with = "preserve_unknown_fields")]
+    pub metrics: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conditions: Vec<Condition>,
 }
 
-#[derive(
-    Deserialize, Serialize, Clone, Debug, PartialEq, Eq, Hash, Validate, Default, JsonSchema,
-)]
+impl ComputerStatus {
+    /// Whether the computer has acknowledged a shutdown and been marked `Ready=False` with reason `Drained`
+    pub fn is_drained(&self) -> bool {
+        self.conditions.iter().any(|condition| {
+            condition.type_ == "Ready"
+                && condition.status == "False"
+                && condition.reason == "Drained"
+        })
+    }
+}
+
+fn preserve_unknown_fields(_: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+        "type": "object",
+        "nullable": true,
+        "x-kubernetes-preserve-unknown-fields": true,
+    })
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Validate, Default, JsonSchema)]
 pub struct ComputerInternalState {
     #[garde(skip)]
     pub label: Option<String>,
     #[garde(skip)]
     pub script: Option<String>,
+    /// Take the script from a ConfigMap key instead, overriding `script` if both are set
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub script_ref: Option<ConfigMapKeySelector>,
+    /// Client script version the computer should be running, for coordinating fleet upgrades
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// Whether the computer should be running; when unset, it's woken whenever it falls out of sync
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub desired_power: Option<PowerState>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, JsonSchema)]
+pub enum PowerState {
+    /// Wake the computer whenever it's offline
+    On,
+    /// Shut the computer down whenever it's online, and never wake it
+    Off,
 }
 
 #[derive(CustomResource, Deserialize, Serialize, Clone, Debug, Validate, JsonSchema)]
@@ -41,9 +98,55 @@ pub struct ComputerInternalState {
     kind = "ComputerCluster",
     namespaced
 )]
+#[kube(status = "ComputerClusterStatus")]
 pub struct ComputerClusterSpec {
+    /// Shorthand for a single gateway, named after the cluster
     #[garde(skip)]
     pub gateway: Option<ComputerGatewaySpec>,
+    /// Further gateways with their own route tables, e.g. separate public and internal ones.
+    /// Each is a ComputerGateway named `<cluster>-<name>`.
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub gateways: Vec<NamedGatewaySpec>,
+    /// Fraction of computers that may be offline before `AllComputersHealthy` turns `False` (default 0)
+    #[garde(range(min = 0.0, max = 1.0))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_offline_fraction: Option<f64>,
+    /// Seconds since its last heartbeat after which a computer counts as offline (default 300)
+    #[garde(range(min = 1))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_heartbeat_age_secs: Option<u64>,
+    /// Secret in the controller namespace with a certificate for the cluster's gateways' own
+    /// hostnames, `<gateway>.<namespace>.<web gateway hostname>`, which are then served over HTTPS
+    /// instead of plain HTTP. A gateway setting its own `tls_secret_name` keeps it.
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_secret_name: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+pub struct NamedGatewaySpec {
+    /// Tells the gateway apart from the cluster's others; must be unique within the cluster
+    pub name: String,
+    #[serde(flatten)]
+    pub spec: ComputerGatewaySpec,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+pub struct ComputerClusterStatus {
+    /// Whether the cluster has a gateway and every one of its gateway Deployments has at least one
+    /// available replica
+    pub gateway_ready: bool,
+    pub computers_online: u32,
+    pub computers_total: u32,
+    /// Computers reporting the client script version their spec asks for, or any version if it asks for none
+    #[serde(default)]
+    pub computers_at_desired_version: u32,
+    /// Reconciles that have failed in a row, capped at a bound; reset by a successful reconcile
+    #[serde(default)]
+    pub reconcile_failures: u32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conditions: Vec<Condition>,
 }
 
 #[derive(CustomResource, Deserialize, Serialize, Clone, Debug, Validate, JsonSchema)]
@@ -53,25 +156,99 @@ pub struct ComputerClusterSpec {
     kind = "ComputerGateway",
     namespaced
 )]
+#[kube(status = "ComputerGatewayStatus")]
 pub struct ComputerGatewaySpec {
     #[garde(skip)]
     pub routes: Vec<HttpOverRednetRoute>,
     #[garde(skip)]
     pub links: Vec<ComputerGatewayLink>,
+    /// Resources for the gateway container, defaulting to a 50m CPU / 64Mi memory request
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resources: Option<ResourceRequirements>,
+    /// Backend for requests that match no route, which otherwise get a 404
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_backend: Option<RednetBackend>,
+    /// Secret in the controller namespace with a certificate for the gateway's own hostname,
+    /// `<gateway>.<namespace>.<web gateway hostname>`, served over HTTPS on 443 if set and plain
+    /// HTTP on 80 otherwise
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_secret_name: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+pub struct ComputerGatewayStatus {
+    /// Listeners linked to the gateway when it last reported
+    pub connected_listeners: u32,
+    pub last_report_unix_sec: Option<i64>,
+    /// Reconciles that have failed in a row, capped at a bound; reset by a successful reconcile
+    #[serde(default)]
+    pub reconcile_failures: u32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conditions: Vec<Condition>,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug, Validate, JsonSchema)]
 pub struct ComputerGatewayLink {
     #[garde(skip)]
-    host_id: String,
+    pub host_id: String,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, Hash, Validate, JsonSchema)]
 pub struct HttpOverRednetRoute {
+    /// Shorthand for a single entry in `backends`; set exactly one of the two
     #[garde(skip)]
-    pub backend: RednetBackend,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend: Option<RednetBackend>,
+    /// Backends to split requests between in proportion to their weights, e.g. to canary a new version
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub backends: Vec<WeightedRednetBackend>,
     #[garde(skip)]
     pub prefix: PathBuf,
+    /// Remove `prefix` from the path sent to the backend, so `/api/x` on an `/api` route arrives as `/x`
+    #[garde(skip)]
+    #[serde(default)]
+    pub strip_prefix: bool,
+    /// Send the backend this path instead, with `{rest}` replaced by the part of the request path
+    /// after `prefix`, e.g. `/v1/{rest}`; can't be combined with `strip_prefix`
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rewrite_path: Option<String>,
+    /// Only match requests for this `Host`, either exact or a `*.` wildcard covering any subdomain
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub request_headers_set: BTreeMap<String, String>,
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub request_headers_remove: Vec<String>,
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub response_headers_set: BTreeMap<String, String>,
+    #[garde(skip)]
+    #[serde(default)]
+    pub websocket: bool,
+    /// Cache successful GET responses at the gateway for this many seconds
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_ttl_secs: Option<u64>,
+    /// Also send a copy of each request to this backend, discarding its response
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mirror: Option<RednetBackend>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, Hash, Validate, JsonSchema)]
+pub struct WeightedRednetBackend {
+    #[garde(skip)]
+    pub backend: RednetBackend,
+    #[garde(skip)]
+    pub weight: u32,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, Hash, Validate, JsonSchema)]
@@ -93,9 +270,27 @@ pub enum RednetBackend {
         #[garde(skip)]
         host: String,
     },
+    /// Like `Anycast`, but requests with the same `key_header` value always go to the same computer
+    Sharded {
+        #[garde(skip)]
+        protocol: String,
+        #[garde(skip)]
+        key_header: String,
+    },
+    /// Like `Anycast`, but only to computers that advertised every one of `capabilities` when linking
+    Capable {
+        #[garde(skip)]
+        protocol: String,
+        #[garde(skip)]
+        capabilities: Vec<String>,
+    },
 }
 
+/// The rednet config read by the gateway, which parses it with its own copy of these types.
+/// Changes to the shape of routes or backends must be mirrored in the gateway crate.
 #[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, Hash, JsonSchema)]
 pub struct RednetGatewayConfigMapData {
     pub routes: Vec<HttpOverRednetRoute>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_backend: Option<RednetBackend>,
 }
@@ -0,0 +1,158 @@
+use std::{collections::HashSet, fmt::Debug};
+
+use k8s_openapi::{
+    NamespaceResourceScope,
+    api::{
+        apps::v1::Deployment,
+        core::v1::{ConfigMap, Service},
+    },
+};
+use kcr_gateway_networking_k8s_io::v1::httproutes::HTTPRoute;
+use kube::{
+    Client, Resource, ResourceExt,
+    api::{DeleteParams, ListParams},
+};
+use serde::de::DeserializeOwned;
+
+use crate::{
+    Result,
+    api::{ComputerCluster, ComputerGateway},
+    reconcilers::{cluster, gateway, scoped_api},
+};
+
+/// An object whose owning ComputerCluster or ComputerGateway no longer exists
+#[derive(Clone, Debug)]
+pub struct Orphan {
+    pub kind: String,
+    pub namespace: String,
+    pub name: String,
+}
+
+/// Find the objects the controllers created for clusters and gateways that have since disappeared,
+/// deleting them unless `dry_run` is set. Only objects applied by one of the controllers are considered.
+pub async fn collect_garbage(
+    client: &Client,
+    namespace: Option<&str>,
+    dry_run: bool,
+) -> Result<Vec<Orphan>> {
+    let mut live_owners = scoped_api::<ComputerCluster>(client, namespace)
+        .list(&ListParams::default())
+        .await?
+        .into_iter()
+        .filter_map(|cluster| cluster.metadata.uid)
+        .collect::<HashSet<_>>();
+
+    // A gateway whose cluster is gone is itself garbage, and so is everything it owns
+    let gateways = scoped_api::<ComputerGateway>(client, namespace)
+        .list(&ListParams::default())
+        .await?;
+    let live_gateways = gateways
+        .iter()
+        .filter(|gateway| !is_orphaned(*gateway, cluster::MANAGER_NAME, &live_owners))
+        .filter_map(|gateway| gateway.metadata.uid.clone())
+        .collect::<Vec<_>>();
+    live_owners.extend(live_gateways);
+
+    let mut orphans = sweep::<ComputerGateway>(
+        client,
+        namespace,
+        cluster::MANAGER_NAME,
+        &live_owners,
+        dry_run,
+    )
+    .await?;
+    orphans.extend(
+        sweep::<Deployment>(
+            client,
+            namespace,
+            gateway::MANAGER_NAME,
+            &live_owners,
+            dry_run,
+        )
+        .await?,
+    );
+    orphans.extend(
+        sweep::<ConfigMap>(
+            client,
+            namespace,
+            gateway::MANAGER_NAME,
+            &live_owners,
+            dry_run,
+        )
+        .await?,
+    );
+    orphans.extend(
+        sweep::<Service>(
+            client,
+            namespace,
+            gateway::MANAGER_NAME,
+            &live_owners,
+            dry_run,
+        )
+        .await?,
+    );
+    orphans.extend(
+        sweep::<HTTPRoute>(
+            client,
+            namespace,
+            gateway::MANAGER_NAME,
+            &live_owners,
+            dry_run,
+        )
+        .await?,
+    );
+
+    Ok(orphans)
+}
+
+/// List the orphaned objects of one kind that `manager` applied, deleting them unless `dry_run` is set
+async fn sweep<K>(
+    client: &Client,
+    namespace: Option<&str>,
+    manager: &str,
+    live_owners: &HashSet<String>,
+    dry_run: bool,
+) -> Result<Vec<Orphan>>
+where
+    K: Resource<Scope = NamespaceResourceScope> + Clone + DeserializeOwned + Debug,
+    K::DynamicType: Default,
+{
+    let mut orphans = vec![];
+    for object in scoped_api::<K>(client, namespace)
+        .list(&ListParams::default())
+        .await?
+    {
+        if !is_orphaned(&object, manager, live_owners) {
+            continue;
+        }
+
+        let orphan = Orphan {
+            kind: K::kind(&K::DynamicType::default()).to_string(),
+            namespace: object.namespace().unwrap_or_default(),
+            name: object.name_any(),
+        };
+        if !dry_run {
+            scoped_api::<K>(client, Some(&orphan.namespace))
+                .delete(&orphan.name, &DeleteParams::default())
+                .await?;
+        }
+        orphans.push(orphan);
+    }
+
+    Ok(orphans)
+}
+
+/// Whether `manager` applied the object and one of its smcs.dev owners is no longer live
+fn is_orphaned<K: Resource>(object: &K, manager: &str, live_owners: &HashSet<String>) -> bool {
+    let managed = object
+        .meta()
+        .managed_fields
+        .iter()
+        .flatten()
+        .any(|fields| fields.manager.as_deref() == Some(manager));
+
+    managed
+        && object.owner_references().iter().any(|owner| {
+            owner.api_version.starts_with("smcs.dev/") && !live_owners.contains(&owner.uid)
+        })
+}
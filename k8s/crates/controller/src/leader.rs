@@ -0,0 +1,317 @@
+use std::{future::Future, time::Duration};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use k8s_openapi::api::coordination::v1::Lease;
+use kube::{Api, Client, api::PostParams};
+use serde::Serialize;
+use serde_json::json;
+use tokio::time::{Instant, sleep};
+
+use crate::{Error, Result};
+
+/// How long a lease is valid for without being renewed
+const LEASE_DURATION: Duration = Duration::from_secs(15);
+/// How often to attempt to acquire or renew the lease
+const RETRY_PERIOD: Duration = Duration::from_secs(5);
+
+/// Elects a single leader among controller replicas using a `coordination.k8s.io` Lease
+pub struct LeaderElector {
+    leases: Api<Lease>,
+    lease_name: String,
+    identity: String,
+}
+
+impl LeaderElector {
+    pub fn new(client: Client, namespace: &str, lease_name: &str, identity: String) -> Self {
+        Self {
+            leases: Api::namespaced(client, namespace),
+            lease_name: lease_name.to_string(),
+            identity,
+        }
+    }
+
+    /// Wait until this replica holds the lease, then drive `fut` to completion while renewing it.
+    /// Returns [`Error::LeadershipLost`] if the lease can't be renewed before it expires.
+    pub async fn run<F: Future>(&self, fut: F) -> Result<F::Output> {
+        while !self.try_acquire_or_renew().await? {
+            tracing::debug!(
+                "Lease {} held by another replica, standing by",
+                self.lease_name
+            );
+            sleep(RETRY_PERIOD).await;
+        }
+
+        tracing::info!(
+            "Acquired lease {} as {}, starting controller",
+            self.lease_name,
+            self.identity
+        );
+
+        let renew = async {
+            let mut last_renewed = Instant::now();
+            loop {
+                sleep(RETRY_PERIOD).await;
+                match self.try_acquire_or_renew().await {
+                    Ok(true) => last_renewed = Instant::now(),
+                    Ok(false) => return Error::LeadershipLost,
+                    Err(e) => {
+                        tracing::warn!("Failed to renew lease {}: {:?}", self.lease_name, e);
+                        if last_renewed.elapsed() >= LEASE_DURATION {
+                            return Error::LeadershipLost;
+                        }
+                    }
+                }
+            }
+        };
+
+        tokio::select! {
+            out = fut => Ok(out),
+            e = renew => Err(e),
+        }
+    }
+
+    /// Attempt to take (or keep) the lease, returning whether this replica holds it afterwards
+    pub async fn try_acquire_or_renew(&self) -> Result<bool> {
+        let now = Utc::now();
+
+        let Some(existing) = self.leases.get_opt(&self.lease_name).await? else {
+            let lease = self.lease(now, now, 0, None)?;
+            return match self.leases.create(&PostParams::default(), &lease).await {
+                Ok(_) => Ok(true),
+                // Another replica created it first
+                Err(kube::Error::Api(e)) if e.code == 409 => Ok(false),
+                Err(e) => Err(e.into()),
+            };
+        };
+
+        let spec = existing.spec.unwrap_or_default();
+        let is_ours = spec.holder_identity.as_deref() == Some(self.identity.as_str());
+        let lease_duration = spec
+            .lease_duration_seconds
+            .map(|s| chrono::Duration::seconds(s.into()))
+            .unwrap_or_else(|| chrono::Duration::from_std(LEASE_DURATION).unwrap());
+        let expired = spec
+            .renew_time
+            .as_ref()
+            .and_then(parse_micro_time)
+            .is_none_or(|renewed| renewed + lease_duration < now);
+
+        if !is_ours && !expired {
+            return Ok(false);
+        }
+
+        let (acquired, transitions) = if is_ours {
+            (
+                spec.acquire_time
+                    .as_ref()
+                    .and_then(parse_micro_time)
+                    .unwrap_or(now),
+                spec.lease_transitions.unwrap_or(0),
+            )
+        } else {
+            (now, spec.lease_transitions.unwrap_or(0) + 1)
+        };
+
+        // Replacing with the observed resourceVersion guards against two replicas taking over at once
+        let lease = self.lease(
+            acquired,
+            now,
+            transitions,
+            existing.metadata.resource_version,
+        )?;
+        match self
+            .leases
+            .replace(&self.lease_name, &PostParams::default(), &lease)
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(kube::Error::Api(e)) if e.code == 409 => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn lease(
+        &self,
+        acquired: DateTime<Utc>,
+        renewed: DateTime<Utc>,
+        transitions: i32,
+        resource_version: Option<String>,
+    ) -> Result<Lease> {
+        Ok(serde_json::from_value(json!({
+            "apiVersion": "coordination.k8s.io/v1",
+            "kind": "Lease",
+            "metadata": {
+                "name": self.lease_name,
+                "resourceVersion": resource_version,
+            },
+            "spec": {
+                "holderIdentity": self.identity,
+                "leaseDurationSeconds": LEASE_DURATION.as_secs(),
+                "acquireTime": acquired.to_rfc3339_opts(SecondsFormat::Micros, true),
+                "renewTime": renewed.to_rfc3339_opts(SecondsFormat::Micros, true),
+                "leaseTransitions": transitions,
+            },
+        }))?)
+    }
+}
+
+/// Identity of this replica, taken from the pod hostname when available
+pub fn default_identity() -> String {
+    std::env::var("HOSTNAME").unwrap_or_else(|_| format!("controller-{}", std::process::id()))
+}
+
+/// Read a `MicroTime` through its serialized RFC 3339 form, independent of the underlying time library
+fn parse_micro_time<T: Serialize>(time: &T) -> Option<DateTime<Utc>> {
+    serde_json::to_value(time).ok()?.as_str()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::http::{Method, StatusCode};
+
+    use super::*;
+    use crate::testing::{ApiServer, mock_client, next_request};
+
+    const LEASE_PATH: &str = "/apis/coordination.k8s.io/v1/namespaces/ns/leases/controller";
+
+    fn elector(client: Client) -> LeaderElector {
+        LeaderElector::new(client, "ns", "controller", "me".to_string())
+    }
+
+    /// A lease held by `holder`, last renewed `renewed_secs_ago`
+    fn held_lease(holder: &str, renewed_secs_ago: i64) -> serde_json::Value {
+        let renewed = Utc::now() - chrono::Duration::seconds(renewed_secs_ago);
+        json!({
+            "apiVersion": "coordination.k8s.io/v1",
+            "kind": "Lease",
+            "metadata": { "name": "controller", "namespace": "ns", "resourceVersion": "5" },
+            "spec": {
+                "holderIdentity": holder,
+                "leaseDurationSeconds": 15,
+                "acquireTime": "2026-01-01T00:00:00.000000Z",
+                "renewTime": renewed.to_rfc3339_opts(SecondsFormat::Micros, true),
+                "leaseTransitions": 2,
+            },
+        })
+    }
+
+    /// Answer the lookup of the lease with `lease`, or not found if `None`
+    async fn answer_get(server: &mut ApiServer, lease: Option<serde_json::Value>) {
+        let request = next_request(server).await;
+        assert_eq!(request.method, Method::GET);
+        assert_eq!(request.path, LEASE_PATH);
+        match lease {
+            Some(lease) => request.respond(StatusCode::OK, &lease),
+            None => request.respond_not_found(),
+        }
+    }
+
+    #[tokio::test]
+    async fn acquires_a_missing_lease() {
+        let (client, mut server) = mock_client();
+        let api_server = tokio::spawn(async move {
+            answer_get(&mut server, None).await;
+            let request = next_request(&mut server).await;
+            assert_eq!(request.method, Method::POST);
+            let body = request.body.clone();
+            request.respond_with_body();
+            body
+        });
+
+        assert!(elector(client).try_acquire_or_renew().await.unwrap());
+        let created = api_server.await.unwrap();
+        assert_eq!(created["spec"]["holderIdentity"], "me");
+        assert_eq!(created["spec"]["leaseTransitions"], 0);
+    }
+
+    #[tokio::test]
+    async fn renews_its_own_lease_keeping_its_acquire_time() {
+        let (client, mut server) = mock_client();
+        let api_server = tokio::spawn(async move {
+            answer_get(&mut server, Some(held_lease("me", 5))).await;
+            let request = next_request(&mut server).await;
+            assert_eq!(request.method, Method::PUT);
+            assert_eq!(request.path, LEASE_PATH);
+            let body = request.body.clone();
+            request.respond_with_body();
+            body
+        });
+
+        assert!(elector(client).try_acquire_or_renew().await.unwrap());
+        let renewed = api_server.await.unwrap();
+        assert_eq!(renewed["metadata"]["resourceVersion"], "5");
+        assert_eq!(renewed["spec"]["holderIdentity"], "me");
+        assert_eq!(
+            renewed["spec"]["acquireTime"],
+            "2026-01-01T00:00:00.000000Z"
+        );
+        assert_eq!(renewed["spec"]["leaseTransitions"], 2);
+    }
+
+    #[tokio::test]
+    async fn stands_by_while_another_replica_holds_the_lease() {
+        let (client, mut server) = mock_client();
+        let api_server = tokio::spawn(async move {
+            answer_get(&mut server, Some(held_lease("other", 5))).await;
+        });
+
+        assert!(!elector(client).try_acquire_or_renew().await.unwrap());
+        api_server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn takes_over_an_expired_lease() {
+        let (client, mut server) = mock_client();
+        let api_server = tokio::spawn(async move {
+            answer_get(&mut server, Some(held_lease("other", 60))).await;
+            let request = next_request(&mut server).await;
+            assert_eq!(request.method, Method::PUT);
+            let body = request.body.clone();
+            request.respond_with_body();
+            body
+        });
+
+        assert!(elector(client).try_acquire_or_renew().await.unwrap());
+        let taken = api_server.await.unwrap();
+        assert_eq!(taken["spec"]["holderIdentity"], "me");
+        assert_eq!(taken["spec"]["leaseTransitions"], 3);
+        assert_ne!(taken["spec"]["acquireTime"], "2026-01-01T00:00:00.000000Z");
+    }
+
+    #[tokio::test]
+    async fn loses_a_race_to_take_over() {
+        let (client, mut server) = mock_client();
+        let api_server = tokio::spawn(async move {
+            answer_get(&mut server, Some(held_lease("other", 60))).await;
+            next_request(&mut server).await.respond(
+                StatusCode::CONFLICT,
+                &json!({
+                    "kind": "Status",
+                    "apiVersion": "v1",
+                    "status": "Failure",
+                    "message": "the object has been modified",
+                    "reason": "Conflict",
+                    "code": 409,
+                }),
+            );
+        });
+
+        assert!(!elector(client).try_acquire_or_renew().await.unwrap());
+        api_server.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stops_when_another_replica_takes_the_lease() {
+        let (client, mut server) = mock_client();
+        let api_server = tokio::spawn(async move {
+            answer_get(&mut server, None).await;
+            next_request(&mut server).await.respond_with_body();
+            // The next renewal finds the lease taken over
+            answer_get(&mut server, Some(held_lease("other", 0))).await;
+        });
+
+        let result = elector(client).run(std::future::pending::<()>()).await;
+        assert!(matches!(result, Err(Error::LeadershipLost)), "{result:?}");
+        api_server.await.unwrap();
+    }
+}
@@ -1,9 +1,24 @@
 /// K8s API objects
 pub mod api;
 
+/// Cleanup of objects left behind by deleted clusters and gateways
+pub mod gc;
+
+/// Leader election between controller replicas
+pub mod leader;
+
+/// Prometheus metrics for the control loops
+pub mod metrics;
+
 /// K8s reconciliation logic
 pub mod reconcilers;
 
+/// HTTP endpoints served alongside the reconcilers
+pub mod server;
+
+#[cfg(test)]
+mod testing;
+
 use thiserror::Error;
 use tokio::sync::watch::error::SendError;
 
@@ -13,16 +28,51 @@ pub enum Error {
     Kube(#[from] kube::Error),
     #[error("Serde error: {0}")]
     SerdeYaml(#[from] serde_yaml_ng::Error),
+    #[error("Serde error: {0}")]
+    SerdeJson(#[from] serde_json::Error),
     #[error("No peers available for cluster: {0}")]
     ClusterUnavailable(#[from] SendError<Vec<GatewayCommand>>),
     #[error("Missing field in object reference")]
     MissingField,
+    #[error("Invalid spec: {0}")]
+    InvalidSpec(#[from] garde::Report),
+    #[error("Lost leadership lease")]
+    LeadershipLost,
+    #[error("Kubernetes API call timed out after {0:?}")]
+    ApiTimeout(std::time::Duration),
+    #[error("Kubernetes API call failed after {attempts} attempts: {last}")]
+    ApiRetriesExhausted { attempts: u32, last: Box<Error> },
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
-/// Commands that can be sent to gateways
+/// Commands that can be sent to gateways.
+///
+/// Each command carries an `idempotency_key` that stays the same for as long as the computer's
+/// desired state does. Computers must skip a command whose key they've already executed, so that
+/// a command redelivered after a lost ack runs at most once.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum GatewayCommand {
     #[allow(unused)]
-    Wake { computer_id: String },
+    Wake {
+        computer_id: String,
+        idempotency_key: String,
+    },
+    /// Ask a computer to finish its work within `drain_secs` and acknowledge before going offline
+    #[allow(unused)]
+    Shutdown {
+        computer_id: String,
+        drain_secs: u32,
+        idempotency_key: String,
+    },
+}
+
+impl GatewayCommand {
+    /// The computer the command is for
+    pub fn computer_id(&self) -> &str {
+        match self {
+            GatewayCommand::Wake { computer_id, .. }
+            | GatewayCommand::Shutdown { computer_id, .. } => computer_id,
+        }
+    }
 }
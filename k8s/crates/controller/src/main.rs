@@ -1,18 +1,97 @@
-use clap::{Parser, Subcommand};
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+use clap::{Parser, Subcommand, ValueEnum};
 use futures::StreamExt;
-use kube::{Client, CustomResourceExt};
+use k8s_openapi::{
+    api::{
+        core::v1::ServiceAccount,
+        rbac::v1::{ClusterRole, ClusterRoleBinding, RoleRef, Subject},
+    },
+    apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition,
+};
+use kube::{
+    Api, Client, CustomResourceExt, Resource as _, ResourceExt as _,
+    api::{ObjectMeta, Patch, PatchParams},
+    runtime::controller::Config as ControllerConfig,
+};
+use serde::{Serialize, de::DeserializeOwned};
 
 use controller::{
     api::{Computer, ComputerCluster, ComputerGateway},
-    reconcilers,
+    gc,
+    leader::{self, LeaderElector},
+    metrics::Metrics,
+    reconcilers::{self, ApiOptions, ReconcileTrigger, RequeueIntervals, gateway::WebGateway},
+    server,
 };
-use tracing_subscriber::{EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_sdk::{Resource, trace::SdkTracerProvider};
+use tracing_subscriber::{EnvFilter, Layer as _, layer::SubscriberExt, util::SubscriberInitExt};
 
 #[derive(Debug, Clone, Parser)]
 #[command(version, about)]
 struct Cli {
     #[arg(short, long, env = "KUBE_NAMESPACE")]
     namespace: String,
+    /// Only reconcile objects in these namespaces (repeatable or comma-separated); all namespaces if unset
+    #[arg(
+        long = "watch-namespace",
+        env = "WATCH_NAMESPACES",
+        value_delimiter = ','
+    )]
+    watch_namespaces: Vec<String>,
+    /// Only reconcile while holding a Lease in the controller namespace, so that replicas don't fight (default)
+    #[arg(long, overrides_with = "no_leader_election")]
+    leader_election: bool,
+    /// Reconcile unconditionally, without leader election
+    #[arg(long, overrides_with = "leader_election")]
+    no_leader_election: bool,
+    /// Maximum reconciles run at once by each watcher; 0 (default) leaves it unbounded
+    #[arg(long, env = "MAX_CONCURRENT_RECONCILES", default_value_t = 0)]
+    max_concurrent_reconciles: u16,
+    /// Seconds before reconciling an object again when nothing needed doing
+    #[arg(long, env = "HEALTHY_REQUEUE_SECS", default_value_t = 300)]
+    healthy_requeue_secs: u64,
+    /// Seconds before reconciling a cluster again when its computers have drifted from their desired state
+    #[arg(long, env = "DRIFT_REQUEUE_SECS", default_value_t = 10)]
+    drift_requeue_secs: u64,
+    /// Exit with an error if watched objects exist but no reconcile has finished for this many seconds,
+    /// so that Kubernetes restarts a controller whose watch has stalled; 0 disables the watchdog
+    #[arg(long, env = "WATCHDOG_SECS", default_value_t = 900)]
+    watchdog_secs: u64,
+    /// Run the reconcile loop without writing anything, logging each patch it would have made instead.
+    /// Combine with --no-leader-election to run without any write access.
+    #[arg(long, env = "OBSERVE_ONLY")]
+    observe_only: bool,
+    /// Seconds a single Kubernetes API call made while reconciling may take before it's abandoned
+    #[arg(long, env = "API_TIMEOUT_SECS", default_value_t = 10)]
+    api_timeout_secs: u64,
+    /// Times to retry a Kubernetes API call that timed out or failed with 429 or a 5xx before giving up
+    #[arg(long, env = "API_RETRIES", default_value_t = 3)]
+    api_retries: u32,
+    /// Users or groups allowed to call the operator endpoints: cluster status, log tails and triggered
+    /// reconciles (repeatable or comma-separated). Callers present a token, which is checked with a
+    /// TokenReview; a service account's username is `system:serviceaccount:<namespace>:<name>`.
+    /// If unset, nobody may call them.
+    #[arg(long = "operator", env = "OPERATORS", value_delimiter = ',')]
+    operators: Vec<String>,
+    /// Name of the Gateway the gateway controller applies in its namespace and routes gateways through
+    #[arg(long, env = "GATEWAY_NAME", default_value = "cc-web-gateway")]
+    gateway_name: String,
+    /// GatewayClass of the Gateway, naming the Gateway API implementation that serves it
+    #[arg(long, env = "GATEWAY_CLASS", default_value = "cilium")]
+    gateway_class: String,
+    /// Hostname the Gateway serves
+    #[arg(long, env = "GATEWAY_HOSTNAME", default_value = "smcs.dev")]
+    gateway_hostname: String,
+    /// Secret holding the Gateway's TLS certificate
+    #[arg(long, env = "GATEWAY_TLS_SECRET", default_value = "gateway-tls-secret")]
+    gateway_tls_secret: String,
     #[command(subcommand)]
     command: Option<Commands>,
 }
@@ -23,8 +102,45 @@ enum Commands {
     #[command(subcommand)]
     Reconcile(ReconcileTarget),
     /// Output K8s manifest for a given CRD resource
-    #[command(subcommand)]
-    CrdManifest(Crd),
+    CrdManifest {
+        #[command(subcommand)]
+        crd: Crd,
+        /// Format to print the manifest in
+        #[arg(long, value_enum, default_value_t = OutputFormat::Yaml)]
+        output: OutputFormat,
+    },
+    /// Output every CRD plus the ServiceAccount and RBAC a controller needs, as one multi-document YAML
+    /// stream, or apply them with `--apply`
+    Install {
+        #[command(subcommand)]
+        target: ReconcileTarget,
+        /// Server-side apply the bundle to the cluster instead of printing it, so that repeated
+        /// installs and upgrades update the objects in place
+        #[arg(long)]
+        apply: bool,
+        /// Take over fields another field manager owns, e.g. from an earlier `kubectl apply`,
+        /// rather than failing on the conflict
+        #[arg(long, requires = "apply")]
+        force: bool,
+    },
+    /// Find gateway resources whose ComputerCluster or ComputerGateway was deleted without cleaning them up
+    Gc {
+        /// Only list what would be deleted (default)
+        #[arg(long, overrides_with = "no_dry_run")]
+        dry_run: bool,
+        /// Delete the orphaned resources
+        #[arg(long, overrides_with = "dry_run")]
+        no_dry_run: bool,
+    },
+    /// Print the rednet config the gateway reconciler would write for a ComputerGateway, without applying it
+    RenderRednet {
+        /// Name of the ComputerGateway
+        #[arg(long)]
+        gateway: String,
+        /// Namespace of the ComputerGateway
+        #[arg(long)]
+        namespace: String,
+    },
 }
 
 #[derive(Debug, Clone, Subcommand)]
@@ -40,66 +156,618 @@ enum Crd {
     Gateway,
 }
 
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum OutputFormat {
+    Yaml,
+    Json,
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let tracer_provider = otlp_tracer_provider()?;
+
+    // Each layer gets its own filter, so exported traces keep the reconcile spans, which are
+    // DEBUG, without turning up the log level
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::fmt::layer()
                 .with_writer(std::io::stderr)
                 .with_target(true)
                 .with_file(true)
-                .with_line_number(true),
+                .with_line_number(true)
+                .with_filter(EnvFilter::from_default_env()),
         )
-        .with(EnvFilter::from_default_env())
+        .with(tracer_provider.as_ref().map(|provider| {
+            tracing_opentelemetry::layer()
+                .with_tracer(provider.tracer("controller"))
+                .with_filter(otel_filter())
+        }))
         .try_init()?;
 
     let cli = Cli::parse();
     match cli.command {
-        Some(Commands::Reconcile(target)) => run_controller(target, cli.namespace).await?,
-        Some(Commands::CrdManifest(crd)) => {
-            let crd = match crd {
-                Crd::Cluster => ComputerCluster::crd(),
-                Crd::Computer => Computer::crd(),
-                Crd::Gateway => ComputerGateway::crd(),
-            };
+        Some(Commands::Reconcile(target)) => {
+            // A healthy object is only reconciled every `healthy_requeue_secs`, so a watchdog that
+            // short would fire on a controller with nothing to do
+            anyhow::ensure!(
+                cli.watchdog_secs == 0 || cli.watchdog_secs > cli.healthy_requeue_secs,
+                "--watchdog-secs ({}) must be 0 or greater than --healthy-requeue-secs ({})",
+                cli.watchdog_secs,
+                cli.healthy_requeue_secs
+            );
+            run_controller(
+                target,
+                cli.namespace,
+                cli.watch_namespaces,
+                cli.max_concurrent_reconciles,
+                RequeueIntervals {
+                    healthy: Duration::from_secs(cli.healthy_requeue_secs),
+                    drift: Duration::from_secs(cli.drift_requeue_secs),
+                },
+                Duration::from_secs(cli.watchdog_secs),
+                !cli.no_leader_election,
+                ApiOptions {
+                    observe_only: cli.observe_only,
+                    timeout: Duration::from_secs(cli.api_timeout_secs),
+                    retries: cli.api_retries,
+                },
+                cli.operators,
+                WebGateway {
+                    name: cli.gateway_name,
+                    class_name: cli.gateway_class,
+                    hostname: cli.gateway_hostname,
+                    tls_secret: cli.gateway_tls_secret,
+                },
+            )
+            .await?
+        }
+        Some(Commands::CrdManifest { crd, output }) => {
+            println!("{}", crd_manifest(crd, output)?);
+        }
+        Some(Commands::Install {
+            target,
+            apply,
+            force,
+        }) => {
+            let bundle = install_bundle(target, &cli.namespace);
+            if apply {
+                bundle.apply(Client::try_default().await?, force).await?;
+            } else {
+                print!("{}", bundle.to_yaml()?);
+            }
+        }
+        Some(Commands::Gc { no_dry_run, .. }) => {
+            let client = Client::try_default().await?;
+            let scopes = reconcilers::watch_scopes(cli.watch_namespaces);
+
+            for namespace in scopes {
+                for orphan in
+                    gc::collect_garbage(&client, namespace.as_deref(), !no_dry_run).await?
+                {
+                    let action = if no_dry_run {
+                        "Deleted"
+                    } else {
+                        "Would delete"
+                    };
+                    println!(
+                        "{action} {} {}/{}",
+                        orphan.kind, orphan.namespace, orphan.name
+                    );
+                }
+            }
+        }
+        Some(Commands::RenderRednet { gateway, namespace }) => {
+            let client = Client::try_default().await?;
+            let gateway = Api::<ComputerGateway>::namespaced(client, &namespace)
+                .get(&gateway)
+                .await?;
 
-            println!("{}", serde_yaml_ng::to_string(&crd)?);
+            print!("{}", reconcilers::gateway::render_rednet_config(&gateway)?);
         }
         None => {}
     }
 
+    if let Some(provider) = tracer_provider {
+        provider.shutdown()?;
+    }
+
+    Ok(())
+}
+
+/// A CRD's manifest in the requested format
+fn crd_manifest(crd: Crd, output: OutputFormat) -> anyhow::Result<String> {
+    let crd = match crd {
+        Crd::Cluster => ComputerCluster::crd(),
+        Crd::Computer => Computer::crd(),
+        Crd::Gateway => ComputerGateway::crd(),
+    };
+
+    Ok(match output {
+        OutputFormat::Yaml => serde_yaml_ng::to_string(&crd)?,
+        OutputFormat::Json => serde_json::to_string_pretty(&crd)?,
+    })
+}
+
+/// Field manager that `install --apply` applies the bundle as, kept stable so that each install
+/// updates the fields the previous one set instead of conflicting with them
+const INSTALL_MANAGER_NAME: &str = "cc-installer";
+
+/// The CRDs and a controller's ServiceAccount, ClusterRole and ClusterRoleBinding
+struct InstallBundle {
+    crds: Vec<CustomResourceDefinition>,
+    service_account: ServiceAccount,
+    cluster_role: ClusterRole,
+    cluster_role_binding: ClusterRoleBinding,
+    /// ClusterRoles the controller binds for others rather than holding itself
+    bound_cluster_roles: Vec<ClusterRole>,
+}
+
+impl InstallBundle {
+    /// Render the bundle as one multi-document YAML stream
+    fn to_yaml(&self) -> anyhow::Result<String> {
+        let mut documents = self
+            .crds
+            .iter()
+            .map(serde_yaml_ng::to_string)
+            .collect::<Result<Vec<_>, _>>()?;
+        documents.push(serde_yaml_ng::to_string(&self.service_account)?);
+        documents.push(serde_yaml_ng::to_string(&self.cluster_role)?);
+        documents.push(serde_yaml_ng::to_string(&self.cluster_role_binding)?);
+        for cluster_role in &self.bound_cluster_roles {
+            documents.push(serde_yaml_ng::to_string(cluster_role)?);
+        }
+
+        Ok(documents.join("---\n"))
+    }
+
+    /// Server-side apply every object in the bundle as [`INSTALL_MANAGER_NAME`]
+    async fn apply(&self, client: Client, force: bool) -> anyhow::Result<()> {
+        let mut pp = PatchParams::apply(INSTALL_MANAGER_NAME);
+        if force {
+            pp = pp.force();
+        }
+
+        for crd in &self.crds {
+            apply_object(Api::all(client.clone()), crd, &pp).await?;
+        }
+        let namespace = self
+            .service_account
+            .namespace()
+            .context("service account has no namespace")?;
+        apply_object(
+            Api::namespaced(client.clone(), &namespace),
+            &self.service_account,
+            &pp,
+        )
+        .await?;
+        apply_object(Api::all(client.clone()), &self.cluster_role, &pp).await?;
+        apply_object(Api::all(client.clone()), &self.cluster_role_binding, &pp).await?;
+        for cluster_role in &self.bound_cluster_roles {
+            apply_object(Api::all(client.clone()), cluster_role, &pp).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Server-side apply `object` through `api`, logging what was applied
+async fn apply_object<K>(api: Api<K>, object: &K, pp: &PatchParams) -> anyhow::Result<()>
+where
+    K: kube::Resource + Clone + Serialize + DeserializeOwned + Debug,
+    K::DynamicType: Default,
+{
+    let kind = K::kind(&K::DynamicType::default()).into_owned();
+    let name = object.name_any();
+    api.patch(&name, pp, &Patch::Apply(object))
+        .await
+        .with_context(|| format!("Failed to apply {kind} {name}"))?;
+    tracing::info!("Applied {kind} {name}");
+
     Ok(())
 }
 
+/// The CRDs and a controller's ServiceAccount, ClusterRole and ClusterRoleBinding, plus for the
+/// cluster controller the ClusterRole it binds computers to
+fn install_bundle(target: ReconcileTarget, namespace: &str) -> InstallBundle {
+    let (name, rules, bound_cluster_roles) = match target {
+        ReconcileTarget::Clusters => (
+            reconcilers::cluster::MANAGER_NAME,
+            reconcilers::cluster::controller_policy_rules(),
+            vec![reconcilers::cluster::computer_cluster_role()],
+        ),
+        ReconcileTarget::Gateways => (
+            reconcilers::gateway::MANAGER_NAME,
+            reconcilers::gateway::controller_policy_rules(),
+            vec![],
+        ),
+    };
+
+    let service_account = ServiceAccount {
+        metadata: ObjectMeta {
+            name: Some(name.to_string()),
+            namespace: Some(namespace.to_string()),
+            ..Default::default()
+        },
+        automount_service_account_token: Some(true),
+        ..Default::default()
+    };
+    let cluster_role = ClusterRole {
+        metadata: ObjectMeta {
+            name: Some(name.to_string()),
+            ..Default::default()
+        },
+        rules: Some(rules),
+        ..Default::default()
+    };
+    let cluster_role_binding = ClusterRoleBinding {
+        metadata: ObjectMeta {
+            name: Some(name.to_string()),
+            ..Default::default()
+        },
+        role_ref: RoleRef {
+            api_group: "rbac.authorization.k8s.io".to_string(),
+            kind: "ClusterRole".to_string(),
+            name: name.to_string(),
+        },
+        subjects: Some(vec![Subject {
+            kind: "ServiceAccount".to_string(),
+            name: name.to_string(),
+            namespace: Some(namespace.to_string()),
+            ..Default::default()
+        }]),
+    };
+
+    InstallBundle {
+        crds: vec![
+            ComputerCluster::crd(),
+            Computer::crd(),
+            ComputerGateway::crd(),
+        ],
+        service_account,
+        cluster_role,
+        cluster_role_binding,
+        bound_cluster_roles,
+    }
+}
+
+/// Export spans over OTLP when an exporter endpoint is configured through the standard `OTEL_*` env vars
+fn otlp_tracer_provider() -> anyhow::Result<Option<SdkTracerProvider>> {
+    if std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_none()
+        && std::env::var_os("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT").is_none()
+    {
+        return Ok(None);
+    }
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()?;
+
+    Ok(Some(
+        SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(Resource::builder().with_service_name("controller").build())
+            .build(),
+    ))
+}
+
+/// Spans exported over OTLP, from `OTEL_TRACES_FILTER` in `RUST_LOG` syntax. Defaults to the
+/// controller's own spans down to DEBUG, which includes every reconcile, and INFO for everything else.
+fn otel_filter() -> EnvFilter {
+    EnvFilter::try_from_env("OTEL_TRACES_FILTER")
+        .unwrap_or_else(|_| EnvFilter::new("info,controller=debug"))
+}
+
 async fn run_controller(
     target: ReconcileTarget,
     controller_namespace: String,
+    watch_namespaces: Vec<String>,
+    max_concurrent_reconciles: u16,
+    requeue: RequeueIntervals,
+    watchdog_threshold: Duration,
+    leader_election: bool,
+    api_options: ApiOptions,
+    operators: Vec<String>,
+    web_gateway: WebGateway,
 ) -> anyhow::Result<()> {
     let client = Client::try_default().await.expect("connect to k8s");
+    let config = ControllerConfig::default().concurrency(max_concurrent_reconciles);
+
+    let lease_name = match target {
+        ReconcileTarget::Clusters => reconcilers::cluster::MANAGER_NAME,
+        ReconcileTarget::Gateways => reconcilers::gateway::MANAGER_NAME,
+    };
+    let elector = leader_election.then(|| {
+        LeaderElector::new(
+            client.clone(),
+            &controller_namespace,
+            lease_name,
+            leader::default_identity(),
+        )
+    });
+
+    let metrics = Arc::new(Metrics::default());
+    let trigger = ReconcileTrigger::new(match target {
+        ReconcileTarget::Clusters => ComputerCluster::plural(&()).into_owned(),
+        ReconcileTarget::Gateways => ComputerGateway::plural(&()).into_owned(),
+    });
+    let server = server::rocket(
+        client.clone(),
+        Arc::clone(&metrics),
+        trigger.clone(),
+        operators,
+    )
+    .launch();
 
-    match target {
-        ReconcileTarget::Clusters => {
-            reconcilers::cluster::control_loop(client.clone())
-                .for_each(|res| async move {
-                    match res {
-                        Ok(o) => tracing::info!("Reconciled cluster {:?}", o),
-                        Err(e) => tracing::error!("Cluster reconcile failed: {:?}", e),
+    let last_result = Arc::new(Mutex::new(Instant::now()));
+    let watchdog = watchdog(
+        Arc::clone(&last_result),
+        Arc::clone(&metrics),
+        watchdog_threshold,
+    );
+
+    let controller = async move {
+        // Leadership may have taken a while, so don't count the wait against the watchdog
+        *last_result.lock().unwrap() = Instant::now();
+
+        match target {
+            ReconcileTarget::Clusters => {
+                reconcilers::cluster::control_loop(
+                    client.clone(),
+                    watch_namespaces,
+                    config,
+                    metrics,
+                    requeue,
+                    api_options,
+                    trigger,
+                )
+                .for_each(|res| {
+                    *last_result.lock().unwrap() = Instant::now();
+                    async move {
+                        match res {
+                            Ok(o) => tracing::info!("Reconciled cluster {:?}", o),
+                            Err(e) => tracing::error!("Cluster reconcile failed: {:?}", e),
+                        }
                     }
                 })
                 .await
-        }
-        ReconcileTarget::Gateways => {
-            reconcilers::gateway::control_loop(client, controller_namespace)
-                .for_each(|res| async move {
-                    match res {
-                        Ok(o) => tracing::info!("Reconciled gateway {:?}", o),
-                        Err(e) => tracing::error!("Gateway reconcile failed: {:?}", e),
+            }
+            ReconcileTarget::Gateways => {
+                reconcilers::gateway::control_loop(
+                    client,
+                    controller_namespace,
+                    web_gateway,
+                    watch_namespaces,
+                    config,
+                    metrics,
+                    requeue,
+                    api_options,
+                    trigger,
+                )
+                .for_each(|res| {
+                    *last_result.lock().unwrap() = Instant::now();
+                    async move {
+                        match res {
+                            Ok(o) => tracing::info!("Reconciled gateway {:?}", o),
+                            Err(e) => tracing::error!("Gateway reconcile failed: {:?}", e),
+                        }
                     }
                 })
                 .await
+            }
+        }
+    };
+
+    let controller = async move {
+        match elector {
+            Some(elector) => elector.run(controller).await,
+            None => Ok(controller.await),
         }
     };
 
+    // Any replica can serve requests, but only the leader reconciles
+    tokio::select! {
+        res = server => {
+            res?;
+        }
+        res = controller => res?,
+        res = watchdog => res?,
+    }
+
     tracing::info!("controller terminated");
     Ok(())
 }
+
+/// Fail once `threshold` passes without a reconcile result while the watchers know of objects to
+/// reconcile, since a silently stalled watch stream never recovers on its own
+async fn watchdog(
+    last_result: Arc<Mutex<Instant>>,
+    metrics: Arc<Metrics>,
+    threshold: Duration,
+) -> anyhow::Result<()> {
+    if threshold.is_zero() {
+        return std::future::pending().await;
+    }
+
+    let mut checks = tokio::time::interval(threshold / 10);
+    loop {
+        checks.tick().await;
+
+        let idle = last_result.lock().unwrap().elapsed();
+        if idle > threshold && metrics.watched_objects() > 0 {
+            tracing::error!(
+                "No reconcile finished in {:?} despite watched objects, the watch has likely stalled",
+                idle
+            );
+            anyhow::bail!("control loop stalled");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::http::{Method, Request, Response, StatusCode};
+    use kube::client::Body;
+    use tower_test::mock::{self, Handle};
+
+    use super::*;
+
+    const CRD_NAMES: [&str; 3] = [
+        "computerclusters.smcs.dev",
+        "computers.smcs.dev",
+        "computergateways.smcs.dev",
+    ];
+
+    #[test]
+    fn crd_manifests_parse_in_either_format() {
+        for (crd, name) in [Crd::Cluster, Crd::Computer, Crd::Gateway]
+            .into_iter()
+            .zip(CRD_NAMES)
+        {
+            let json = crd_manifest(crd.clone(), OutputFormat::Json).unwrap();
+            let parsed: CustomResourceDefinition = serde_json::from_str(&json).unwrap();
+            assert_eq!(parsed.name_any(), name);
+
+            let yaml = crd_manifest(crd, OutputFormat::Yaml).unwrap();
+            let parsed: CustomResourceDefinition = serde_yaml_ng::from_str(&yaml).unwrap();
+            assert_eq!(parsed.name_any(), name);
+        }
+    }
+
+    #[test]
+    fn install_bundle_holds_crds_and_rbac_for_the_controller() {
+        for (target, manager, bound) in [
+            (
+                ReconcileTarget::Clusters,
+                reconcilers::cluster::MANAGER_NAME,
+                vec![reconcilers::cluster::COMPUTER_CLUSTER_ROLE],
+            ),
+            (
+                ReconcileTarget::Gateways,
+                reconcilers::gateway::MANAGER_NAME,
+                vec![],
+            ),
+        ] {
+            let bundle = install_bundle(target, "computercraft");
+
+            let crds = bundle
+                .crds
+                .iter()
+                .map(|crd| crd.name_any())
+                .collect::<Vec<_>>();
+            assert_eq!(crds, CRD_NAMES);
+
+            assert_eq!(bundle.service_account.name_any(), manager);
+            assert_eq!(
+                bundle.service_account.namespace().as_deref(),
+                Some("computercraft")
+            );
+            assert_eq!(bundle.cluster_role.name_any(), manager);
+            assert!(!bundle.cluster_role.rules.as_ref().unwrap().is_empty());
+
+            let binding = &bundle.cluster_role_binding;
+            assert_eq!(binding.role_ref.kind, "ClusterRole");
+            assert_eq!(binding.role_ref.name, manager);
+            let subject = &binding.subjects.as_ref().unwrap()[0];
+            assert_eq!(subject.kind, "ServiceAccount");
+            assert_eq!(subject.name, manager);
+            assert_eq!(subject.namespace.as_deref(), Some("computercraft"));
+
+            let bound_names = bundle
+                .bound_cluster_roles
+                .iter()
+                .map(|role| role.name_any())
+                .collect::<Vec<_>>();
+            assert_eq!(bound_names, bound);
+        }
+    }
+
+    #[test]
+    fn install_bundle_yaml_is_one_document_per_object() {
+        let yaml = install_bundle(ReconcileTarget::Clusters, "computercraft")
+            .to_yaml()
+            .unwrap();
+        let kinds = yaml
+            .split("---\n")
+            .map(|document| {
+                let value: serde_json::Value = serde_yaml_ng::from_str(document).unwrap();
+                value["kind"].as_str().unwrap().to_string()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                "CustomResourceDefinition",
+                "CustomResourceDefinition",
+                "CustomResourceDefinition",
+                "ServiceAccount",
+                "ClusterRole",
+                "ClusterRoleBinding",
+                "ClusterRole",
+            ]
+        );
+    }
+
+    /// Answer `count` server-side applies with the object applied, returning each one's path and query
+    async fn answer_applies(
+        server: &mut Handle<Request<Body>, Response<Body>>,
+        count: usize,
+    ) -> Vec<(String, String)> {
+        let mut applied = vec![];
+        for _ in 0..count {
+            let (request, response) = server.next_request().await.unwrap();
+            assert_eq!(*request.method(), Method::PATCH);
+            applied.push((
+                request.uri().path().to_string(),
+                request.uri().query().unwrap_or_default().to_string(),
+            ));
+            let body = request.into_body().collect_bytes().await.unwrap();
+            response.send_response(
+                Response::builder()
+                    .status(StatusCode::OK)
+                    .body(Body::from(body.to_vec()))
+                    .unwrap(),
+            );
+        }
+        applied
+    }
+
+    #[tokio::test]
+    async fn install_apply_is_repeatable_as_the_installer() {
+        let (service, mut server) = mock::pair::<Request<Body>, Response<Body>>();
+        let client = Client::new(service, "default");
+        let bundle = install_bundle(ReconcileTarget::Clusters, "computercraft");
+        let objects = 7;
+
+        let api_server = tokio::spawn(async move {
+            let first = answer_applies(&mut server, objects).await;
+            let second = answer_applies(&mut server, objects).await;
+            let forced = answer_applies(&mut server, objects).await;
+            (first, second, forced)
+        });
+
+        bundle.apply(client.clone(), false).await.unwrap();
+        bundle.apply(client.clone(), false).await.unwrap();
+        bundle.apply(client, true).await.unwrap();
+
+        let (first, second, forced) = api_server.await.unwrap();
+        assert_eq!(first, second);
+        for (path, query) in &first {
+            assert!(
+                query.contains("fieldManager=cc-installer"),
+                "{path}?{query}"
+            );
+            assert!(!query.contains("force=true"), "{path}?{query}");
+        }
+        assert_eq!(
+            first[3].0,
+            "/api/v1/namespaces/computercraft/serviceaccounts/cc-cluster-controller"
+        );
+        for (path, query) in &forced {
+            assert!(
+                query.contains("fieldManager=cc-installer"),
+                "{path}?{query}"
+            );
+            assert!(query.contains("force=true"), "{path}?{query}");
+        }
+    }
+}
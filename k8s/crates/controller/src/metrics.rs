@@ -0,0 +1,133 @@
+use std::{future::Future, hash::Hash, sync::Mutex};
+
+use kube::{Resource, runtime::reflector::Store};
+use prometheus_client::{
+    encoding::{EncodeLabelSet, text::encode},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{Histogram, exponential_buckets},
+    },
+    registry::Registry,
+};
+use tokio::time::Instant;
+
+use crate::Result;
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct KindLabels {
+    kind: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ReconcileLabels {
+    kind: &'static str,
+    result: &'static str,
+}
+
+/// Reconcile counts and durations, plus the number of objects each control loop is watching
+pub struct Metrics {
+    registry: Registry,
+    reconciles: Family<ReconcileLabels, Counter>,
+    reconcile_duration: Family<KindLabels, Histogram, fn() -> Histogram>,
+    watched_objects: Family<KindLabels, Gauge>,
+    /// Counts the objects in each watcher's store, read when metrics are scraped
+    stores: Mutex<Vec<(&'static str, Box<dyn Fn() -> usize + Send + Sync>)>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let reconciles = Family::<ReconcileLabels, Counter>::default();
+        let reconcile_duration =
+            Family::<KindLabels, Histogram, fn() -> Histogram>::new_with_constructor(|| {
+                Histogram::new(exponential_buckets(0.01, 2.0, 12))
+            });
+        let watched_objects = Family::<KindLabels, Gauge>::default();
+
+        let mut registry = Registry::with_prefix("cc_controller");
+        registry.register(
+            "reconciles",
+            "Reconciles by object kind and result",
+            reconciles.clone(),
+        );
+        registry.register(
+            "reconcile_duration_seconds",
+            "Time spent reconciling an object",
+            reconcile_duration.clone(),
+        );
+        registry.register(
+            "watched_objects",
+            "Objects currently known to the control loop",
+            watched_objects.clone(),
+        );
+
+        Self {
+            registry,
+            reconciles,
+            reconcile_duration,
+            watched_objects,
+            stores: Mutex::new(vec![]),
+        }
+    }
+}
+
+impl Metrics {
+    /// Run a reconcile, recording its duration and whether it succeeded
+    pub async fn measure<T>(
+        &self,
+        kind: &'static str,
+        reconcile: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let start = Instant::now();
+        let result = reconcile.await;
+
+        self.reconcile_duration
+            .get_or_create(&KindLabels { kind })
+            .observe(start.elapsed().as_secs_f64());
+        self.reconciles
+            .get_or_create(&ReconcileLabels {
+                kind,
+                result: if result.is_ok() { "success" } else { "error" },
+            })
+            .inc();
+
+        result
+    }
+
+    /// Report the size of a watcher's store under `kind`, summed across all stores of that kind
+    pub fn watch_store<K>(&self, kind: &'static str, store: Store<K>)
+    where
+        K: Resource + Clone + Send + Sync + 'static,
+        K::DynamicType: Eq + Hash + Clone + Send + Sync,
+    {
+        self.stores
+            .lock()
+            .unwrap()
+            .push((kind, Box::new(move || store.state().len())));
+    }
+
+    /// Objects currently in all watchers' stores
+    pub fn watched_objects(&self) -> usize {
+        self.stores
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, len)| len())
+            .sum()
+    }
+
+    /// Render all metrics in the OpenMetrics text format
+    pub fn encode(&self) -> Result<String, std::fmt::Error> {
+        self.watched_objects.clear();
+        for (kind, len) in self.stores.lock().unwrap().iter() {
+            self.watched_objects
+                .get_or_create(&KindLabels { kind })
+                .inc_by(len() as i64);
+        }
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &self.registry)?;
+        Ok(buffer)
+    }
+}
@@ -1,5 +1,22 @@
+use futures::{Stream, StreamExt, channel::mpsc};
 use k8s_openapi::{
-    api::core::v1::ObjectReference, apimachinery::pkg::apis::meta::v1::OwnerReference,
+    NamespaceResourceScope,
+    api::{core::v1::ObjectReference, rbac::v1::PolicyRule},
+    apimachinery::pkg::apis::meta::v1::{Condition, OwnerReference},
+};
+use kube::{
+    Api, Client, Resource,
+    api::{DeleteParams, ListParams, ObjectList, Patch, PatchParams},
+    runtime::reflector::ObjectRef,
+};
+use rand::Rng;
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::json;
+
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 
 use super::{Error, Result};
@@ -7,6 +24,8 @@ use super::{Error, Result};
 pub mod cluster;
 pub mod gateway;
 
+/// An owner reference marking `object_ref` as the managing controller, so that foreground deletion
+/// of the owner waits for the dependent
 pub(crate) fn owner_ref_from_object_ref(object_ref: &ObjectReference) -> Result<OwnerReference> {
     Ok(OwnerReference {
         api_version: object_ref
@@ -16,6 +35,400 @@ pub(crate) fn owner_ref_from_object_ref(object_ref: &ObjectReference) -> Result<
         kind: object_ref.kind.clone().ok_or_else(|| Error::MissingField)?,
         name: object_ref.name.clone().ok_or_else(|| Error::MissingField)?,
         uid: object_ref.uid.clone().ok_or_else(|| Error::MissingField)?,
-        ..Default::default()
+        controller: Some(true),
+        block_owner_deletion: Some(true),
     })
 }
+
+/// How long the reconcilers wait before looking at an object again
+#[derive(Clone, Copy, Debug)]
+pub struct RequeueIntervals {
+    /// After a reconcile that found nothing to do
+    pub healthy: Duration,
+    /// After a reconcile that found computers out of their desired state
+    pub drift: Duration,
+}
+
+/// `interval` adjusted by up to 10% either way, so that objects reconciled on the same cadence spread
+/// their API calls out instead of arriving in bursts
+pub(crate) fn jittered(interval: Duration) -> Duration {
+    interval.mul_f64(rand::rng().random_range(0.9..=1.1))
+}
+
+/// An [`Api`] scoped to one namespace, or to all namespaces when none is given
+pub(crate) fn scoped_api<K>(client: &Client, namespace: Option<&str>) -> Api<K>
+where
+    K: Resource<Scope = NamespaceResourceScope>,
+    K::DynamicType: Default,
+{
+    match namespace {
+        Some(namespace) => Api::namespaced(client.clone(), namespace),
+        None => Api::all(client.clone()),
+    }
+}
+
+/// How the reconcilers call the Kubernetes API
+#[derive(Clone, Copy, Debug)]
+pub struct ApiOptions {
+    /// Log patches instead of sending them, for running the controller without write access
+    pub observe_only: bool,
+    /// How long a single call may take before it's abandoned
+    pub timeout: Duration,
+    /// Further attempts after a call times out or fails with a transient error (429 or 5xx)
+    pub retries: u32,
+}
+
+/// Make an API call under `options`, retrying transient failures with a short doubling backoff
+pub(crate) async fn call_api<T, F, Fut>(options: ApiOptions, mut call: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = kube::Result<T>>,
+{
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let error = match tokio::time::timeout(options.timeout, call()).await {
+            Ok(Ok(value)) => return Ok(value),
+            Ok(Err(e)) if !is_transient(&e) => return Err(e.into()),
+            Ok(Err(e)) => Error::Kube(e),
+            Err(_) => Error::ApiTimeout(options.timeout),
+        };
+
+        if attempts > options.retries {
+            return Err(Error::ApiRetriesExhausted {
+                attempts,
+                last: Box::new(error),
+            });
+        }
+
+        tracing::debug!("Retrying transient API error: {}", error);
+        tokio::time::sleep(Duration::from_millis(200) * 2u32.pow(attempts - 1)).await;
+    }
+}
+
+/// Whether an API error may go away on its own: throttling or a server-side failure
+fn is_transient(error: &kube::Error) -> bool {
+    matches!(error, kube::Error::Api(e) if e.code == 429 || e.code >= 500)
+}
+
+/// An [`Api`] whose calls are bounded and retried according to [`ApiOptions`], and whose patches
+/// are only logged, not sent, when the controller runs with `--observe-only`
+pub(crate) struct ReconcileApi<K> {
+    api: Api<K>,
+    options: ApiOptions,
+}
+
+impl<K> ReconcileApi<K>
+where
+    K: Resource + Clone + DeserializeOwned + Debug,
+    K::DynamicType: Default,
+{
+    pub(crate) fn new(api: Api<K>, options: ApiOptions) -> Self {
+        Self { api, options }
+    }
+
+    pub(crate) async fn list(&self, lp: &ListParams) -> Result<ObjectList<K>> {
+        call_api(self.options, || self.api.list(lp)).await
+    }
+
+    pub(crate) async fn get_opt(&self, name: &str) -> Result<Option<K>> {
+        call_api(self.options, || self.api.get_opt(name)).await
+    }
+
+    pub(crate) async fn patch<P: Serialize + Debug>(
+        &self,
+        name: &str,
+        pp: &PatchParams,
+        patch: &Patch<P>,
+    ) -> Result<()> {
+        if self.options.observe_only {
+            log_skipped_patch::<K, P>(name, "", patch);
+            return Ok(());
+        }
+
+        call_api(self.options, || self.api.patch(name, pp, patch)).await?;
+        Ok(())
+    }
+
+    pub(crate) async fn patch_status<P: Serialize + Debug>(
+        &self,
+        name: &str,
+        pp: &PatchParams,
+        patch: &Patch<P>,
+    ) -> Result<()> {
+        if self.options.observe_only {
+            log_skipped_patch::<K, P>(name, "status of ", patch);
+            return Ok(());
+        }
+
+        call_api(self.options, || self.api.patch_status(name, pp, patch)).await?;
+        Ok(())
+    }
+
+    /// Delete an object, treating one that's already gone as deleted
+    pub(crate) async fn delete(&self, name: &str) -> Result<()> {
+        if self.options.observe_only {
+            tracing::info!(
+                "Observe-only, not deleting {} {name}",
+                K::kind(&K::DynamicType::default())
+            );
+            return Ok(());
+        }
+
+        match call_api(self.options, || {
+            self.api.delete(name, &DeleteParams::default())
+        })
+        .await
+        {
+            Err(Error::Kube(kube::Error::Api(e))) if e.code == 404 => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+}
+
+fn log_skipped_patch<K, P>(name: &str, target: &str, patch: &Patch<P>)
+where
+    K: Resource,
+    K::DynamicType: Default,
+    P: Serialize + Debug,
+{
+    tracing::info!(
+        "Observe-only, not patching {target}{} {name}: {patch:?}",
+        K::kind(&K::DynamicType::default())
+    );
+}
+
+/// Triggered reconciles each controller holds before further triggers are turned away, so that a
+/// flood of requests can't grow the queue without bound
+const TRIGGER_QUEUE_DEPTH: usize = 64;
+
+/// Requests to reconcile a named object right away, fed to each watch scope's controller through
+/// `reconcile_on`. Clones share the same subscribers.
+#[derive(Clone)]
+pub struct ReconcileTrigger {
+    /// Plural resource name of the kind the controllers reconcile, e.g. `computerclusters`
+    kind: String,
+    /// Namespace and name of each object to reconcile, sent to the controllers of matching scopes
+    subscribers: Arc<Mutex<Vec<(Option<String>, mpsc::Sender<(String, String)>)>>>,
+}
+
+/// What became of a [`ReconcileTrigger::trigger`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerOutcome {
+    /// A controller watching the object will reconcile it
+    Queued,
+    /// Every controller watching the object already has [`TRIGGER_QUEUE_DEPTH`] reconciles queued
+    Full,
+    /// No running controller watches the object
+    Unwatched,
+}
+
+impl ReconcileTrigger {
+    pub fn new(kind: String) -> Self {
+        Self {
+            kind,
+            subscribers: Default::default(),
+        }
+    }
+
+    pub fn kind(&self) -> &str {
+        &self.kind
+    }
+
+    /// Objects to reconcile for the controller watching `scope`, for as long as the stream is kept
+    pub(crate) fn subscribe<K>(
+        &self,
+        scope: Option<String>,
+    ) -> impl Stream<Item = ObjectRef<K>> + use<K>
+    where
+        K: Resource,
+        K::DynamicType: Default,
+    {
+        let (tx, rx) = mpsc::channel(TRIGGER_QUEUE_DEPTH);
+        self.subscribers.lock().unwrap().push((scope, tx));
+        rx.map(|(namespace, name)| ObjectRef::new(&name).within(&namespace))
+    }
+
+    /// Queue a reconcile of `name` in `namespace` with each running controller that watches it.
+    /// Controllers only run on the leader, so on other replicas nothing is listening.
+    pub fn trigger(&self, namespace: &str, name: &str) -> TriggerOutcome {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        // Controllers stopped by losing leadership have dropped their streams
+        subscribers.retain(|(_, tx)| !tx.is_closed());
+
+        let mut outcome = TriggerOutcome::Unwatched;
+        for (scope, tx) in subscribers.iter_mut() {
+            if scope.as_deref().is_none_or(|scope| scope == namespace) {
+                match tx.try_send((namespace.to_string(), name.to_string())) {
+                    Ok(()) => outcome = TriggerOutcome::Queued,
+                    Err(e) if e.is_full() && outcome == TriggerOutcome::Unwatched => {
+                        outcome = TriggerOutcome::Full;
+                    }
+                    Err(_) => {}
+                }
+            }
+        }
+        outcome
+    }
+}
+
+/// Namespaces to run a watcher in: each namespace in the allow-list, or a single cluster-wide watcher
+pub fn watch_scopes(namespaces: Vec<String>) -> Vec<Option<String>> {
+    if namespaces.is_empty() {
+        vec![None]
+    } else {
+        namespaces.into_iter().map(Some).collect()
+    }
+}
+
+/// An RBAC rule granting `verbs` on `resources` in an API group
+pub(crate) fn policy_rule(api_group: &str, resources: &[&str], verbs: &[&str]) -> PolicyRule {
+    PolicyRule {
+        api_groups: Some(vec![api_group.to_string()]),
+        resources: Some(resources.iter().map(|r| r.to_string()).collect()),
+        verbs: verbs.iter().map(|v| v.to_string()).collect(),
+        ..Default::default()
+    }
+}
+
+/// Condition reporting whether the last reconcile of an object succeeded
+pub(crate) const RECONCILED_CONDITION: &str = "Reconciled";
+
+/// Consecutive reconcile failures are counted up to this many, where the error backoff stops growing
+pub(crate) const MAX_RECONCILE_FAILURES: u32 = 5;
+
+/// The `Reconciled` condition after a reconcile, `False` with the message of `error` if it failed.
+/// Keeps the transition time of the matching condition in `previous` while the status is unchanged.
+pub(crate) fn reconciled_condition(
+    previous: &[Condition],
+    generation: Option<i64>,
+    error: Option<&Error>,
+) -> Result<Condition> {
+    let (status, reason, message) = match error {
+        None => ("True", "ReconcileSucceeded", String::new()),
+        Some(e) => ("False", "ReconcileFailed", e.to_string()),
+    };
+
+    let mut condition: Condition = serde_json::from_value(json!({
+        "type": RECONCILED_CONDITION,
+        "status": status,
+        "reason": reason,
+        "message": message,
+        "observedGeneration": generation,
+        "lastTransitionTime": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+    }))?;
+
+    if let Some(previous) = previous
+        .iter()
+        .find(|c| c.type_ == RECONCILED_CONDITION && c.status == condition.status)
+    {
+        condition.last_transition_time = previous.last_transition_time.clone();
+    }
+
+    Ok(condition)
+}
+
+/// Replace the condition of the same type in `conditions`, or add it if there is none
+pub(crate) fn set_condition(conditions: &mut Vec<Condition>, condition: Condition) {
+    match conditions.iter_mut().find(|c| c.type_ == condition.type_) {
+        Some(existing) => *existing = condition,
+        None => conditions.push(condition),
+    }
+}
+
+/// How long to wait before retrying after `failures` consecutive failed reconciles:
+/// 10 seconds, doubling with each failure up to [`MAX_RECONCILE_FAILURES`]
+pub(crate) fn error_backoff(failures: u32) -> Duration {
+    Duration::from_secs(10) * 2u32.pow(failures.min(MAX_RECONCILE_FAILURES).saturating_sub(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+    use k8s_openapi::api::core::v1::ConfigMap;
+
+    use super::*;
+
+    fn trigger() -> ReconcileTrigger {
+        ReconcileTrigger::new("configmaps".to_string())
+    }
+
+    #[test]
+    fn jittered_stays_within_ten_percent() {
+        let interval = Duration::from_secs(300);
+        for _ in 0..1000 {
+            let jittered = jittered(interval);
+            assert!(jittered >= Duration::from_secs(270), "{jittered:?}");
+            assert!(jittered <= Duration::from_secs(330), "{jittered:?}");
+        }
+    }
+
+    #[test]
+    fn jittered_spreads_intervals_out() {
+        let interval = Duration::from_secs(300);
+        let distinct = (0..100)
+            .map(|_| jittered(interval))
+            .collect::<std::collections::HashSet<_>>();
+        assert!(distinct.len() > 1);
+    }
+
+    #[test]
+    fn jittered_zero_stays_zero() {
+        assert_eq!(jittered(Duration::ZERO), Duration::ZERO);
+    }
+
+    #[test]
+    fn trigger_without_controllers_is_unwatched() {
+        assert_eq!(trigger().trigger("ns", "a"), TriggerOutcome::Unwatched);
+    }
+
+    #[test]
+    fn trigger_reaches_controllers_watching_namespace() {
+        let trigger = trigger();
+        let mut scoped = trigger.subscribe::<ConfigMap>(Some("ns".to_string()));
+        let mut cluster_wide = trigger.subscribe::<ConfigMap>(None);
+
+        assert_eq!(trigger.trigger("ns", "a"), TriggerOutcome::Queued);
+        let expected = ObjectRef::<ConfigMap>::new("a").within("ns");
+        assert_eq!(scoped.next().now_or_never(), Some(Some(expected.clone())));
+        assert_eq!(cluster_wide.next().now_or_never(), Some(Some(expected)));
+
+        assert_eq!(trigger.trigger("other", "b"), TriggerOutcome::Queued);
+        assert_eq!(scoped.next().now_or_never(), None);
+        assert!(cluster_wide.next().now_or_never().is_some());
+    }
+
+    #[test]
+    fn trigger_outside_watched_namespaces_is_unwatched() {
+        let trigger = trigger();
+        let _scoped = trigger.subscribe::<ConfigMap>(Some("ns".to_string()));
+
+        assert_eq!(trigger.trigger("other", "a"), TriggerOutcome::Unwatched);
+    }
+
+    #[test]
+    fn trigger_after_controller_stops_is_unwatched() {
+        let trigger = trigger();
+        drop(trigger.subscribe::<ConfigMap>(None));
+
+        assert_eq!(trigger.trigger("ns", "a"), TriggerOutcome::Unwatched);
+    }
+
+    #[test]
+    fn trigger_turns_away_requests_once_queue_is_full() {
+        let trigger = trigger();
+        let _stream = trigger.subscribe::<ConfigMap>(None);
+
+        for i in 0..TRIGGER_QUEUE_DEPTH {
+            assert_eq!(
+                trigger.trigger("ns", &i.to_string()),
+                TriggerOutcome::Queued
+            );
+        }
+        // The channel holds one more message per sender on top of its buffer
+        let outcomes = (0..2)
+            .map(|_| trigger.trigger("ns", "over"))
+            .collect::<Vec<_>>();
+        assert_eq!(outcomes.last(), Some(&TriggerOutcome::Full));
+    }
+}
@@ -1,17 +1,26 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
 
-use futures::Stream;
-use k8s_openapi::api::{
-    core::v1::{Secret, ServiceAccount},
-    rbac::v1::{PolicyRule, Role, RoleBinding, RoleRef, Subject},
+use futures::{FutureExt, Stream, StreamExt, TryStreamExt};
+use garde::Validate;
+use k8s_openapi::{
+    api::{
+        apps::v1::Deployment,
+        core::v1::{ConfigMap, Secret, ServiceAccount},
+        rbac::v1::{ClusterRole, PolicyRule, Role, RoleBinding, RoleRef, Subject},
+    },
+    apimachinery::pkg::apis::meta::v1::Condition,
 };
 use kube::{
     Api, Client, Resource,
     api::{ListParams, ObjectMeta, Patch, PatchParams},
     runtime::{
-        Controller,
-        controller::{Action, Error as ControllerError},
-        reflector::ObjectRef,
+        Controller, WatchStreamExt,
+        controller::{self, Action, Error as ControllerError},
+        predicates,
+        reflector::{self, ObjectRef, Store},
         watcher,
     },
 };
@@ -20,33 +29,161 @@ use tracing::{Level, instrument};
 
 use crate::{
     Error, GatewayCommand, Result,
-    api::{Computer, ComputerCluster, ComputerGateway, ComputerGatewaySpec},
-    reconcilers::owner_ref_from_object_ref,
+    api::{
+        Computer, ComputerCluster, ComputerClusterStatus, ComputerGateway, ComputerGatewaySpec,
+        ComputerInternalState, ComputerStatus, PowerState,
+    },
+    metrics::Metrics,
+    reconcilers::{
+        ApiOptions, MAX_RECONCILE_FAILURES, ReconcileApi, ReconcileTrigger, RequeueIntervals,
+        error_backoff, gateway, jittered, owner_ref_from_object_ref, policy_rule,
+        reconciled_condition, scoped_api, set_condition, watch_scopes,
+    },
 };
 
-const MANAGER_NAME: &str = "cc-cluster-controller";
+pub const MANAGER_NAME: &str = "cc-cluster-controller";
+
+/// Label for this control loop's metrics
+const KIND: &str = "ComputerCluster";
+
+/// Condition reporting whether enough of a cluster's computers are online
+const HEALTHY_CONDITION: &str = "AllComputersHealthy";
+
+/// Maximum number of computer status patches in flight at once per reconcile
+const STATUS_PATCH_CONCURRENCY: usize = 16;
 
 struct ReconcilerCtx {
     client: Client,
+    metrics: Arc<Metrics>,
+    requeue: RequeueIntervals,
+    api_options: ApiOptions,
+    /// Each watch scope's cache of Computers, kept by the watcher that also triggers their clusters
+    computer_stores: Vec<(Option<String>, Store<Computer>)>,
+    delivered: DeliveredCommands,
+}
+
+/// The command last delivered to each computer, by cluster namespace and name, so that a desired
+/// state that hasn't changed since the last reconcile doesn't send the same command again
+#[derive(Default)]
+struct DeliveredCommands(Mutex<HashMap<(String, String), HashMap<String, GatewayCommand>>>);
+
+impl DeliveredCommands {
+    /// Record `commands` as the cluster's delivered commands, returning those that weren't already.
+    /// A computer without a command this time is forgotten, so that it gets the same command again
+    /// should it need it later.
+    fn undelivered(
+        &self,
+        namespace: &str,
+        cluster: &str,
+        commands: &[GatewayCommand],
+    ) -> Vec<GatewayCommand> {
+        let current = commands
+            .iter()
+            .map(|command| (command.computer_id().to_string(), command.clone()))
+            .collect();
+        let previous = self
+            .0
+            .lock()
+            .unwrap()
+            .insert((namespace.to_string(), cluster.to_string()), current)
+            .unwrap_or_default();
+
+        commands
+            .iter()
+            .filter(|command| previous.get(command.computer_id()) != Some(*command))
+            .cloned()
+            .collect()
+    }
 }
 
+/// Reconcile ComputerClusters in the given namespaces, or in all namespaces if none are given
 pub fn control_loop(
     client: Client,
+    namespaces: Vec<String>,
+    config: controller::Config,
+    metrics: Arc<Metrics>,
+    requeue: RequeueIntervals,
+    api_options: ApiOptions,
+    trigger: ReconcileTrigger,
 ) -> impl Stream<
     Item = Result<(ObjectRef<ComputerCluster>, Action), ControllerError<Error, watcher::Error>>,
 > {
-    let clusters = Api::<ComputerCluster>::all(client.clone());
-    let computers = Api::<Computer>::all(client.clone());
+    let scopes = watch_scopes(namespaces)
+        .into_iter()
+        .map(|namespace| {
+            let (store, writer) = reflector::store();
+            // TODO: use label selectors to only watch objects we care about
+            let computer_events = watcher::watcher(
+                scoped_api::<Computer>(&client, namespace.as_deref()),
+                watcher::Config::default(),
+            )
+            .default_backoff()
+            .reflect(writer)
+            .touched_objects();
+            (namespace, store, computer_events)
+        })
+        .collect::<Vec<_>>();
 
     let context = Arc::new(ReconcilerCtx {
         client: client.clone(),
+        metrics,
+        requeue,
+        api_options,
+        computer_stores: scopes
+            .iter()
+            .map(|(namespace, store, _)| (namespace.clone(), store.clone()))
+            .collect(),
+        delivered: DeliveredCommands::default(),
     });
 
-    Controller::new(clusters, watcher::Config::default())
-        // TODO: use label selectors to only watch objects we care about
-        .owns(computers, watcher::Config::default())
-        .shutdown_on_signal()
-        .run(reconcile, error_policy, context)
+    futures::stream::select_all(scopes.into_iter().map(|(namespace, _, computer_events)| {
+        let (store, writer) = reflector::store();
+        // Only spec changes trigger a reconcile. Status writes would otherwise trigger one right
+        // away, including the failure count a failed reconcile records, defeating `error_policy`'s backoff.
+        let clusters = watcher::watcher(
+            scoped_api::<ComputerCluster>(&client, namespace.as_deref()),
+            watcher::Config::default(),
+        )
+        .default_backoff()
+        .reflect(writer)
+        .applied_objects()
+        .predicate_filter(predicates::generation, Default::default());
+
+        let controller = Controller::for_stream(clusters, store)
+            .with_config(config.clone())
+            .owns_stream(computer_events)
+            .reconcile_on(trigger.subscribe(namespace));
+        context.metrics.watch_store(KIND, controller.store());
+
+        controller
+            .shutdown_on_signal()
+            .run(
+                |cluster, context| async move {
+                    let metrics = Arc::clone(&context.metrics);
+                    let result = metrics
+                        .measure(KIND, reconcile(Arc::clone(&cluster), Arc::clone(&context)))
+                        .await;
+
+                    if let Err(e) = &result {
+                        let recorded = record_reconcile_error(
+                            &context.client,
+                            &cluster,
+                            e,
+                            context.api_options,
+                        )
+                        .await;
+                        if let Err(e) = recorded {
+                            tracing::warn!("Failed to record reconcile error: {:?}", e);
+                        }
+                    }
+
+                    result
+                },
+                error_policy,
+                Arc::clone(&context),
+            )
+            .boxed()
+    }))
 }
 
 #[instrument(level = Level::DEBUG, skip(context))]
@@ -55,20 +192,65 @@ async fn reconcile(cluster: Arc<ComputerCluster>, context: Arc<ReconcilerCtx>) -
 
     let cluster_namespace = cluster.metadata.namespace.as_deref().unwrap();
 
-    create_cluster_rbac(&context.client, cluster.as_ref()).await?;
+    // The CRD schema can't express every constraint, so the spec is checked before acting on it
+    cluster.spec.validate()?;
 
-    let computers = Api::<Computer>::namespaced(context.client.clone(), cluster_namespace);
+    create_cluster_rbac(&context.client, cluster.as_ref(), context.api_options).await?;
 
-    if let Err(e) = create_gateway(&context.client, &cluster).await {
-        tracing::error!("Failed to create gateway: {:?}", e);
+    let computers = ReconcileApi::new(
+        Api::<Computer>::namespaced(context.client.clone(), cluster_namespace),
+        context.api_options,
+    );
+
+    if let Err(e) = create_gateways(&context.client, &cluster, context.api_options).await {
+        tracing::error!("Failed to create gateways: {:?}", e);
     }
 
-    let commands = compute_cluster_diff_and_set_statuses(&computers, cluster.as_ref()).await?;
-    if commands.is_empty() {
+    let configmaps = ReconcileApi::new(
+        Api::<ConfigMap>::namespaced(context.client.clone(), cluster_namespace),
+        context.api_options,
+    );
+    // The watcher's cache saves listing every computer on each reconcile, once it has caught up
+    let computers_in_namespace = match cached_computers(&context.computer_stores, cluster_namespace)
+    {
+        Some(cached) => cached,
+        None => computers
+            .list(&ListParams::default())
+            .await?
+            .into_iter()
+            .map(Arc::new)
+            .collect(),
+    };
+    let diff = compute_cluster_diff_and_set_statuses(
+        &computers,
+        &configmaps,
+        cluster.as_ref(),
+        computers_in_namespace,
+    )
+    .await?;
+    update_cluster_status(
+        &context.client,
+        cluster.as_ref(),
+        &diff,
+        context.api_options,
+    )
+    .await?;
+
+    let cluster_name = cluster.metadata.name.as_deref().unwrap();
+    let commands = context
+        .delivered
+        .undelivered(cluster_namespace, cluster_name, &diff.commands);
+
+    if diff.commands.is_empty() {
         // The cluster is in a good state, check again in 5 minutes
-        return Ok(Action::requeue(Duration::from_secs(300)));
+        return Ok(Action::requeue(jittered(context.requeue.healthy)));
     }
 
+    tracing::debug!(
+        "{} of {} commands not yet delivered",
+        commands.len(),
+        diff.commands.len()
+    );
     // TODO: send commands to new gateway
     // context
     //     .c2_server
@@ -76,91 +258,191 @@ async fn reconcile(cluster: Arc<ComputerCluster>, context: Arc<ReconcilerCtx>) -
     //     .send(commands)?;
 
     // Check again in 10 seconds
-    Ok(Action::requeue(Duration::from_secs(10)))
+    Ok(Action::requeue(jittered(context.requeue.drift)))
 }
 
-async fn create_gateway(client: &Client, cluster: &ComputerCluster) -> Result<()> {
-    let Some(gateway) = cluster.spec.gateway.as_ref() else {
-        return Ok(());
-    };
+/// The ComputerGateways a cluster asks for, by object name: the `gateway` shorthand named after the
+/// cluster, then each of `gateways` named `<cluster>-<name>`. Repeated names, and names that aren't
+/// DNS labels, are skipped.
+fn desired_gateways(cluster: &ComputerCluster) -> Vec<(String, &ComputerGatewaySpec)> {
+    let cluster_name = cluster.metadata.name.as_deref().unwrap();
 
-    let pp = PatchParams::apply(MANAGER_NAME);
+    let shorthand = cluster
+        .spec
+        .gateway
+        .iter()
+        .map(|spec| (cluster_name.to_string(), spec));
+    let named = cluster
+        .spec
+        .gateways
+        .iter()
+        .filter(|named| {
+            let valid = is_dns_label(&named.name);
+            if !valid {
+                tracing::warn!(
+                    "Cluster {} names a gateway {:?}, which isn't a DNS label",
+                    cluster_name,
+                    named.name
+                );
+            }
+            valid
+        })
+        .map(|named| (format!("{cluster_name}-{}", named.name), &named.spec));
 
-    // Create a rednet gateway for this cluster
+    let mut seen = HashSet::new();
+    shorthand
+        .chain(named)
+        .filter(|(name, _)| {
+            let first = seen.insert(name.clone());
+            if !first {
+                tracing::warn!("Cluster {} asks for gateway {} twice", cluster_name, name);
+            }
+            first
+        })
+        .collect()
+}
+
+/// Lowercase letters, digits and `-`, starting and ending with a letter or digit, at most 63 long
+fn is_dns_label(name: &str) -> bool {
+    let bytes = name.as_bytes();
+    !bytes.is_empty()
+        && bytes.len() <= 63
+        && bytes
+            .iter()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || *b == b'-')
+        && bytes[0] != b'-'
+        && bytes[bytes.len() - 1] != b'-'
+}
+
+/// Apply a ComputerGateway for each gateway the cluster asks for. A gateway of the same name that
+/// another cluster owns is left alone, since `<cluster>-<name>` can name the same object for two
+/// clusters, e.g. gateway `b-c` of cluster `a` and gateway `c` of cluster `a-b`.
+async fn create_gateways(
+    client: &Client,
+    cluster: &ComputerCluster,
+    api_options: ApiOptions,
+) -> Result<()> {
+    let desired = desired_gateways(cluster);
+    if desired.is_empty() {
+        return Ok(());
+    }
 
+    let pp = PatchParams::apply(MANAGER_NAME);
     let cluster_namespace = cluster.metadata.namespace.as_deref().unwrap();
-    let cluster_name = cluster.metadata.name.as_deref().unwrap();
+    let owner_ref = owner_ref_from_object_ref(&cluster.object_ref(&()))?;
 
-    let gateways = Api::<ComputerGateway>::namespaced(client.clone(), cluster_namespace);
+    let gateways = ReconcileApi::new(
+        Api::<ComputerGateway>::namespaced(client.clone(), cluster_namespace),
+        api_options,
+    );
 
-    gateways
-        .patch(
-            cluster_name,
-            &pp,
-            &Patch::Apply(ComputerGateway {
-                metadata: ObjectMeta {
-                    name: Some(cluster_name.to_string()),
-                    namespace: Some(cluster_namespace.to_string()),
-                    owner_references: Some(vec![owner_ref_from_object_ref(
-                        &cluster.object_ref(&()),
-                    )?]),
-                    ..Default::default()
-                },
-                spec: ComputerGatewaySpec {
-                    routes: gateway.routes.clone(),
-                    links: gateway.links.clone(),
-                },
-            }),
-        )
-        .await?;
+    for (name, spec) in desired {
+        let owned_elsewhere = gateways.get_opt(&name).await?.is_some_and(|existing| {
+            existing
+                .metadata
+                .owner_references
+                .as_ref()
+                .is_some_and(|owners| {
+                    owners
+                        .iter()
+                        .any(|o| o.kind == ComputerCluster::kind(&()) && o.uid != owner_ref.uid)
+                })
+        });
+        if owned_elsewhere {
+            tracing::warn!(
+                "Gateway {} belongs to another cluster, not applying it for cluster {}",
+                name,
+                cluster.metadata.name.as_deref().unwrap()
+            );
+            continue;
+        }
+
+        gateways
+            .patch(
+                &name,
+                &pp,
+                &Patch::Apply(ComputerGateway {
+                    metadata: ObjectMeta {
+                        name: Some(name.clone()),
+                        namespace: Some(cluster_namespace.to_string()),
+                        owner_references: Some(vec![owner_ref.clone()]),
+                        ..Default::default()
+                    },
+                    spec: ComputerGatewaySpec {
+                        tls_secret_name: spec
+                            .tls_secret_name
+                            .clone()
+                            .or_else(|| cluster.spec.tls_secret_name.clone()),
+                        ..spec.clone()
+                    },
+                }),
+            )
+            .await?;
+    }
 
     Ok(())
 }
 
+/// ClusterRole holding the computers' permissions, shared by every cluster's RoleBinding.
+/// It's part of the install bundle, so the controller only needs to bind it, not write it.
+pub const COMPUTER_CLUSTER_ROLE: &str = "cc-computer";
+
+/// The ClusterRole computers are bound to, installed alongside the cluster controller
+pub fn computer_cluster_role() -> ClusterRole {
+    ClusterRole {
+        metadata: ObjectMeta {
+            name: Some(COMPUTER_CLUSTER_ROLE.to_string()),
+            ..Default::default()
+        },
+        rules: Some(computer_policy_rules()),
+        ..Default::default()
+    }
+}
+
 /// Create a service account for computers in this cluster if it doesn't already exist
 #[instrument(level = Level::DEBUG, skip(client))]
-async fn create_cluster_rbac(client: &Client, cluster: &ComputerCluster) -> Result<()> {
+async fn create_cluster_rbac(
+    client: &Client,
+    cluster: &ComputerCluster,
+    api_options: ApiOptions,
+) -> Result<()> {
     let cluster_namespace = cluster.metadata.namespace.as_deref().unwrap();
     let cluster_name = cluster.metadata.name.as_deref().unwrap();
 
-    let service_accounts = Api::<ServiceAccount>::namespaced(client.clone(), cluster_namespace);
-    let roles = Api::<Role>::namespaced(client.clone(), cluster_namespace);
-    let role_bindings = Api::<RoleBinding>::namespaced(client.clone(), cluster_namespace);
-    let secrets = Api::<Secret>::namespaced(client.clone(), cluster_namespace);
+    let service_accounts = ReconcileApi::new(
+        Api::<ServiceAccount>::namespaced(client.clone(), cluster_namespace),
+        api_options,
+    );
+    let roles = ReconcileApi::new(
+        Api::<Role>::namespaced(client.clone(), cluster_namespace),
+        api_options,
+    );
+    let role_bindings = ReconcileApi::new(
+        Api::<RoleBinding>::namespaced(client.clone(), cluster_namespace),
+        api_options,
+    );
+    let secrets = ReconcileApi::new(
+        Api::<Secret>::namespaced(client.clone(), cluster_namespace),
+        api_options,
+    );
 
     let pp = PatchParams::apply(MANAGER_NAME);
 
-    let name = format!("computer-{}", cluster_name);
+    let name = computer_service_account_name(cluster_name);
 
     let cluster_as_owner_ref = owner_ref_from_object_ref(&cluster.object_ref(&()))?;
 
-    roles
-        .patch(
-            &name,
-            &pp,
-            &Patch::Apply(Role {
-                metadata: ObjectMeta {
-                    name: Some(name.clone()),
-                    owner_references: Some(vec![cluster_as_owner_ref.clone()]),
-                    ..Default::default()
-                },
-                rules: Some(vec![
-                    PolicyRule {
-                        api_groups: Some(vec!["smcs.dev".to_string()]),
-                        resources: Some(vec!["computers".to_string()]),
-                        verbs: vec!["create".to_string(), "delete".to_string()],
-                        ..Default::default()
-                    },
-                    PolicyRule {
-                        api_groups: Some(vec!["smcs.dev".to_string()]),
-                        resources: Some(vec!["computers/status".to_string()]),
-                        verbs: vec!["update".to_string(), "patch".to_string()],
-                        ..Default::default()
-                    },
-                ]),
-            }),
-        )
-        .await?;
+    // Clusters created before the shared ClusterRole bind a Role of their own, and later ones may
+    // bind the ClusterRole under its old name. A binding's roleRef can't be changed, so replace the
+    // binding, and drop the Role if there is one.
+    let binds_other_role = role_bindings.get_opt(&name).await?.is_some_and(|binding| {
+        binding.role_ref.kind != "ClusterRole" || binding.role_ref.name != COMPUTER_CLUSTER_ROLE
+    });
+    if binds_other_role {
+        tracing::info!("Moving {} to the shared ClusterRole", name);
+        role_bindings.delete(&name).await?;
+        roles.delete(&name).await?;
+    }
 
     service_accounts
         .patch(
@@ -188,9 +470,9 @@ async fn create_cluster_rbac(client: &Client, cluster: &ComputerCluster) -> Resu
                     ..Default::default()
                 },
                 role_ref: RoleRef {
-                    kind: "Role".to_string(),
-                    name: name.clone(),
-                    ..Default::default()
+                    api_group: "rbac.authorization.k8s.io".to_string(),
+                    kind: "ClusterRole".to_string(),
+                    name: COMPUTER_CLUSTER_ROLE.to_string(),
                 },
                 subjects: Some(vec![Subject {
                     kind: "ServiceAccount".to_string(),
@@ -228,21 +510,113 @@ async fn create_cluster_rbac(client: &Client, cluster: &ComputerCluster) -> Resu
     Ok(())
 }
 
+/// Permissions the cluster controller needs to run, granted through its ClusterRole
+pub fn controller_policy_rules() -> Vec<PolicyRule> {
+    const RW: &[&str] = &["get", "list", "watch", "create", "update", "patch"];
+
+    vec![
+        policy_rule("", &["namespaces"], &["get", "list"]),
+        policy_rule(
+            "coordination.k8s.io",
+            &["leases"],
+            &["get", "create", "update"],
+        ),
+        policy_rule("authentication.k8s.io", &["tokenreviews"], &["create"]),
+        policy_rule("", &["serviceaccounts", "secrets"], RW),
+        policy_rule("rbac.authorization.k8s.io", &["rolebindings"], RW),
+        // Binding a role needs either every permission it grants or `bind` on it
+        PolicyRule {
+            resource_names: Some(vec![COMPUTER_CLUSTER_ROLE.to_string()]),
+            ..policy_rule("rbac.authorization.k8s.io", &["clusterroles"], &["bind"])
+        },
+        // Clusters' own Roles predate the shared ClusterRole, and are replaced along with their bindings
+        policy_rule(
+            "rbac.authorization.k8s.io",
+            &["roles", "rolebindings"],
+            &["delete"],
+        ),
+        policy_rule(
+            "smcs.dev",
+            &["computerclusters", "computers"],
+            &["get", "list", "watch", "create"],
+        ),
+        policy_rule("smcs.dev", &["computergateways"], RW),
+        policy_rule(
+            "smcs.dev",
+            &["computers/status", "computerclusters/status"],
+            &["update", "patch"],
+        ),
+        // Setting blockOwnerDeletion on an owner reference requires updating the owner's finalizers
+        policy_rule("smcs.dev", &["computerclusters/finalizers"], &["update"]),
+        policy_rule("apps", &["deployments"], &["get"]),
+        policy_rule("", &["configmaps"], &["get"]),
+    ]
+}
+
+/// Permissions computers get through the [`COMPUTER_CLUSTER_ROLE`], bound in their cluster's namespace
+fn computer_policy_rules() -> Vec<PolicyRule> {
+    vec![
+        policy_rule("smcs.dev", &["computers"], &["create", "delete"]),
+        policy_rule("smcs.dev", &["computers/status"], &["update", "patch"]),
+    ]
+}
+
+/// Name of the service account (and its RoleBinding and token Secret) for a cluster's computers
+pub fn computer_service_account_name(cluster_name: &str) -> String {
+    format!("computer-{}", cluster_name)
+}
+
+/// Outcome of comparing a cluster's computers against their desired state
+#[derive(Default)]
+struct ClusterDiff {
+    commands: Vec<GatewayCommand>,
+    computers_online: u32,
+    computers_total: u32,
+    computers_at_desired_version: u32,
+    /// Computers without a recent heartbeat, excluding deliberately drained ones
+    offline_computers: Vec<String>,
+}
+
+/// The computers in `namespace` from the cache of the watch scope covering it, or `None` if that
+/// cache hasn't finished its initial list yet
+fn cached_computers(
+    stores: &[(Option<String>, Store<Computer>)],
+    namespace: &str,
+) -> Option<Vec<Arc<Computer>>> {
+    let (_, store) = stores
+        .iter()
+        .find(|(scope, _)| scope.as_deref().is_none_or(|scope| scope == namespace))?;
+    if !matches!(store.wait_until_ready().now_or_never(), Some(Ok(()))) {
+        return None;
+    }
+
+    Some(
+        store
+            .state()
+            .into_iter()
+            .filter(|computer| computer.metadata.namespace.as_deref() == Some(namespace))
+            .collect(),
+    )
+}
+
 async fn compute_cluster_diff_and_set_statuses(
-    computers: &Api<Computer>,
+    computers: &ReconcileApi<Computer>,
+    configmaps: &ReconcileApi<ConfigMap>,
     cluster: &ComputerCluster,
-) -> Result<Vec<GatewayCommand>> {
+    computers_for_cluster: Vec<Arc<Computer>>,
+) -> Result<ClusterDiff> {
     let cluster_name = cluster.metadata.name.as_deref().unwrap();
 
-    // List all computers belonging to this cluster
-    let computers_for_cluster = computers.list(&ListParams::default()).await?;
-
-    if computers_for_cluster.items.is_empty() {
+    if computers_for_cluster.is_empty() {
         tracing::info!("No computers found for cluster: {}", cluster_name);
     }
 
-    let mut commands = vec![];
-    let pp = PatchParams::apply(MANAGER_NAME);
+    let max_heartbeat_age_secs = max_heartbeat_age_secs(cluster);
+    let mut diff = ClusterDiff::default();
+    let mut status_patches = vec![];
+    let mut scripts = ScriptCache::default();
+    // Heartbeats also write `online`, so take ownership of it rather than conflicting
+    let pp = PatchParams::apply(MANAGER_NAME).force();
 
     for computer in computers_for_cluster {
         // TODO: use label selectors
@@ -260,49 +634,988 @@ async fn compute_cluster_diff_and_set_statuses(
             continue;
         }
 
-        if computer.status.as_ref().map(|stat| &stat.state) != Some(&computer.spec.state) {
-            commands.push(GatewayCommand::Wake {
+        let desired_power = computer.spec.state.desired_power;
+        let is_online = computer
+            .status
+            .as_ref()
+            .is_some_and(|status| has_recent_heartbeat(status, max_heartbeat_age_secs));
+        diff.computers_total += 1;
+        if is_online {
+            diff.computers_online += 1;
+        } else if !computer.status.as_ref().is_some_and(|s| s.is_drained())
+            && desired_power != Some(PowerState::Off)
+        {
+            diff.offline_computers.push(computer.spec.id.clone());
+        }
+
+        // Recorded before any of the branches below skip the computer, so a computer that's powered
+        // off or draining still has its status follow its heartbeats
+        let online_changed = computer
+            .status
+            .as_ref()
+            .is_some_and(|status| status.online != is_online);
+        if online_changed {
+            // Computer hasn't sent a heartbeat within the cluster's max age, consider it offline
+            status_patches.push((computer.metadata.name.clone().unwrap(), is_online));
+        }
+
+        if let Some(drain_secs) = computer.spec.drain_secs {
+            // A drained computer stays down until the operator clears `drain_secs`, so never wake it
+            if !computer.status.as_ref().is_some_and(|s| s.is_drained()) {
+                diff.commands.push(GatewayCommand::Shutdown {
+                    computer_id: computer.spec.id.clone(),
+                    drain_secs,
+                    idempotency_key: idempotency_key(&computer),
+                });
+            }
+            continue;
+        }
+
+        if desired_power == Some(PowerState::Off) {
+            if is_online {
+                diff.commands.push(GatewayCommand::Shutdown {
+                    computer_id: computer.spec.id.clone(),
+                    drain_secs: 0,
+                    idempotency_key: idempotency_key(&computer),
+                });
+            }
+            continue;
+        }
+
+        let desired_state = match resolve_state(configmaps, &mut scripts, &computer).await {
+            Ok(state) => state,
+            Err(e) => {
+                tracing::warn!(
+                    "Failed to resolve script for computer {}: {:?}",
+                    computer.spec.id,
+                    e
+                );
+                continue;
+            }
+        };
+
+        let at_desired_version = computer
+            .status
+            .as_ref()
+            .is_some_and(|stat| is_at_desired_version(stat, &desired_state));
+        if at_desired_version {
+            diff.computers_at_desired_version += 1;
+        }
+
+        // A status reporting an older generation predates the current spec, even if it looks converged.
+        // Computers whose client doesn't report a generation are judged on their state alone.
+        let converged = at_desired_version
+            && computer.status.as_ref().is_some_and(|stat| {
+                stat.state == desired_state
+                    && stat
+                        .observed_generation
+                        .is_none_or(|generation| Some(generation) == computer.metadata.generation)
+            });
+        if !converged {
+            diff.commands.push(GatewayCommand::Wake {
                 computer_id: computer.spec.id.clone(),
+                idempotency_key: idempotency_key(&computer),
             });
             continue;
         }
 
-        if let Some(status) = &computer.status {
-            let is_online = status
-                .last_heartbeat_unix_sec
-                .is_some_and(|t| t >= (chrono::Utc::now().timestamp() - 300));
+        // A computer asked to be on is woken every time it's found offline, not just when it goes offline
+        let wake = (desired_power == Some(PowerState::On) || online_changed) && !is_online;
+        if wake {
+            diff.commands.push(GatewayCommand::Wake {
+                computer_id: computer.spec.id.clone(),
+                idempotency_key: idempotency_key(&computer),
+            });
+        }
+    }
 
-            if status.online != is_online {
-                // Computer hasn't sent a heartbeat in the last 5 minutes, consider it offline
-                // Optionally, send a command to check its status or take other actions
+    futures::stream::iter(status_patches)
+        .map(|(name, is_online)| {
+            let pp = &pp;
+            async move {
                 computers
                     .patch_status(
-                        computer.metadata.name.as_deref().unwrap(),
-                        &pp,
+                        &name,
+                        pp,
                         &Patch::Apply(json!({
+                            "apiVersion": Computer::api_version(&()),
+                            "kind": Computer::kind(&()),
                             "status": {
                                 "online": is_online,
                             }
                         })),
                     )
-                    .await?;
-
-                if !is_online {
-                    commands.push(GatewayCommand::Wake {
-                        computer_id: computer.spec.id.clone(),
-                    });
-                }
+                    .await
             }
+        })
+        .buffer_unordered(STATUS_PATCH_CONCURRENCY)
+        .try_for_each(|_| async { Ok(()) })
+        .await?;
+
+    Ok(diff)
+}
+
+/// ConfigMaps already fetched during a reconcile, so computers sharing a script only cost one GET
+type ScriptCache = HashMap<String, Option<ConfigMap>>;
+
+/// Desired state of a computer, with any `script_ref` replaced by the script it points to.
+/// `desired_power` is left out, since the reconciler enforces it rather than the computer applying it.
+async fn resolve_state(
+    configmaps: &ReconcileApi<ConfigMap>,
+    cache: &mut ScriptCache,
+    computer: &Computer,
+) -> Result<ComputerInternalState> {
+    let mut state = computer.spec.state.clone();
+    state.desired_power = None;
+    let Some(script_ref) = state.script_ref.take() else {
+        return Ok(state);
+    };
+
+    if state.script.is_some() {
+        tracing::warn!(
+            "Computer {} sets both script and script_ref, using script_ref",
+            computer.spec.id
+        );
+    }
+
+    if !cache.contains_key(&script_ref.name) {
+        let configmap = configmaps.get_opt(&script_ref.name).await?;
+        cache.insert(script_ref.name.clone(), configmap);
+    }
+    let configmap = &cache[&script_ref.name];
+
+    state.script = configmap
+        .as_ref()
+        .and_then(|configmap| configmap.data.as_ref())
+        .and_then(|data| data.get(&script_ref.key))
+        .cloned();
+    if state.script.is_none() && script_ref.optional != Some(true) {
+        return Err(Error::MissingField);
+    }
+
+    Ok(state)
+}
+
+/// Key identifying a computer's current desired state, which changes whenever its spec does
+fn idempotency_key(computer: &Computer) -> String {
+    format!(
+        "{}/{}",
+        computer.metadata.uid.as_deref().unwrap_or_default(),
+        computer.metadata.generation.unwrap_or_default()
+    )
+}
+
+/// Whether a computer reports running the client script version its desired state asks for, if any
+fn is_at_desired_version(status: &ComputerStatus, desired_state: &ComputerInternalState) -> bool {
+    desired_state.version.is_none() || status.version == desired_state.version
+}
+
+/// Heartbeat age after which a computer counts as offline, unless its cluster sets another
+const DEFAULT_MAX_HEARTBEAT_AGE_SECS: u64 = 300;
+
+/// Seconds since its last heartbeat after which a computer of `cluster` counts as offline
+pub(crate) fn max_heartbeat_age_secs(cluster: &ComputerCluster) -> u64 {
+    cluster
+        .spec
+        .max_heartbeat_age_secs
+        .unwrap_or(DEFAULT_MAX_HEARTBEAT_AGE_SECS)
+}
+
+/// Whether a computer has sent a heartbeat in the last `max_age_secs`
+pub(crate) fn has_recent_heartbeat(status: &ComputerStatus, max_age_secs: u64) -> bool {
+    let max_age_secs = i64::try_from(max_age_secs).unwrap_or(i64::MAX);
+    status
+        .last_heartbeat_unix_sec
+        .is_some_and(|t| t >= chrono::Utc::now().timestamp().saturating_sub(max_age_secs))
+}
+
+/// Report gateway readiness and computer counts on the cluster's status subresource
+async fn update_cluster_status(
+    client: &Client,
+    cluster: &ComputerCluster,
+    diff: &ClusterDiff,
+    api_options: ApiOptions,
+) -> Result<()> {
+    let cluster_namespace = cluster.metadata.namespace.as_deref().unwrap();
+
+    let desired = desired_gateways(cluster);
+    let deployments = ReconcileApi::new(
+        Api::<Deployment>::namespaced(client.clone(), cluster_namespace),
+        api_options,
+    );
+    let mut gateway_ready = !desired.is_empty();
+    for (name, _) in &desired {
+        let available = deployments
+            .get_opt(&gateway::deployment_name(name))
+            .await?
+            .and_then(|deployment| deployment.status)
+            .and_then(|status| status.available_replicas)
+            .is_some_and(|replicas| replicas > 0);
+        if !available {
+            gateway_ready = false;
+            break;
         }
     }
 
-    Ok(commands)
+    let previous = cluster
+        .status
+        .as_ref()
+        .map_or(&[][..], |status| &status.conditions);
+    let conditions = vec![
+        healthy_condition(cluster, diff)?,
+        reconciled_condition(previous, cluster.metadata.generation, None)?,
+    ];
+
+    let status = ComputerClusterStatus {
+        gateway_ready,
+        computers_online: diff.computers_online,
+        computers_total: diff.computers_total,
+        computers_at_desired_version: diff.computers_at_desired_version,
+        reconcile_failures: 0,
+        conditions,
+    };
+    apply_cluster_status(client, cluster, api_options, status).await
+}
+
+/// Mark the cluster `Reconciled=False` with `error`, leaving the rest of its last status as it was
+async fn record_reconcile_error(
+    client: &Client,
+    cluster: &ComputerCluster,
+    error: &Error,
+    api_options: ApiOptions,
+) -> Result<()> {
+    let mut status = cluster.status.clone().unwrap_or_default();
+    status.reconcile_failures = (status.reconcile_failures + 1).min(MAX_RECONCILE_FAILURES);
+    let condition =
+        reconciled_condition(&status.conditions, cluster.metadata.generation, Some(error))?;
+    set_condition(&mut status.conditions, condition);
+    apply_cluster_status(client, cluster, api_options, status).await
+}
+
+/// Apply a status to the cluster. Only this controller's field manager writes these fields, and the
+/// patch leaves out `resourceVersion`, so concurrent updates to the cluster don't conflict with it.
+async fn apply_cluster_status(
+    client: &Client,
+    cluster: &ComputerCluster,
+    api_options: ApiOptions,
+    status: ComputerClusterStatus,
+) -> Result<()> {
+    let cluster_namespace = cluster.metadata.namespace.as_deref().unwrap();
+    let cluster_name = cluster
+        .metadata
+        .name
+        .as_deref()
+        .ok_or(Error::MissingField)?;
+
+    ReconcileApi::new(
+        Api::<ComputerCluster>::namespaced(client.clone(), cluster_namespace),
+        api_options,
+    )
+    .patch_status(
+        cluster_name,
+        &PatchParams::apply(MANAGER_NAME),
+        &Patch::Apply(json!({
+            "apiVersion": ComputerCluster::api_version(&()),
+            "kind": ComputerCluster::kind(&()),
+            "status": status,
+        })),
+    )
+    .await
+}
+
+/// `AllComputersHealthy` is `False` once the offline fraction exceeds the cluster's `max_offline_fraction`
+fn healthy_condition(cluster: &ComputerCluster, diff: &ClusterDiff) -> Result<Condition> {
+    let max_offline_fraction = cluster.spec.max_offline_fraction.unwrap_or(0.0);
+    let offline_fraction = match diff.computers_total {
+        0 => 0.0,
+        total => diff.offline_computers.len() as f64 / total as f64,
+    };
+
+    let (status, reason, message) = if offline_fraction > max_offline_fraction {
+        (
+            "False",
+            "ComputersOffline",
+            format!("Offline computers: {}", diff.offline_computers.join(", ")),
+        )
+    } else {
+        (
+            "True",
+            "ComputersOnline",
+            format!(
+                "{} of {} computers online",
+                diff.computers_online, diff.computers_total
+            ),
+        )
+    };
+
+    let mut condition: Condition = serde_json::from_value(json!({
+        "type": HEALTHY_CONDITION,
+        "status": status,
+        "reason": reason,
+        "message": message,
+        "observedGeneration": cluster.metadata.generation,
+        "lastTransitionTime": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+    }))?;
+
+    // Only move the transition time when the status actually flips
+    if let Some(previous) = cluster.status.as_ref().and_then(|status| {
+        status
+            .conditions
+            .iter()
+            .find(|c| c.type_ == HEALTHY_CONDITION && c.status == condition.status)
+    }) {
+        condition.last_transition_time = previous.last_transition_time.clone();
+    }
+
+    Ok(condition)
 }
 
 fn error_policy(
-    _object: Arc<ComputerCluster>,
+    object: Arc<ComputerCluster>,
     _error: &Error,
     _context: Arc<ReconcilerCtx>,
 ) -> Action {
-    Action::requeue(Duration::from_secs(10))
+    // The object predates this failure, so count it on top of the ones already recorded
+    let failures = object
+        .status
+        .as_ref()
+        .map_or(0, |status| status.reconcile_failures);
+    Action::requeue(error_backoff(failures + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use k8s_openapi::http::{Method, StatusCode};
+
+    use super::*;
+    use crate::{
+        api::{
+            ComputerClusterSpec, ComputerInternalState, ComputerSpec, ComputerStatus,
+            NamedGatewaySpec,
+        },
+        testing::{api_options, mock_client, next_request},
+    };
+
+    fn cluster() -> ComputerCluster {
+        let mut cluster = ComputerCluster::new(
+            "c",
+            ComputerClusterSpec {
+                gateway: None,
+                gateways: vec![],
+                max_offline_fraction: None,
+                max_heartbeat_age_secs: None,
+                tls_secret_name: None,
+            },
+        );
+        cluster.metadata.namespace = Some("ns".to_string());
+        cluster.metadata.uid = Some("cluster-uid".to_string());
+        cluster
+    }
+
+    /// A computer of `owner`, online if it has a recent heartbeat, with its status claiming `reported_online`
+    fn computer(
+        id: &str,
+        owner: &ComputerCluster,
+        state: ComputerInternalState,
+        heartbeat: bool,
+        reported_online: bool,
+    ) -> Arc<Computer> {
+        let mut computer = Computer::new(
+            &format!("c-{id}"),
+            ComputerSpec {
+                id: id.to_string(),
+                state,
+                drain_secs: None,
+            },
+        );
+        computer.metadata.namespace = Some("ns".to_string());
+        computer.metadata.uid = Some(format!("uid-{id}"));
+        computer.metadata.generation = Some(1);
+        computer.metadata.owner_references = Some(vec![
+            owner_ref_from_object_ref(&owner.object_ref(&())).unwrap(),
+        ]);
+        computer.status = Some(ComputerStatus {
+            state: Default::default(),
+            online: reported_online,
+            last_heartbeat_unix_sec: heartbeat.then(|| chrono::Utc::now().timestamp()),
+            observed_generation: None,
+            version: None,
+            metrics: None,
+            conditions: vec![],
+        });
+        Arc::new(computer)
+    }
+
+    fn wake(computer_id: &str, idempotency_key: &str) -> GatewayCommand {
+        GatewayCommand::Wake {
+            computer_id: computer_id.to_string(),
+            idempotency_key: idempotency_key.to_string(),
+        }
+    }
+
+    #[test]
+    fn unchanged_desired_state_is_not_redelivered() {
+        let delivered = DeliveredCommands::default();
+        let commands = vec![wake("1", "uid-1/3"), wake("2", "uid-2/1")];
+
+        assert_eq!(delivered.undelivered("ns", "c", &commands), commands);
+        assert!(delivered.undelivered("ns", "c", &commands).is_empty());
+    }
+
+    #[test]
+    fn changed_desired_state_is_delivered() {
+        let delivered = DeliveredCommands::default();
+        delivered.undelivered("ns", "c", &[wake("1", "uid-1/3")]);
+
+        let changed = vec![wake("1", "uid-1/4")];
+        assert_eq!(delivered.undelivered("ns", "c", &changed), changed);
+    }
+
+    #[test]
+    fn command_is_redelivered_after_computer_had_none() {
+        let delivered = DeliveredCommands::default();
+        let commands = vec![wake("1", "uid-1/3")];
+        delivered.undelivered("ns", "c", &commands);
+        delivered.undelivered("ns", "c", &[]);
+
+        assert_eq!(delivered.undelivered("ns", "c", &commands), commands);
+    }
+
+    #[test]
+    fn clusters_are_tracked_separately() {
+        let delivered = DeliveredCommands::default();
+        let commands = vec![wake("1", "uid-1/3")];
+        delivered.undelivered("ns", "a", &commands);
+
+        assert_eq!(delivered.undelivered("ns", "b", &commands), commands);
+        assert_eq!(delivered.undelivered("other", "a", &commands), commands);
+    }
+
+    fn gateway_spec() -> ComputerGatewaySpec {
+        serde_json::from_value(json!({ "routes": [], "links": [] })).unwrap()
+    }
+
+    fn cluster_with_gateways(shorthand: bool, names: &[&str]) -> ComputerCluster {
+        let mut cluster = cluster();
+        cluster.spec.gateway = shorthand.then(gateway_spec);
+        cluster.spec.gateways = names
+            .iter()
+            .map(|name| NamedGatewaySpec {
+                name: name.to_string(),
+                spec: gateway_spec(),
+            })
+            .collect();
+        cluster
+    }
+
+    fn desired_names(cluster: &ComputerCluster) -> Vec<String> {
+        desired_gateways(cluster)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    #[test]
+    fn desired_gateways_name_shorthand_after_cluster() {
+        let cluster = cluster_with_gateways(true, &["public", "internal"]);
+        assert_eq!(desired_names(&cluster), ["c", "c-public", "c-internal"]);
+    }
+
+    #[test]
+    fn desired_gateways_skip_repeated_names() {
+        let cluster = cluster_with_gateways(false, &["public", "public"]);
+        assert_eq!(desired_names(&cluster), ["c-public"]);
+    }
+
+    #[test]
+    fn desired_gateways_skip_names_that_arent_dns_labels() {
+        let too_long = "a".repeat(64);
+        let cluster = cluster_with_gateways(
+            false,
+            &[
+                "ok",
+                "",
+                "Upper",
+                "under_score",
+                "-leading",
+                "trailing-",
+                "a.b",
+                too_long.as_str(),
+            ],
+        );
+        assert_eq!(desired_names(&cluster), ["c-ok"]);
+    }
+
+    #[test]
+    fn dns_labels() {
+        for name in ["a", "a-b", "0", "web-2", "a".repeat(63).as_str()] {
+            assert!(is_dns_label(name), "{name:?}");
+        }
+        for name in [
+            "",
+            "A",
+            "a_b",
+            "-a",
+            "a-",
+            "a.b",
+            "é",
+            "a".repeat(64).as_str(),
+        ] {
+            assert!(!is_dns_label(name), "{name:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn gateways_default_to_the_clusters_tls_secret() {
+        let mut cluster = cluster_with_gateways(true, &["public"]);
+        cluster.spec.tls_secret_name = Some("c-tls".to_string());
+        cluster.spec.gateways[0].spec.tls_secret_name = Some("public-tls".to_string());
+
+        let (client, mut server) = mock_client();
+        let api_server = tokio::spawn(async move {
+            let mut applied = vec![];
+            for _ in 0..2 {
+                let request = next_request(&mut server).await;
+                assert_eq!(request.method, Method::GET);
+                request.respond_not_found();
+
+                let request = next_request(&mut server).await;
+                assert_eq!(request.method, Method::PATCH);
+                applied.push((request.path.clone(), request.body.clone()));
+                request.respond_with_body();
+            }
+            applied
+        });
+
+        create_gateways(&client, &cluster, api_options())
+            .await
+            .unwrap();
+
+        let applied = api_server.await.unwrap();
+        assert_eq!(
+            applied[0].0,
+            "/apis/smcs.dev/v1/namespaces/ns/computergateways/c"
+        );
+        assert_eq!(applied[0].1["spec"]["tls_secret_name"], "c-tls");
+        assert_eq!(
+            applied[1].0,
+            "/apis/smcs.dev/v1/namespaces/ns/computergateways/c-public"
+        );
+        assert_eq!(applied[1].1["spec"]["tls_secret_name"], "public-tls");
+    }
+
+    #[tokio::test]
+    async fn cluster_rbac_binds_computers_to_shared_cluster_role() {
+        let (client, mut server) = mock_client();
+        let api_server = tokio::spawn(async move {
+            let request = next_request(&mut server).await;
+            assert_eq!(request.method, Method::GET);
+            assert_eq!(
+                request.path,
+                "/apis/rbac.authorization.k8s.io/v1/namespaces/ns/rolebindings/computer-c"
+            );
+            request.respond_not_found();
+
+            let mut applied = vec![];
+            for _ in 0..3 {
+                let request = next_request(&mut server).await;
+                assert_eq!(request.method, Method::PATCH);
+                assert!(request.query.contains("fieldManager=cc-cluster-controller"));
+                applied.push((request.path.clone(), request.body.clone()));
+                request.respond_with_body();
+            }
+            applied
+        });
+
+        create_cluster_rbac(&client, &cluster(), api_options())
+            .await
+            .unwrap();
+
+        let applied = api_server.await.unwrap();
+        let paths = applied
+            .iter()
+            .map(|(path, _)| path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            [
+                "/api/v1/namespaces/ns/serviceaccounts/computer-c",
+                "/apis/rbac.authorization.k8s.io/v1/namespaces/ns/rolebindings/computer-c",
+                "/api/v1/namespaces/ns/secrets/computer-c",
+            ]
+        );
+
+        let binding = &applied[1].1;
+        assert_eq!(binding["roleRef"]["kind"], "ClusterRole");
+        assert_eq!(binding["roleRef"]["name"], COMPUTER_CLUSTER_ROLE);
+        assert_eq!(binding["subjects"][0]["name"], "computer-c");
+        for (_, object) in &applied {
+            assert_eq!(
+                object["metadata"]["ownerReferences"][0]["uid"],
+                "cluster-uid"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn cluster_rbac_replaces_binding_to_another_role() {
+        let (client, mut server) = mock_client();
+        let api_server = tokio::spawn(async move {
+            let request = next_request(&mut server).await;
+            assert_eq!(request.method, Method::GET);
+            request.respond(
+                StatusCode::OK,
+                &RoleBinding {
+                    metadata: ObjectMeta {
+                        name: Some("computer-c".to_string()),
+                        namespace: Some("ns".to_string()),
+                        ..Default::default()
+                    },
+                    role_ref: RoleRef {
+                        api_group: "rbac.authorization.k8s.io".to_string(),
+                        kind: "Role".to_string(),
+                        name: "computer-c".to_string(),
+                    },
+                    subjects: None,
+                },
+            );
+
+            let mut deleted = vec![];
+            for _ in 0..2 {
+                let request = next_request(&mut server).await;
+                assert_eq!(request.method, Method::DELETE);
+                deleted.push(request.path.clone());
+                request.respond_not_found();
+            }
+            for _ in 0..3 {
+                next_request(&mut server).await.respond_with_body();
+            }
+            deleted
+        });
+
+        create_cluster_rbac(&client, &cluster(), api_options())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            api_server.await.unwrap(),
+            [
+                "/apis/rbac.authorization.k8s.io/v1/namespaces/ns/rolebindings/computer-c",
+                "/apis/rbac.authorization.k8s.io/v1/namespaces/ns/roles/computer-c",
+            ]
+        );
+    }
+
+    /// The computers' RoleBinding of cluster `c`, bound to the `kind` named `role`
+    fn computer_binding(kind: &str, role: &str) -> RoleBinding {
+        RoleBinding {
+            metadata: ObjectMeta {
+                name: Some("computer-c".to_string()),
+                namespace: Some("ns".to_string()),
+                ..Default::default()
+            },
+            role_ref: RoleRef {
+                api_group: "rbac.authorization.k8s.io".to_string(),
+                kind: kind.to_string(),
+                name: role.to_string(),
+            },
+            subjects: None,
+        }
+    }
+
+    /// Run `create_cluster_rbac` against an existing `binding`, returning the method and path of
+    /// every request after the binding's lookup
+    async fn rbac_requests_given(binding: RoleBinding) -> Vec<(Method, String)> {
+        let (client, mut server) = mock_client();
+        let api_server = tokio::spawn(async move {
+            next_request(&mut server)
+                .await
+                .respond(StatusCode::OK, &binding);
+
+            let mut requests = vec![];
+            loop {
+                let request = next_request(&mut server).await;
+                let method = request.method.clone();
+                requests.push((method.clone(), request.path.clone()));
+                if method == Method::DELETE {
+                    request.respond_not_found();
+                } else {
+                    let last = request.path.ends_with("/secrets/computer-c");
+                    request.respond_with_body();
+                    if last {
+                        return requests;
+                    }
+                }
+            }
+        });
+
+        create_cluster_rbac(&client, &cluster(), api_options())
+            .await
+            .unwrap();
+        api_server.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn cluster_rbac_keeps_a_binding_to_the_shared_role() {
+        let requests =
+            rbac_requests_given(computer_binding("ClusterRole", COMPUTER_CLUSTER_ROLE)).await;
+        assert!(
+            requests.iter().all(|(method, _)| *method == Method::PATCH),
+            "{requests:?}"
+        );
+        assert_eq!(requests.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn cluster_rbac_replaces_a_binding_to_the_old_cluster_role_name() {
+        let requests = rbac_requests_given(computer_binding("ClusterRole", "computer")).await;
+        assert_eq!(
+            requests[..2],
+            [
+                (
+                    Method::DELETE,
+                    "/apis/rbac.authorization.k8s.io/v1/namespaces/ns/rolebindings/computer-c"
+                        .to_string()
+                ),
+                (
+                    Method::DELETE,
+                    "/apis/rbac.authorization.k8s.io/v1/namespaces/ns/roles/computer-c".to_string()
+                ),
+            ]
+        );
+        assert!(
+            requests[2..]
+                .iter()
+                .all(|(method, _)| *method == Method::PATCH)
+        );
+    }
+
+    #[tokio::test]
+    async fn status_apply_leaves_out_resource_version() {
+        let mut cluster = cluster();
+        cluster.metadata.resource_version = Some("42".to_string());
+        cluster.status = Some(ComputerClusterStatus {
+            reconcile_failures: 1,
+            ..Default::default()
+        });
+
+        let (client, mut server) = mock_client();
+        let api_server = tokio::spawn(async move {
+            let request = next_request(&mut server).await;
+            assert_eq!(request.method, Method::PATCH);
+            assert_eq!(
+                request.path,
+                "/apis/smcs.dev/v1/namespaces/ns/computerclusters/c/status"
+            );
+            assert!(request.query.contains("fieldManager=cc-cluster-controller"));
+            let body = request.body.clone();
+            request.respond_with_body();
+            body
+        });
+
+        record_reconcile_error(&client, &cluster, &Error::MissingField, api_options())
+            .await
+            .unwrap();
+
+        let applied = api_server.await.unwrap();
+        assert!(
+            applied["metadata"]["resourceVersion"].is_null(),
+            "{applied}"
+        );
+        assert_eq!(applied["status"]["reconcile_failures"], 2);
+    }
+
+    #[tokio::test]
+    async fn cluster_diff_commands_computers_and_patches_stale_statuses() {
+        let cluster = cluster();
+        let mut other = cluster.clone();
+        other.metadata.name = Some("other".to_string());
+        other.metadata.uid = Some("other-uid".to_string());
+
+        let powered_off = ComputerInternalState {
+            desired_power: Some(PowerState::Off),
+            ..Default::default()
+        };
+        let upgraded = ComputerInternalState {
+            version: Some("2".to_string()),
+            ..Default::default()
+        };
+        let computers = vec![
+            // Converged, but reported offline despite its heartbeat
+            computer("1", &cluster, Default::default(), true, false),
+            // Asked to power off, and still online
+            computer("2", &cluster, powered_off, true, true),
+            // Behind on its version, and gone quiet since it reported online
+            computer("3", &cluster, upgraded, false, true),
+            // Another cluster's, however out of date
+            computer("4", &other, Default::default(), false, true),
+        ];
+        let responses = computers.clone();
+
+        let (client, mut server) = mock_client();
+        let api_server = tokio::spawn(async move {
+            let mut patched = vec![];
+            for _ in 0..2 {
+                let request = next_request(&mut server).await;
+                assert_eq!(request.method, Method::PATCH);
+                assert_eq!(request.body["apiVersion"], "smcs.dev/v1");
+                assert_eq!(request.body["kind"], "Computer");
+                let name = request
+                    .path
+                    .strip_prefix("/apis/smcs.dev/v1/namespaces/ns/computers/")
+                    .and_then(|path| path.strip_suffix("/status"))
+                    .unwrap()
+                    .to_string();
+                patched.push((name.clone(), request.body["status"]["online"].clone()));
+
+                let computer = responses
+                    .iter()
+                    .find(|computer| computer.metadata.name.as_deref() == Some(name.as_str()))
+                    .unwrap();
+                request.respond(StatusCode::OK, computer.as_ref());
+            }
+            // Patches are made concurrently, so may arrive in any order
+            patched.sort_by(|a, b| a.0.cmp(&b.0));
+            patched
+        });
+
+        let diff = compute_cluster_diff_and_set_statuses(
+            &ReconcileApi::new(Api::namespaced(client.clone(), "ns"), api_options()),
+            &ReconcileApi::new(Api::namespaced(client, "ns"), api_options()),
+            &cluster,
+            computers,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            api_server.await.unwrap(),
+            [
+                ("c-1".to_string(), json!(true)),
+                ("c-3".to_string(), json!(false)),
+            ]
+        );
+        assert_eq!(
+            diff.commands,
+            [
+                GatewayCommand::Shutdown {
+                    computer_id: "2".to_string(),
+                    drain_secs: 0,
+                    idempotency_key: "uid-2/1".to_string(),
+                },
+                GatewayCommand::Wake {
+                    computer_id: "3".to_string(),
+                    idempotency_key: "uid-3/1".to_string(),
+                },
+            ]
+        );
+        assert_eq!(diff.computers_total, 3);
+        assert_eq!(diff.computers_online, 2);
+        assert_eq!(diff.offline_computers, ["3"]);
+    }
+
+    /// `computer` with its last heartbeat `age_secs` ago
+    fn with_heartbeat_age(computer: Arc<Computer>, age_secs: i64) -> Arc<Computer> {
+        let mut computer = Arc::unwrap_or_clone(computer);
+        computer.status.as_mut().unwrap().last_heartbeat_unix_sec =
+            Some(chrono::Utc::now().timestamp() - age_secs);
+        Arc::new(computer)
+    }
+
+    /// Diff `computers` against `cluster`, answering status patches, and return the diff along with
+    /// the names of the computers patched and the `online` they were patched to
+    async fn diff_with_status_patches(
+        cluster: &ComputerCluster,
+        computers: Vec<Arc<Computer>>,
+        expected_patches: usize,
+    ) -> (ClusterDiff, Vec<(String, serde_json::Value)>) {
+        let (client, mut server) = mock_client();
+        let api_server = tokio::spawn(async move {
+            let mut patched = vec![];
+            for _ in 0..expected_patches {
+                let request = next_request(&mut server).await;
+                assert_eq!(request.method, Method::PATCH);
+                let name = request
+                    .path
+                    .strip_prefix("/apis/smcs.dev/v1/namespaces/ns/computers/")
+                    .and_then(|path| path.strip_suffix("/status"))
+                    .unwrap()
+                    .to_string();
+                patched.push((name, request.body["status"]["online"].clone()));
+                request.respond_with_body();
+            }
+            patched.sort_by(|a, b| a.0.cmp(&b.0));
+            patched
+        });
+
+        let diff = compute_cluster_diff_and_set_statuses(
+            &ReconcileApi::new(Api::namespaced(client.clone(), "ns"), api_options()),
+            &ReconcileApi::new(Api::namespaced(client, "ns"), api_options()),
+            cluster,
+            computers,
+        )
+        .await
+        .unwrap();
+        (diff, api_server.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn max_heartbeat_age_decides_which_computers_are_online() {
+        let mut cluster = cluster();
+        cluster.spec.max_heartbeat_age_secs = Some(60);
+        let computers = vec![
+            with_heartbeat_age(computer("1", &cluster, Default::default(), true, true), 30),
+            with_heartbeat_age(computer("2", &cluster, Default::default(), true, true), 120),
+        ];
+
+        let (diff, patched) = diff_with_status_patches(&cluster, computers.clone(), 1).await;
+        assert_eq!(patched, [("c-2".to_string(), json!(false))]);
+        assert_eq!(diff.computers_online, 1);
+        assert_eq!(diff.offline_computers, ["2"]);
+
+        let condition = healthy_condition(&cluster, &diff).unwrap();
+        assert_eq!(condition.status, "False");
+        assert_eq!(condition.message, "Offline computers: 2");
+
+        // Two minutes is well within the default five
+        cluster.spec.max_heartbeat_age_secs = None;
+        let (diff, patched) = diff_with_status_patches(&cluster, computers, 0).await;
+        assert!(patched.is_empty());
+        assert_eq!(diff.computers_online, 2);
+        assert_eq!(healthy_condition(&cluster, &diff).unwrap().status, "True");
+    }
+
+    #[tokio::test]
+    async fn invalid_spec_fails_reconcile_before_any_api_call() {
+        let (client, mut server) = mock_client();
+        let context = Arc::new(ReconcilerCtx {
+            client,
+            metrics: Arc::new(Metrics::default()),
+            requeue: RequeueIntervals {
+                healthy: Duration::from_secs(300),
+                drift: Duration::from_secs(10),
+            },
+            api_options: api_options(),
+            computer_stores: vec![],
+            delivered: DeliveredCommands::default(),
+        });
+
+        for spec in [
+            json!({ "max_offline_fraction": 1.5 }),
+            json!({ "max_heartbeat_age_secs": 0 }),
+        ] {
+            let mut cluster = cluster();
+            cluster.spec = serde_json::from_value(spec.clone()).unwrap();
+            let result = reconcile(Arc::new(cluster), Arc::clone(&context)).await;
+            assert!(matches!(result, Err(Error::InvalidSpec(_))), "{spec}");
+        }
+
+        drop(context);
+        assert!(
+            server.next_request().await.is_none(),
+            "reconciling an invalid spec called the API"
+        );
+    }
 }
@@ -1,12 +1,26 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::Arc,
+};
 
-use futures::Stream;
+use futures::{Stream, StreamExt};
 use k8s_openapi::{
     api::{
         apps::v1::Deployment,
-        core::v1::{ConfigMap, Service, ServiceSpec},
+        core::v1::{ConfigMap, ResourceRequirements, Service, ServiceSpec},
+        rbac::v1::PolicyRule,
+    },
+    apimachinery::pkg::{
+        api::resource::Quantity, apis::meta::v1::Condition, util::intstr::IntOrString,
     },
-    apimachinery::pkg::util::intstr::IntOrString,
+    http,
+};
+use kcr_gateway_networking_k8s_io::v1::gateways::{
+    Gateway, GatewayListeners, GatewayListenersAllowedRoutes,
+    GatewayListenersAllowedRoutesNamespaces, GatewayListenersAllowedRoutesNamespacesFrom,
+    GatewayListenersAllowedRoutesNamespacesSelector, GatewayListenersTls,
+    GatewayListenersTlsCertificateRefs, GatewaySpec,
 };
 use kcr_gateway_networking_k8s_io::v1::httproutes::{
     HTTPRoute, HTTPRouteParentRefs, HTTPRouteRules, HTTPRouteRulesBackendRefs,
@@ -16,82 +30,385 @@ use kcr_gateway_networking_k8s_io::v1::httproutes::{
 };
 use kube::{
     Api, Client, Resource,
-    api::{ObjectMeta, Patch, PatchParams},
+    api::{ListParams, ObjectMeta, Patch, PatchParams},
     runtime::{
-        Controller,
-        controller::{Action, Error as ControllerError},
-        reflector::ObjectRef,
+        Controller, WatchStreamExt,
+        controller::{self, Action, Error as ControllerError},
+        events::{Event, EventType, Recorder, Reporter},
+        predicates,
+        reflector::{self, ObjectRef},
         watcher,
     },
 };
+use serde::Deserialize;
+use serde_json::json;
 use tracing::{Level, instrument};
 
 use crate::{
     Error, Result,
-    api::{ComputerGateway, RednetGatewayConfigMapData},
-    reconcilers::owner_ref_from_object_ref,
+    api::{
+        Computer, ComputerGateway, ComputerGatewayStatus, HttpOverRednetRoute,
+        RednetGatewayConfigMapData,
+    },
+    metrics::Metrics,
+    reconcilers::{
+        ApiOptions, MAX_RECONCILE_FAILURES, ReconcileApi, ReconcileTrigger, RequeueIntervals,
+        call_api, error_backoff, jittered, owner_ref_from_object_ref, policy_rule,
+        reconciled_condition, scoped_api, set_condition, watch_scopes,
+    },
 };
 
-const MANAGER_NAME: &str = "cc-gateway-controller";
+pub const MANAGER_NAME: &str = "cc-gateway-controller";
+
+/// Label for this control loop's metrics
+const KIND: &str = "ComputerGateway";
+
+/// Condition reporting whether every route can be told apart from the others
+const ROUTES_CONDITION: &str = "RoutesUnambiguous";
+
+/// The shared Gateway every ComputerGateway's HTTPRoute attaches to, applied in the controller namespace
+#[derive(Debug, Clone)]
+pub struct WebGateway {
+    pub name: String,
+    /// GatewayClass of the Gateway API implementation serving it, e.g. `cilium` or `istio`
+    pub class_name: String,
+    pub hostname: String,
+    /// Secret holding the certificate served for `hostname`
+    pub tls_secret: String,
+}
 
 struct ReconcilerCtx {
     client: Client,
     controller_namespace: String,
+    web_gateway: WebGateway,
+    /// Namespaces whose ComputerGateways get a listener on the shared Gateway, or all if empty
+    namespaces: Vec<String>,
+    recorder: Recorder,
+    metrics: Arc<Metrics>,
+    requeue: RequeueIntervals,
+    api_options: ApiOptions,
 }
 
+/// Reconcile ComputerGateways in the given namespaces, or in all namespaces if none are given
 pub fn control_loop(
     client: Client,
     controller_namespace: String,
+    web_gateway: WebGateway,
+    namespaces: Vec<String>,
+    config: controller::Config,
+    metrics: Arc<Metrics>,
+    requeue: RequeueIntervals,
+    api_options: ApiOptions,
+    trigger: ReconcileTrigger,
 ) -> impl Stream<
     Item = Result<(ObjectRef<ComputerGateway>, Action), ControllerError<Error, watcher::Error>>,
 > {
-    let gateways = Api::<ComputerGateway>::all(client.clone());
-    let httproutes = Api::<HTTPRoute>::all(client.clone());
-    let configmaps = Api::<ConfigMap>::all(client.clone());
-    let deployments = Api::<Deployment>::all(client.clone());
-
     let context = Arc::new(ReconcilerCtx {
         client: client.clone(),
         controller_namespace,
+        web_gateway,
+        namespaces: namespaces.clone(),
+        recorder: Recorder::new(
+            client.clone(),
+            Reporter {
+                controller: MANAGER_NAME.to_string(),
+                instance: None,
+            },
+        ),
+        metrics,
+        requeue,
+        api_options,
     });
 
-    Controller::new(gateways, watcher::Config::default())
-        .owns(httproutes, watcher::Config::default())
-        .owns(configmaps, watcher::Config::default())
-        .owns(deployments, watcher::Config::default())
-        .shutdown_on_signal()
-        .run(reconcile, error_policy, context)
+    futures::stream::select_all(watch_scopes(namespaces).into_iter().map(|namespace| {
+        let (store, writer) = reflector::store();
+        // Only spec changes trigger a reconcile. Status writes would otherwise trigger one right
+        // away, including the failure count a failed reconcile records, defeating `error_policy`'s backoff.
+        let gateways = watcher::watcher(
+            scoped_api::<ComputerGateway>(&client, namespace.as_deref()),
+            watcher::Config::default(),
+        )
+        .default_backoff()
+        .reflect(writer)
+        .applied_objects()
+        .predicate_filter(predicates::generation, Default::default());
+        let httproutes = scoped_api::<HTTPRoute>(&client, namespace.as_deref());
+        let configmaps = scoped_api::<ConfigMap>(&client, namespace.as_deref());
+        let deployments = scoped_api::<Deployment>(&client, namespace.as_deref());
+
+        let controller = Controller::for_stream(gateways, store)
+            .with_config(config.clone())
+            .owns(httproutes, watcher::Config::default())
+            .owns(configmaps, watcher::Config::default())
+            .owns(deployments, watcher::Config::default())
+            .reconcile_on(trigger.subscribe(namespace));
+        context.metrics.watch_store(KIND, controller.store());
+
+        controller
+            .shutdown_on_signal()
+            .run(
+                |gateway, context| async move {
+                    let metrics = Arc::clone(&context.metrics);
+                    let result = metrics
+                        .measure(KIND, reconcile(Arc::clone(&gateway), Arc::clone(&context)))
+                        .await;
+
+                    if let Err(e) = &result {
+                        let recorded = record_reconcile_error(
+                            &context.client,
+                            &gateway,
+                            e,
+                            context.api_options,
+                        )
+                        .await;
+                        if let Err(e) = recorded {
+                            tracing::warn!("Failed to record reconcile error: {:?}", e);
+                        }
+                    }
+
+                    result
+                },
+                error_policy,
+                Arc::clone(&context),
+            )
+            .boxed()
+    }))
 }
 
 #[instrument(level = Level::DEBUG, skip(context))]
 async fn reconcile(gateway: Arc<ComputerGateway>, context: Arc<ReconcilerCtx>) -> Result<Action> {
     tracing::info!("Reconciling...");
 
+    let gateways = list_gateways(&context).await?;
+    ReconcileApi::new(
+        Api::<Gateway>::namespaced(context.client.clone(), &context.controller_namespace),
+        context.api_options,
+    )
+    .patch(
+        &context.web_gateway.name,
+        &PatchParams::apply(MANAGER_NAME),
+        &Patch::Apply(render_web_gateway(
+            &context.web_gateway,
+            &context.controller_namespace,
+            &gateways,
+        )),
+    )
+    .await?;
+
     create_gateway_hub(
         &context.client,
         &gateway,
+        &context.web_gateway,
         context.controller_namespace.clone(),
+        context.api_options,
     )
     .await?;
 
-    Ok(Action::requeue(Duration::from_secs(300)))
+    // Dangling links are only worth a warning, so they don't hold up reconciling the rest
+    if let Err(e) = check_links(&context, &gateway).await {
+        tracing::warn!("Failed to check gateway links: {:?}", e);
+    }
+    // Likewise overlapping routes, which are deployed as they are
+    if let Err(e) = check_routes(&context, &gateway).await {
+        tracing::warn!("Failed to check gateway routes: {:?}", e);
+    }
+
+    update_gateway_status(&context.client, &gateway, context.api_options).await?;
+
+    Ok(Action::requeue(jittered(context.requeue.healthy)))
+}
+
+/// Listener of the shared Gateway that serves gateways' HTTPRoutes, named as it was when the Gateway
+/// was deployed by hand, so that applying it over that Gateway doesn't add a second HTTPS listener
+const HTTPS_LISTENER: &str = "cc-web-gateway";
+
+/// Listener cert-manager answers HTTP-01 challenges for the Gateway's certificate on
+const ACME_LISTENER: &str = "letsencrypt";
+
+/// Every ComputerGateway in the watched namespaces that isn't being deleted, so that the shared
+/// Gateway is rendered with a listener for each. A deleted gateway's listener is dropped the next
+/// time any gateway is reconciled.
+async fn list_gateways(context: &ReconcilerCtx) -> Result<Vec<ComputerGateway>> {
+    let mut gateways = vec![];
+    for namespace in watch_scopes(context.namespaces.clone()) {
+        let listed = ReconcileApi::new(
+            scoped_api::<ComputerGateway>(&context.client, namespace.as_deref()),
+            context.api_options,
+        )
+        .list(&ListParams::default())
+        .await?;
+        gateways.extend(
+            listed
+                .into_iter()
+                .filter(|gateway| gateway.metadata.deletion_timestamp.is_none()),
+        );
+    }
+    Ok(gateways)
+}
+
+/// Name of a gateway's own listener on the shared Gateway, `<namespace>.<name>`. It's unique, since
+/// namespaces can't contain dots, and never collides with the shared listeners, whose names have none.
+fn gateway_listener_name(gateway: &ComputerGateway) -> String {
+    format!(
+        "{}.{}",
+        gateway.metadata.namespace.as_deref().unwrap(),
+        gateway.metadata.name.as_deref().unwrap()
+    )
+}
+
+/// Hostname a gateway's own listener serves, `<name>.<namespace>.<hostname>`
+fn gateway_hostname(gateway: &ComputerGateway, web_gateway: &WebGateway) -> String {
+    format!(
+        "{}.{}.{}",
+        gateway.metadata.name.as_deref().unwrap(),
+        gateway.metadata.namespace.as_deref().unwrap(),
+        web_gateway.hostname
+    )
+}
+
+/// A gateway's own listener, accepting routes only from the gateway's namespace. It serves HTTPS
+/// with the gateway's `tls_secret_name` if it has one, and plain HTTP otherwise.
+fn render_gateway_listener(
+    gateway: &ComputerGateway,
+    web_gateway: &WebGateway,
+) -> GatewayListeners {
+    let gateway_namespace = gateway.metadata.namespace.as_deref().unwrap();
+    let tls = gateway
+        .spec
+        .tls_secret_name
+        .as_ref()
+        .map(|secret| GatewayListenersTls {
+            certificate_refs: Some(vec![GatewayListenersTlsCertificateRefs {
+                kind: Some("Secret".to_string()),
+                name: secret.clone(),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        });
+    let (protocol, port) = if tls.is_some() {
+        ("HTTPS", 443)
+    } else {
+        ("HTTP", 80)
+    };
+
+    GatewayListeners {
+        name: gateway_listener_name(gateway),
+        protocol: protocol.to_string(),
+        port,
+        hostname: Some(gateway_hostname(gateway, web_gateway)),
+        allowed_routes: Some(GatewayListenersAllowedRoutes {
+            namespaces: Some(GatewayListenersAllowedRoutesNamespaces {
+                from: Some(GatewayListenersAllowedRoutesNamespacesFrom::Selector),
+                selector: Some(GatewayListenersAllowedRoutesNamespacesSelector {
+                    match_labels: Some(
+                        [(
+                            "kubernetes.io/metadata.name".to_string(),
+                            gateway_namespace.to_string(),
+                        )]
+                        .into(),
+                    ),
+                    ..Default::default()
+                }),
+            }),
+            ..Default::default()
+        }),
+        tls,
+    }
+}
+
+/// The shared Gateway: HTTPS for routes from any namespace, plain HTTP for ACME challenges, and a
+/// listener of each of `gateways` own, ordered by name so that reconciles render it identically
+pub fn render_web_gateway(
+    web_gateway: &WebGateway,
+    namespace: &str,
+    gateways: &[ComputerGateway],
+) -> Gateway {
+    let mut gateway_listeners = gateways
+        .iter()
+        .map(|gateway| render_gateway_listener(gateway, web_gateway))
+        .collect::<Vec<_>>();
+    gateway_listeners.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut rendered = Gateway::new(
+        &web_gateway.name,
+        GatewaySpec {
+            gateway_class_name: web_gateway.class_name.clone(),
+            listeners: [
+                GatewayListeners {
+                    name: HTTPS_LISTENER.to_string(),
+                    protocol: "HTTPS".to_string(),
+                    port: 443,
+                    hostname: Some(web_gateway.hostname.clone()),
+                    allowed_routes: Some(GatewayListenersAllowedRoutes {
+                        namespaces: Some(GatewayListenersAllowedRoutesNamespaces {
+                            from: Some(GatewayListenersAllowedRoutesNamespacesFrom::All),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }),
+                    tls: Some(GatewayListenersTls {
+                        certificate_refs: Some(vec![GatewayListenersTlsCertificateRefs {
+                            kind: Some("Secret".to_string()),
+                            name: web_gateway.tls_secret.clone(),
+                            ..Default::default()
+                        }]),
+                        ..Default::default()
+                    }),
+                },
+                GatewayListeners {
+                    name: ACME_LISTENER.to_string(),
+                    protocol: "HTTP".to_string(),
+                    port: 80,
+                    hostname: Some(web_gateway.hostname.clone()),
+                    allowed_routes: Some(GatewayListenersAllowedRoutes {
+                        namespaces: Some(GatewayListenersAllowedRoutesNamespaces {
+                            from: Some(GatewayListenersAllowedRoutesNamespacesFrom::Same),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }),
+                    tls: None,
+                },
+            ]
+            .into_iter()
+            .chain(gateway_listeners)
+            .collect(),
+            ..Default::default()
+        },
+    );
+    rendered.metadata.namespace = Some(namespace.to_string());
+    rendered
 }
 
 #[instrument(level = Level::DEBUG, skip(client))]
 async fn create_gateway_hub(
     client: &Client,
     gateway: &ComputerGateway,
+    web_gateway: &WebGateway,
     controller_namespace: String,
+    api_options: ApiOptions,
 ) -> Result<()> {
     let gateway_namespace = gateway.metadata.namespace.as_deref().unwrap();
     let gateway_name = gateway.metadata.name.as_deref().unwrap();
 
-    let deployment_name = format!("rednet-gateway-{}", gateway_name);
+    let deployment_name = deployment_name(gateway_name);
 
-    let configmaps = Api::<ConfigMap>::namespaced(client.clone(), gateway_namespace);
-    let deployments = Api::<Deployment>::namespaced(client.clone(), gateway_namespace);
-    let services = Api::<Service>::namespaced(client.clone(), gateway_namespace);
-    let routes = Api::<HTTPRoute>::namespaced(client.clone(), gateway_namespace);
+    let configmaps = ReconcileApi::new(
+        Api::<ConfigMap>::namespaced(client.clone(), gateway_namespace),
+        api_options,
+    );
+    let deployments = ReconcileApi::new(
+        Api::<Deployment>::namespaced(client.clone(), gateway_namespace),
+        api_options,
+    );
+    let services = ReconcileApi::new(
+        Api::<Service>::namespaced(client.clone(), gateway_namespace),
+        api_options,
+    );
+    let routes = ReconcileApi::new(
+        Api::<HTTPRoute>::namespaced(client.clone(), gateway_namespace),
+        api_options,
+    );
 
     let pp = PatchParams::apply(MANAGER_NAME);
 
@@ -108,15 +425,7 @@ async fn create_gateway_hub(
                     )?]),
                     ..Default::default()
                 },
-                data: Some(
-                    [(
-                        "rednet".to_string(),
-                        serde_yaml_ng::to_string(&RednetGatewayConfigMapData {
-                            routes: gateway.spec.routes.clone(),
-                        })?,
-                    )]
-                    .into(),
-                ),
+                data: Some([("rednet".to_string(), render_rednet_config(gateway)?)].into()),
                 ..Default::default()
             }),
         )
@@ -171,9 +480,33 @@ async fn create_gateway_hub(
                                     ..Default::default()
                                 }
                             ]),
+                            resources: Some(gateway.spec.resources.clone().unwrap_or_else(default_gateway_resources)),
                             ..Default::default()
                         }
                     ],
+                    affinity: Some(k8s_openapi::api::core::v1::Affinity {
+                        // Prefer spreading replicas across nodes, without blocking scheduling on small clusters
+                        pod_anti_affinity: Some(k8s_openapi::api::core::v1::PodAntiAffinity {
+                            preferred_during_scheduling_ignored_during_execution: Some(vec![
+                                k8s_openapi::api::core::v1::WeightedPodAffinityTerm {
+                                    weight: 100,
+                                    pod_affinity_term: k8s_openapi::api::core::v1::PodAffinityTerm {
+                                        label_selector: Some(k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector {
+                                            match_labels: Some(
+                                                [("app".to_string(), deployment_name.clone())]
+                                                    .into(),
+                                            ),
+                                            ..Default::default()
+                                        }),
+                                        topology_key: "kubernetes.io/hostname".to_string(),
+                                        ..Default::default()
+                                    },
+                                }
+                            ]),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }),
                     volumes: Some(vec![
                         k8s_openapi::api::core::v1::Volume {
                             name: "config".to_string(),
@@ -224,68 +557,833 @@ async fn create_gateway_hub(
         .patch(
             &deployment_name,
             &pp,
-            &Patch::Apply(HTTPRoute {
-                metadata: ObjectMeta {
-                    name: Some(deployment_name.clone()),
-                    namespace: Some(gateway_namespace.to_string()),
-                    owner_references: Some(vec![owner_ref_from_object_ref(
-                        &gateway.object_ref(&()),
-                    )?]),
-                    ..Default::default()
-                },
-                spec: HTTPRouteSpec {
-                    parent_refs: Some(vec![HTTPRouteParentRefs {
-                        name: "cc-web-gateway".to_string(),
-                        namespace: Some(controller_namespace.clone()),
-                        section_name: Some("cc-web-gateway".to_string()),
-                        ..Default::default()
-                    }]),
-                    rules: Some(vec![HTTPRouteRules {
-                        matches: Some(vec![HTTPRouteRulesMatches {
-                            path: Some(HTTPRouteRulesMatchesPath {
-                                value: Some(format!("/{gateway_name}")),
-                                ..Default::default()
-                            }),
-                            ..Default::default()
-                        }]),
-                        filters: Some(vec![HTTPRouteRulesFilters {
-                            r#type: HTTPRouteRulesFiltersType::UrlRewrite,
-                            extension_ref: None,
-                            request_header_modifier: None,
-                            request_mirror: None,
-                            response_header_modifier: None,
-                            request_redirect: None,
-                            url_rewrite: Some(HTTPRouteRulesFiltersUrlRewrite {
-                                path: Some(HTTPRouteRulesFiltersUrlRewritePath {
-                                    r#type:
-                                        HTTPRouteRulesFiltersUrlRewritePathType::ReplacePrefixMatch,
-                                    replace_prefix_match: Some("/".to_string()),
-                                    replace_full_path: None,
-                                }),
-                                hostname: None,
-                            }),
-                        }]),
-                        backend_refs: Some(vec![HTTPRouteRulesBackendRefs {
-                            name: deployment_name.clone(),
-                            port: Some(8000),
-                            ..Default::default()
-                        }]),
+            &Patch::Apply(render_http_route(
+                gateway,
+                web_gateway,
+                &controller_namespace,
+            )?),
+        )
+        .await?;
+
+    Ok(())
+}
+
+/// A gateway's HTTPRoute, serving `/<name>` on the shared HTTPS listener and on the gateway's own
+fn render_http_route(
+    gateway: &ComputerGateway,
+    web_gateway: &WebGateway,
+    controller_namespace: &str,
+) -> Result<HTTPRoute> {
+    let gateway_namespace = gateway.metadata.namespace.as_deref().unwrap();
+    let gateway_name = gateway.metadata.name.as_deref().unwrap();
+    let deployment_name = deployment_name(gateway_name);
+    let parent_ref = |section_name: String| HTTPRouteParentRefs {
+        name: web_gateway.name.clone(),
+        namespace: Some(controller_namespace.to_string()),
+        section_name: Some(section_name),
+        ..Default::default()
+    };
+
+    Ok(HTTPRoute {
+        metadata: ObjectMeta {
+            name: Some(deployment_name.clone()),
+            namespace: Some(gateway_namespace.to_string()),
+            owner_references: Some(vec![owner_ref_from_object_ref(&gateway.object_ref(&()))?]),
+            ..Default::default()
+        },
+        spec: HTTPRouteSpec {
+            parent_refs: Some(vec![
+                parent_ref(HTTPS_LISTENER.to_string()),
+                parent_ref(gateway_listener_name(gateway)),
+            ]),
+            rules: Some(vec![HTTPRouteRules {
+                matches: Some(vec![HTTPRouteRulesMatches {
+                    path: Some(HTTPRouteRulesMatchesPath {
+                        value: Some(format!("/{gateway_name}")),
                         ..Default::default()
-                    }]),
+                    }),
                     ..Default::default()
-                },
+                }]),
+                filters: Some(vec![HTTPRouteRulesFilters {
+                    r#type: HTTPRouteRulesFiltersType::UrlRewrite,
+                    extension_ref: None,
+                    request_header_modifier: None,
+                    request_mirror: None,
+                    response_header_modifier: None,
+                    request_redirect: None,
+                    url_rewrite: Some(HTTPRouteRulesFiltersUrlRewrite {
+                        path: Some(HTTPRouteRulesFiltersUrlRewritePath {
+                            r#type: HTTPRouteRulesFiltersUrlRewritePathType::ReplacePrefixMatch,
+                            replace_prefix_match: Some("/".to_string()),
+                            replace_full_path: None,
+                        }),
+                        hostname: None,
+                    }),
+                }]),
+                backend_refs: Some(vec![HTTPRouteRulesBackendRefs {
+                    name: deployment_name.clone(),
+                    port: Some(8000),
+                    ..Default::default()
+                }]),
                 ..Default::default()
-            }),
+            }]),
+            ..Default::default()
+        },
+        ..Default::default()
+    })
+}
+
+/// Emit a warning Event for each link whose `host_id` doesn't match a Computer in the gateway's namespace
+#[instrument(level = Level::DEBUG, skip(context))]
+async fn check_links(context: &ReconcilerCtx, gateway: &ComputerGateway) -> Result<()> {
+    if gateway.spec.links.is_empty() {
+        return Ok(());
+    }
+
+    let gateway_namespace = gateway.metadata.namespace.as_deref().unwrap();
+    let computer_ids = ReconcileApi::new(
+        Api::<Computer>::namespaced(context.client.clone(), gateway_namespace),
+        context.api_options,
+    )
+    .list(&ListParams::default())
+    .await?
+    .into_iter()
+    .map(|computer| computer.spec.id)
+    .collect::<HashSet<_>>();
+
+    for link in &gateway.spec.links {
+        if computer_ids.contains(&link.host_id) {
+            continue;
+        }
+        if context.api_options.observe_only {
+            tracing::info!(
+                "Observe-only, not publishing UnknownLinkHost event for link host {}",
+                link.host_id
+            );
+            continue;
+        }
+
+        context
+            .recorder
+            .publish(
+                &Event {
+                    type_: EventType::Warning,
+                    reason: "UnknownLinkHost".to_string(),
+                    note: Some(format!(
+                        "Link host {} doesn't match any Computer in namespace {}",
+                        link.host_id, gateway_namespace
+                    )),
+                    action: "CheckLinks".to_string(),
+                    secondary: None,
+                },
+                &gateway.object_ref(&()),
+            )
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Warn with an event about routes that overlap, see [`overlapping_routes`]
+async fn check_routes(context: &ReconcilerCtx, gateway: &ComputerGateway) -> Result<()> {
+    let overlaps = overlapping_routes(&gateway.spec.routes);
+    if overlaps.is_empty() {
+        return Ok(());
+    }
+    if context.api_options.observe_only {
+        tracing::info!("Observe-only, not publishing OverlappingRoutes event: {overlaps:?}");
+        return Ok(());
+    }
+
+    context
+        .recorder
+        .publish(
+            &Event {
+                type_: EventType::Warning,
+                reason: "OverlappingRoutes".to_string(),
+                note: Some(overlaps.join("; ")),
+                action: "CheckRoutes".to_string(),
+                secondary: None,
+            },
+            &gateway.object_ref(&()),
         )
         .await?;
 
     Ok(())
 }
 
+/// Routes sharing a host and prefix with an earlier route, which the gateway always picks instead
+/// since it uses the first match. Each is described by index, for an event or condition message.
+fn overlapping_routes(routes: &[HttpOverRednetRoute]) -> Vec<String> {
+    let mut first_seen = HashMap::new();
+    let mut overlaps = vec![];
+
+    for (index, route) in routes.iter().enumerate() {
+        // Path components ignore duplicate and trailing slashes, as the gateway does
+        let key = (
+            route.host.as_deref().map(str::to_ascii_lowercase),
+            route.prefix.components().collect::<PathBuf>(),
+        );
+        let Some(&first) = first_seen.get(&key) else {
+            first_seen.insert(key, index);
+            continue;
+        };
+
+        let relation = if routes[first] == *route {
+            "duplicates"
+        } else {
+            "conflicts with"
+        };
+        overlaps.push(format!(
+            "route {index} ({}) {relation} route {first}, which takes precedence",
+            route.prefix.display()
+        ));
+    }
+
+    overlaps
+}
+
+/// `RoutesUnambiguous` is `False` while any route overlaps an earlier one
+fn routes_condition(gateway: &ComputerGateway) -> Result<Condition> {
+    let overlaps = overlapping_routes(&gateway.spec.routes);
+    let (status, reason, message) = if overlaps.is_empty() {
+        ("True", "NoOverlappingRoutes", String::new())
+    } else {
+        ("False", "OverlappingRoutes", overlaps.join("; "))
+    };
+
+    let mut condition: Condition = serde_json::from_value(json!({
+        "type": ROUTES_CONDITION,
+        "status": status,
+        "reason": reason,
+        "message": message,
+        "observedGeneration": gateway.metadata.generation,
+        "lastTransitionTime": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+    }))?;
+
+    // Only move the transition time when the status actually flips
+    if let Some(previous) = gateway.status.as_ref().and_then(|status| {
+        status
+            .conditions
+            .iter()
+            .find(|c| c.type_ == ROUTES_CONDITION && c.status == condition.status)
+    }) {
+        condition.last_transition_time = previous.last_transition_time.clone();
+    }
+
+    Ok(condition)
+}
+
+/// Status reported by the gateway's `/status` endpoint
+#[derive(Deserialize)]
+struct GatewayStatusReport {
+    connected_listeners: u32,
+}
+
+/// Ask the gateway how many listeners are linked to it, through the API server's service proxy
+#[instrument(level = Level::DEBUG, skip(client))]
+async fn fetch_gateway_report(
+    client: &Client,
+    gateway: &ComputerGateway,
+    api_options: ApiOptions,
+) -> Result<GatewayStatusReport> {
+    let gateway_namespace = gateway.metadata.namespace.as_deref().unwrap();
+    let gateway_name = gateway.metadata.name.as_deref().unwrap();
+
+    let uri = format!(
+        "/api/v1/namespaces/{gateway_namespace}/services/{}:8000/proxy/status",
+        deployment_name(gateway_name)
+    );
+
+    call_api(api_options, || async {
+        let request = http::Request::get(&uri)
+            .body(vec![])
+            .map_err(kube::Error::HttpError)?;
+        client.request(request).await
+    })
+    .await
+}
+
+/// Report the gateway's listeners and mark it `Reconciled=True` on its status subresource
+#[instrument(level = Level::DEBUG, skip(client))]
+async fn update_gateway_status(
+    client: &Client,
+    gateway: &ComputerGateway,
+    api_options: ApiOptions,
+) -> Result<()> {
+    // The gateway may not be up yet, in which case its last report stands
+    let report = match fetch_gateway_report(client, gateway, api_options).await {
+        Ok(report) => Some((report, chrono::Utc::now().timestamp())),
+        Err(e) => {
+            tracing::warn!("Failed to fetch gateway status: {:?}", e);
+            None
+        }
+    };
+
+    let mut status = gateway.status.clone().unwrap_or_default();
+    if let Some((report, reported_at)) = report {
+        status.connected_listeners = report.connected_listeners;
+        status.last_report_unix_sec = Some(reported_at);
+    }
+
+    status.reconcile_failures = 0;
+    let condition = reconciled_condition(&status.conditions, gateway.metadata.generation, None)?;
+    set_condition(&mut status.conditions, condition);
+    set_condition(&mut status.conditions, routes_condition(gateway)?);
+    apply_gateway_status(client, gateway, api_options, status).await
+}
+
+/// Mark the gateway `Reconciled=False` with `error`, leaving the rest of its last status as it was
+async fn record_reconcile_error(
+    client: &Client,
+    gateway: &ComputerGateway,
+    error: &Error,
+    api_options: ApiOptions,
+) -> Result<()> {
+    let mut status = gateway.status.clone().unwrap_or_default();
+    status.reconcile_failures = (status.reconcile_failures + 1).min(MAX_RECONCILE_FAILURES);
+    let condition =
+        reconciled_condition(&status.conditions, gateway.metadata.generation, Some(error))?;
+    set_condition(&mut status.conditions, condition);
+    apply_gateway_status(client, gateway, api_options, status).await
+}
+
+/// Apply a status to the gateway. Only this controller's field manager writes these fields, and the
+/// patch leaves out `resourceVersion`, so concurrent updates to the gateway don't conflict with it.
+async fn apply_gateway_status(
+    client: &Client,
+    gateway: &ComputerGateway,
+    api_options: ApiOptions,
+    status: ComputerGatewayStatus,
+) -> Result<()> {
+    let gateway_namespace = gateway.metadata.namespace.as_deref().unwrap();
+    let gateway_name = gateway
+        .metadata
+        .name
+        .as_deref()
+        .ok_or(Error::MissingField)?;
+
+    ReconcileApi::new(
+        Api::<ComputerGateway>::namespaced(client.clone(), gateway_namespace),
+        api_options,
+    )
+    .patch_status(
+        gateway_name,
+        &PatchParams::apply(MANAGER_NAME),
+        &Patch::Apply(json!({
+            "apiVersion": ComputerGateway::api_version(&()),
+            "kind": ComputerGateway::kind(&()),
+            "status": status,
+        })),
+    )
+    .await
+}
+
+/// Permissions the gateway controller needs to run, granted through its ClusterRole
+pub fn controller_policy_rules() -> Vec<PolicyRule> {
+    const RW: &[&str] = &["get", "list", "watch", "create", "update", "patch"];
+
+    vec![
+        policy_rule("", &["namespaces"], &["get", "list"]),
+        policy_rule(
+            "coordination.k8s.io",
+            &["leases"],
+            &["get", "create", "update"],
+        ),
+        policy_rule("authentication.k8s.io", &["tokenreviews"], &["create"]),
+        policy_rule("", &["configmaps", "services"], RW),
+        policy_rule("", &["services/proxy"], &["get"]),
+        policy_rule("events.k8s.io", &["events"], &["create", "patch"]),
+        policy_rule("apps", &["deployments"], RW),
+        policy_rule(
+            "gateway.networking.k8s.io",
+            &["gateways"],
+            &["get", "create", "patch"],
+        ),
+        policy_rule(
+            "gateway.networking.k8s.io",
+            &["httproutes"],
+            &[
+                "get", "list", "watch", "create", "update", "patch", "delete",
+            ],
+        ),
+        policy_rule("smcs.dev", &["computerclusters"], &["get", "list", "watch"]),
+        policy_rule("smcs.dev", &["computers"], &["get", "list"]),
+        policy_rule("smcs.dev", &["computergateways"], &["get", "list", "watch"]),
+        policy_rule("smcs.dev", &["computergateways/status"], &["patch"]),
+        // Setting blockOwnerDeletion on an owner reference requires updating the owner's finalizers
+        policy_rule("smcs.dev", &["computergateways/finalizers"], &["update"]),
+    ]
+}
+
+/// Name shared by the Deployment, ConfigMap, Service and HTTPRoute backing a ComputerGateway
+pub fn deployment_name(gateway_name: &str) -> String {
+    format!("rednet-gateway-{}", gateway_name)
+}
+
+/// Resources for the gateway container when the ComputerGateway doesn't specify any
+fn default_gateway_resources() -> ResourceRequirements {
+    ResourceRequirements {
+        requests: Some(
+            [
+                ("cpu".to_string(), Quantity("50m".to_string())),
+                ("memory".to_string(), Quantity("64Mi".to_string())),
+            ]
+            .into(),
+        ),
+        limits: Some([("memory".to_string(), Quantity("128Mi".to_string()))].into()),
+        ..Default::default()
+    }
+}
+
+/// Render the rednet config that the gateway's ConfigMap carries for the gateway deployment
+pub fn render_rednet_config(gateway: &ComputerGateway) -> Result<String> {
+    Ok(serde_yaml_ng::to_string(&RednetGatewayConfigMapData {
+        routes: gateway.spec.routes.clone(),
+        default_backend: gateway.spec.default_backend.clone(),
+    })?)
+}
+
 fn error_policy(
-    _object: Arc<ComputerGateway>,
+    object: Arc<ComputerGateway>,
     _error: &Error,
     _context: Arc<ReconcilerCtx>,
 ) -> Action {
-    Action::requeue(Duration::from_secs(10))
+    // The object predates this failure, so count it on top of the ones already recorded
+    let failures = object
+        .status
+        .as_ref()
+        .map_or(0, |status| status.reconcile_failures);
+    Action::requeue(error_backoff(failures + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::http::Method;
+
+    use super::*;
+    use crate::{
+        api::ComputerGatewaySpec,
+        testing::{api_options, mock_client, next_request},
+    };
+
+    fn web_gateway(class_name: &str) -> WebGateway {
+        WebGateway {
+            name: "web".to_string(),
+            class_name: class_name.to_string(),
+            hostname: "example.com".to_string(),
+            tls_secret: "web-tls".to_string(),
+        }
+    }
+
+    #[test]
+    fn web_gateway_uses_configured_class() {
+        let rendered = render_web_gateway(&web_gateway("istio"), "computercraft", &[]);
+
+        assert_eq!(rendered.metadata.name.as_deref(), Some("web"));
+        assert_eq!(
+            rendered.metadata.namespace.as_deref(),
+            Some("computercraft")
+        );
+        assert_eq!(rendered.spec.gateway_class_name, "istio");
+
+        let yaml = serde_yaml_ng::to_string(&rendered).unwrap();
+        assert!(yaml.contains("gatewayClassName: istio"), "{yaml}");
+    }
+
+    #[test]
+    fn web_gateway_serves_hostname_with_tls() {
+        let rendered = render_web_gateway(&web_gateway("cilium"), "computercraft", &[]);
+
+        let https = rendered
+            .spec
+            .listeners
+            .iter()
+            .find(|listener| listener.name == HTTPS_LISTENER)
+            .unwrap();
+        assert_eq!(https.port, 443);
+        assert_eq!(https.hostname.as_deref(), Some("example.com"));
+        let certificate_refs = https.tls.as_ref().unwrap().certificate_refs.as_ref();
+        assert_eq!(certificate_refs.unwrap()[0].name, "web-tls");
+    }
+
+    fn computer_gateway(namespace: &str, name: &str) -> ComputerGateway {
+        let spec = serde_json::from_value(json!({ "routes": [], "links": [] })).unwrap();
+        let mut gateway = ComputerGateway::new(name, spec);
+        gateway.metadata.namespace = Some(namespace.to_string());
+        gateway.metadata.uid = Some(format!("{namespace}-{name}-uid"));
+        gateway
+    }
+
+    fn listener_names(rendered: &Gateway) -> Vec<&str> {
+        rendered
+            .spec
+            .listeners
+            .iter()
+            .map(|listener| listener.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn each_gateway_gets_its_own_listener_in_a_stable_order() {
+        let gateways = [
+            computer_gateway("team-b", "a"),
+            computer_gateway("team-a", "a-public"),
+            computer_gateway("team-a", "a"),
+        ];
+        let rendered = render_web_gateway(&web_gateway("cilium"), "computercraft", &gateways);
+        assert_eq!(
+            listener_names(&rendered),
+            [
+                HTTPS_LISTENER,
+                ACME_LISTENER,
+                "team-a.a",
+                "team-a.a-public",
+                "team-b.a"
+            ]
+        );
+
+        let mut reversed = gateways.clone();
+        reversed.reverse();
+        let rerendered = render_web_gateway(&web_gateway("cilium"), "computercraft", &reversed);
+        assert_eq!(
+            serde_json::to_value(&rendered).unwrap(),
+            serde_json::to_value(&rerendered).unwrap()
+        );
+
+        let own = &rendered.spec.listeners[2];
+        assert_eq!(own.hostname.as_deref(), Some("a.team-a.example.com"));
+        let namespaces = own.allowed_routes.as_ref().unwrap().namespaces.as_ref();
+        let selector = namespaces.unwrap().selector.as_ref().unwrap();
+        assert_eq!(
+            selector.match_labels.as_ref().unwrap()["kubernetes.io/metadata.name"],
+            "team-a"
+        );
+    }
+
+    #[test]
+    fn gateway_listener_without_tls_serves_plain_http() {
+        let gateway = computer_gateway("team-a", "a");
+        let listener = render_gateway_listener(&gateway, &web_gateway("cilium"));
+
+        assert_eq!(listener.protocol, "HTTP");
+        assert_eq!(listener.port, 80);
+        assert!(listener.tls.is_none());
+    }
+
+    #[test]
+    fn gateway_listener_with_tls_serves_https() {
+        let mut gateway = computer_gateway("team-a", "a");
+        gateway.spec.tls_secret_name = Some("team-a-tls".to_string());
+        let rendered = render_web_gateway(&web_gateway("cilium"), "computercraft", &[gateway]);
+
+        let listener = rendered
+            .spec
+            .listeners
+            .iter()
+            .find(|listener| listener.name == "team-a.a")
+            .unwrap();
+        assert_eq!(listener.protocol, "HTTPS");
+        assert_eq!(listener.port, 443);
+        assert_eq!(listener.hostname.as_deref(), Some("a.team-a.example.com"));
+        let certificate_refs = listener.tls.as_ref().unwrap().certificate_refs.as_ref();
+        let certificate_ref = &certificate_refs.unwrap()[0];
+        assert_eq!(certificate_ref.name, "team-a-tls");
+        assert_eq!(certificate_ref.kind.as_deref(), Some("Secret"));
+        // The Secret lives alongside the Gateway, so no ReferenceGrant is needed
+        assert!(certificate_ref.namespace.is_none());
+    }
+
+    #[test]
+    fn http_routes_attach_to_their_gateways_listener() {
+        let web_gateway = web_gateway("cilium");
+        let gateways = [
+            computer_gateway("team-a", "a"),
+            computer_gateway("team-b", "a"),
+        ];
+        let rendered = render_web_gateway(&web_gateway, "computercraft", &gateways);
+        let listeners = listener_names(&rendered);
+
+        let sections = gateways
+            .iter()
+            .map(|gateway| {
+                let route = render_http_route(gateway, &web_gateway, "computercraft").unwrap();
+                let parent_refs = route.spec.parent_refs.unwrap();
+                assert!(parent_refs.iter().all(|parent| parent.name == "web"
+                    && parent.namespace.as_deref() == Some("computercraft")));
+                parent_refs
+                    .into_iter()
+                    .map(|parent| parent.section_name.unwrap())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(sections[0], [HTTPS_LISTENER, "team-a.a"]);
+        assert_eq!(sections[1], [HTTPS_LISTENER, "team-b.a"]);
+        for section in sections.iter().flatten() {
+            assert!(listeners.contains(&section.as_str()), "{section}");
+        }
+    }
+
+    #[tokio::test]
+    async fn status_apply_leaves_out_resource_version() {
+        let mut gateway = computer_gateway("team-a", "a");
+        gateway.metadata.resource_version = Some("42".to_string());
+
+        let (client, mut server) = mock_client();
+        let api_server = tokio::spawn(async move {
+            let request = next_request(&mut server).await;
+            assert_eq!(request.method, Method::PATCH);
+            assert_eq!(
+                request.path,
+                "/apis/smcs.dev/v1/namespaces/team-a/computergateways/a/status"
+            );
+            assert!(request.query.contains("fieldManager=cc-gateway-controller"));
+            let body = request.body.clone();
+            request.respond_with_body();
+            body
+        });
+
+        record_reconcile_error(&client, &gateway, &Error::MissingField, api_options())
+            .await
+            .unwrap();
+
+        let applied = api_server.await.unwrap();
+        assert!(
+            applied["metadata"]["resourceVersion"].is_null(),
+            "{applied}"
+        );
+        assert_eq!(applied["status"]["reconcile_failures"], 1);
+        assert_eq!(applied["status"]["conditions"][0]["status"], "False");
+    }
+
+    fn route(host: Option<&str>, prefix: &str, protocol: &str) -> HttpOverRednetRoute {
+        serde_json::from_value(json!({
+            "host": host,
+            "prefix": prefix,
+            "backend": { "anycast": { "protocol": protocol } },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn distinct_routes_dont_overlap() {
+        let routes = [
+            route(None, "/api", "api"),
+            route(None, "/web", "web"),
+            route(Some("a.example.com"), "/api", "api"),
+            route(None, "/api/v2", "api"),
+        ];
+        assert!(overlapping_routes(&routes).is_empty());
+    }
+
+    #[test]
+    fn identical_routes_are_duplicates() {
+        let routes = [route(None, "/api", "api"), route(None, "/api", "api")];
+        assert_eq!(
+            overlapping_routes(&routes),
+            ["route 1 (/api) duplicates route 0, which takes precedence"]
+        );
+    }
+
+    #[test]
+    fn routes_sharing_a_prefix_conflict() {
+        let routes = [
+            route(None, "/api", "api"),
+            route(None, "/web", "web"),
+            route(None, "/api", "other"),
+        ];
+        assert_eq!(
+            overlapping_routes(&routes),
+            ["route 2 (/api) conflicts with route 0, which takes precedence"]
+        );
+    }
+
+    #[test]
+    fn prefixes_are_compared_as_the_gateway_matches_them() {
+        let routes = [
+            route(Some("Example.com"), "/api", "api"),
+            route(Some("example.com"), "//api/", "other"),
+        ];
+        assert_eq!(
+            overlapping_routes(&routes),
+            ["route 1 (//api/) conflicts with route 0, which takes precedence"]
+        );
+    }
+
+    #[test]
+    fn every_later_overlap_is_reported_against_the_first() {
+        let routes = [
+            route(None, "/api", "api"),
+            route(None, "/api", "api"),
+            route(None, "/api", "other"),
+        ];
+        assert_eq!(
+            overlapping_routes(&routes),
+            [
+                "route 1 (/api) duplicates route 0, which takes precedence",
+                "route 2 (/api) conflicts with route 0, which takes precedence",
+            ]
+        );
+    }
+
+    /// A copy of the config types in the gateway crate, strict about unknown fields so that
+    /// anything the controller renders but the gateway would ignore fails the round trip
+    mod gateway_config {
+        use std::{collections::HashMap, path::PathBuf};
+
+        use serde::Deserialize;
+
+        #[derive(Debug, Deserialize)]
+        #[serde(deny_unknown_fields)]
+        pub struct RednetConfig {
+            pub routes: Vec<HttpOverRednetRoute>,
+            #[serde(default)]
+            pub default_backend: Option<RednetRpcDestination>,
+        }
+
+        #[derive(Debug, Deserialize)]
+        #[serde(deny_unknown_fields)]
+        pub struct HttpOverRednetRoute {
+            pub prefix: PathBuf,
+            #[serde(default)]
+            pub strip_prefix: bool,
+            #[serde(default)]
+            pub rewrite_path: Option<String>,
+            #[serde(default)]
+            pub host: Option<String>,
+            #[serde(default)]
+            pub backend: Option<RednetRpcDestination>,
+            #[serde(default)]
+            pub backends: Vec<WeightedBackend>,
+            #[serde(default)]
+            pub request_headers_set: HashMap<String, String>,
+            #[serde(default)]
+            pub request_headers_remove: Vec<String>,
+            #[serde(default)]
+            pub response_headers_set: HashMap<String, String>,
+            #[serde(default)]
+            pub websocket: bool,
+            #[serde(default)]
+            pub cache_ttl_secs: Option<u64>,
+            #[serde(default)]
+            pub mirror: Option<RednetRpcDestination>,
+        }
+
+        #[derive(Debug, Deserialize)]
+        #[serde(deny_unknown_fields)]
+        pub struct WeightedBackend {
+            pub backend: RednetRpcDestination,
+            pub weight: u32,
+        }
+
+        #[derive(Debug, PartialEq, Eq, Deserialize)]
+        #[serde(rename_all = "camelCase", deny_unknown_fields)]
+        pub enum RednetRpcDestination {
+            Anycast {
+                protocol: String,
+            },
+            Computer {
+                id: String,
+                protocol: Option<String>,
+            },
+            #[serde(alias = "hostname")]
+            Host {
+                protocol: String,
+                host: String,
+            },
+            Sharded {
+                protocol: String,
+                key_header: String,
+            },
+            Capable {
+                protocol: String,
+                capabilities: Vec<String>,
+            },
+        }
+    }
+
+    #[test]
+    fn rendered_rednet_config_parses_as_the_gateway_reads_it() {
+        use gateway_config::{RednetConfig, RednetRpcDestination};
+
+        let spec: ComputerGatewaySpec = serde_json::from_value(json!({
+            "links": [],
+            "routes": [
+                {
+                    "prefix": "/api",
+                    "strip_prefix": true,
+                    "host": "*.example.com",
+                    "backends": [
+                        { "backend": { "anycast": { "protocol": "api" } }, "weight": 90 },
+                        { "backend": { "computer": { "id": "7", "protocol": null } }, "weight": 10 },
+                    ],
+                    "request_headers_set": { "X-Env": "prod" },
+                    "request_headers_remove": ["Cookie"],
+                    "response_headers_set": { "Cache-Control": "no-store" },
+                    "cache_ttl_secs": 30,
+                    "mirror": { "sharded": { "protocol": "shadow", "key_header": "X-User" } },
+                },
+                {
+                    "prefix": "/ws",
+                    "rewrite_path": "/socket/{rest}",
+                    "backend": { "hostname": { "protocol": "chat", "host": "chat-server" } },
+                    "websocket": true,
+                },
+            ],
+            "default_backend": { "capable": { "protocol": "web", "capabilities": ["static"] } },
+        }))
+        .unwrap();
+        let gateway = ComputerGateway::new("web", spec);
+
+        let rendered = render_rednet_config(&gateway).unwrap();
+        let parsed: RednetConfig = serde_yaml_ng::from_str(&rendered)
+            .unwrap_or_else(|e| panic!("gateway can't read rendered config: {e}\n{rendered}"));
+
+        let [api, ws] = parsed.routes.as_slice() else {
+            panic!("{parsed:?}");
+        };
+        assert_eq!(api.prefix, PathBuf::from("/api"));
+        assert!(api.strip_prefix);
+        assert_eq!(api.host.as_deref(), Some("*.example.com"));
+        assert!(api.backend.is_none());
+        assert_eq!(api.backends.len(), 2);
+        assert_eq!(api.backends[0].weight, 90);
+        assert_eq!(
+            api.backends[1].backend,
+            RednetRpcDestination::Computer {
+                id: "7".to_string(),
+                protocol: None
+            }
+        );
+        assert_eq!(api.request_headers_set["X-Env"], "prod");
+        assert_eq!(api.request_headers_remove, ["Cookie"]);
+        assert_eq!(api.response_headers_set["Cache-Control"], "no-store");
+        assert_eq!(api.cache_ttl_secs, Some(30));
+        assert_eq!(
+            api.mirror,
+            Some(RednetRpcDestination::Sharded {
+                protocol: "shadow".to_string(),
+                key_header: "X-User".to_string()
+            })
+        );
+
+        assert_eq!(ws.rewrite_path.as_deref(), Some("/socket/{rest}"));
+        assert!(ws.websocket);
+        assert_eq!(
+            ws.backend,
+            Some(RednetRpcDestination::Host {
+                protocol: "chat".to_string(),
+                host: "chat-server".to_string()
+            })
+        );
+
+        assert_eq!(
+            parsed.default_backend,
+            Some(RednetRpcDestination::Capable {
+                protocol: "web".to_string(),
+                capabilities: vec!["static".to_string()]
+            })
+        );
+    }
 }
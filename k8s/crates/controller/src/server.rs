@@ -0,0 +1,618 @@
+use std::{fmt::Debug, sync::Arc};
+
+use k8s_openapi::{
+    NamespaceResourceScope,
+    api::authentication::v1::{TokenReview, TokenReviewSpec, UserInfo},
+    apimachinery::pkg::apis::meta::v1::Condition,
+};
+use kube::{
+    Api, Client, Resource,
+    api::{ListParams, ObjectMeta, Patch, PatchParams, PostParams},
+};
+use rand::Rng;
+use rocket::{
+    Build, Request, Rocket, State, get,
+    http::{ContentType, Status},
+    post,
+    request::{self, FromRequest, Outcome},
+    routes,
+    serde::json::Json,
+};
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::json;
+
+use self::logs::LogBuffers;
+use crate::{
+    api::{Computer, ComputerCluster, ComputerGateway, ComputerSpec, PowerState},
+    metrics::Metrics,
+    reconcilers::{
+        ReconcileTrigger, TriggerOutcome,
+        cluster::{computer_service_account_name, has_recent_heartbeat, max_heartbeat_age_secs},
+        owner_ref_from_object_ref,
+    },
+};
+
+mod logs;
+
+struct ServerCtx {
+    client: Client,
+    metrics: Arc<Metrics>,
+    logs: LogBuffers,
+    trigger: ReconcileTrigger,
+    /// Users and groups, as a TokenReview names them, allowed to call the operator endpoints
+    operators: Vec<String>,
+}
+
+/// HTTP endpoints that computers and operators call on the controller
+pub fn rocket(
+    client: Client,
+    metrics: Arc<Metrics>,
+    trigger: ReconcileTrigger,
+    operators: Vec<String>,
+) -> Rocket<Build> {
+    rocket::build()
+        .manage(ServerCtx {
+            client,
+            metrics,
+            logs: LogBuffers::default(),
+            trigger,
+            operators,
+        })
+        .mount(
+            "/",
+            routes![
+                enroll,
+                heartbeat,
+                drained,
+                cluster_status,
+                reconcile_now,
+                metrics,
+                logs::push,
+                logs::tail
+            ],
+        )
+}
+
+/// Prometheus scrape endpoint
+#[get("/metrics")]
+fn metrics(ctx: &State<ServerCtx>) -> Result<(ContentType, String), Status> {
+    let body = ctx.metrics.encode().map_err(|e| {
+        tracing::error!("Failed to encode metrics: {:?}", e);
+        Status::InternalServerError
+    })?;
+
+    Ok((
+        ContentType::new("application", "openmetrics-text")
+            .with_params([("version", "1.0.0"), ("charset", "utf-8")]),
+        body,
+    ))
+}
+
+/// Create the Computer for a computer booting for the first time, owned by the cluster, and return it.
+/// The computer passes its own `id`, or gets a generated one; enrolling an id that's already
+/// enrolled returns the existing Computer.
+#[post("/enroll/<namespace>/<cluster>?<id>")]
+async fn enroll(
+    namespace: &str,
+    cluster: &str,
+    id: Option<&str>,
+    token: BearerToken,
+    ctx: &State<ServerCtx>,
+) -> Result<Json<Computer>, Status> {
+    authorize_computer(&ctx.client, &token, namespace, cluster).await?;
+
+    let clusters = Api::<ComputerCluster>::namespaced(ctx.client.clone(), namespace);
+    let Some(owner) = clusters.get_opt(cluster).await.map_err(internal_error)? else {
+        return Err(Status::NotFound);
+    };
+
+    let computers = Api::<Computer>::namespaced(ctx.client.clone(), namespace);
+    let id = match id {
+        Some(id) => id.to_string(),
+        None => format!("{:08x}", rand::rng().random::<u32>()),
+    };
+    if let Some(computer) = find_computer(&computers, cluster, &id).await? {
+        return Ok(Json(computer));
+    }
+
+    let owner_ref = owner_ref_from_object_ref(&owner.object_ref(&())).map_err(|e| {
+        tracing::error!("Failed to reference cluster {} as owner: {:?}", cluster, e);
+        Status::InternalServerError
+    })?;
+    let name = format!("{cluster}-{id}");
+    let computer = Computer {
+        metadata: ObjectMeta {
+            name: Some(name.clone()),
+            owner_references: Some(vec![owner_ref]),
+            ..Default::default()
+        },
+        spec: ComputerSpec {
+            id,
+            state: Default::default(),
+            drain_secs: None,
+        },
+        status: None,
+    };
+
+    match computers.create(&PostParams::default(), &computer).await {
+        Ok(computer) => {
+            tracing::info!("Enrolled computer {} in cluster {}", name, cluster);
+            Ok(Json(computer))
+        }
+        // A concurrent enrollment of the same id may have got there first, but the name is also
+        // taken by e.g. computer `b-c` of cluster `a` when enrolling computer `c` of cluster `a-b`
+        Err(kube::Error::Api(e)) if e.code == 409 => {
+            let existing = computers.get(&name).await.map_err(internal_error)?;
+            let same_owner = existing
+                .metadata
+                .owner_references
+                .as_ref()
+                .is_some_and(|owners| {
+                    owners
+                        .iter()
+                        .any(|o| Some(o.uid.as_str()) == owner.metadata.uid.as_deref())
+                });
+            if existing.spec.id != computer.spec.id || !same_owner {
+                tracing::warn!(
+                    "Computer {} already exists for another cluster or id, not enrolling",
+                    name
+                );
+                return Err(Status::Conflict);
+            }
+            Ok(Json(existing))
+        }
+        // The id doesn't make a valid object name
+        Err(kube::Error::Api(e)) if e.code == 422 => Err(Status::BadRequest),
+        Err(e) => Err(internal_error(e)),
+    }
+}
+
+/// Record a heartbeat for a computer, marking it online.
+/// A computer that has applied its desired state passes the spec `generation` it applied,
+/// and the `version` of the client script it's running.
+#[post("/heartbeat/<namespace>/<cluster>/<computer_id>?<generation>&<version>")]
+async fn heartbeat(
+    namespace: &str,
+    cluster: &str,
+    computer_id: &str,
+    generation: Option<i64>,
+    version: Option<&str>,
+    token: BearerToken,
+    ctx: &State<ServerCtx>,
+) -> Result<Status, Status> {
+    authorize_computer(&ctx.client, &token, namespace, cluster).await?;
+
+    let computers = Api::<Computer>::namespaced(ctx.client.clone(), namespace);
+    let Some(computer) = find_computer(&computers, cluster, computer_id).await? else {
+        return Err(Status::NotFound);
+    };
+
+    let mut status = json!({
+        "online": true,
+        "last_heartbeat_unix_sec": chrono::Utc::now().timestamp(),
+    });
+    if let Some(generation) = generation {
+        status["observed_generation"] = json!(generation);
+    }
+    if let Some(version) = version {
+        status["version"] = json!(version);
+    }
+
+    computers
+        .patch_status(
+            computer.metadata.name.as_deref().unwrap(),
+            &PatchParams::default(),
+            &Patch::Merge(json!({ "status": status })),
+        )
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Status::NoContent)
+}
+
+/// Acknowledge a shutdown, marking the computer offline with a `Ready=False` condition
+#[post("/drained/<namespace>/<cluster>/<computer_id>")]
+async fn drained(
+    namespace: &str,
+    cluster: &str,
+    computer_id: &str,
+    token: BearerToken,
+    ctx: &State<ServerCtx>,
+) -> Result<Status, Status> {
+    authorize_computer(&ctx.client, &token, namespace, cluster).await?;
+
+    let computers = Api::<Computer>::namespaced(ctx.client.clone(), namespace);
+    let Some(computer) = find_computer(&computers, cluster, computer_id).await? else {
+        return Err(Status::NotFound);
+    };
+    if computer.spec.drain_secs.is_none()
+        && computer.spec.state.desired_power != Some(PowerState::Off)
+    {
+        return Err(Status::Conflict);
+    }
+
+    computers
+        .patch_status(
+            computer.metadata.name.as_deref().unwrap(),
+            &PatchParams::default(),
+            &Patch::Merge(json!({
+                "status": {
+                    "online": false,
+                    "conditions": [{
+                        "type": "Ready",
+                        "status": "False",
+                        "reason": "Drained",
+                        "message": "Computer acknowledged shutdown",
+                        "lastTransitionTime": chrono::Utc::now()
+                            .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+                    }],
+                }
+            })),
+        )
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Status::NoContent)
+}
+
+#[derive(Debug, Serialize)]
+struct ClusterComputersStatus {
+    computers_online: u32,
+    computers_total: u32,
+    /// Sorted by id
+    computers: Vec<ComputerHealth>,
+}
+
+#[derive(Debug, Serialize)]
+struct ComputerHealth {
+    id: String,
+    /// Whether the computer has sent a heartbeat within its cluster's `max_heartbeat_age_secs`
+    online: bool,
+    last_heartbeat_unix_sec: Option<i64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    conditions: Vec<Condition>,
+}
+
+/// Report the health of every computer in a cluster from a single list, for monitoring
+#[get("/clusters/<namespace>/<cluster>/status")]
+async fn cluster_status(
+    namespace: &str,
+    cluster: &str,
+    token: BearerToken,
+    ctx: &State<ServerCtx>,
+) -> Result<Json<ClusterComputersStatus>, Status> {
+    authorize_operator(ctx, &token).await?;
+
+    let clusters = Api::<ComputerCluster>::namespaced(ctx.client.clone(), namespace);
+    let Some(cluster_object) = clusters.get_opt(cluster).await.map_err(internal_error)? else {
+        return Err(Status::NotFound);
+    };
+    let max_heartbeat_age_secs = max_heartbeat_age_secs(&cluster_object);
+
+    let computers = Api::<Computer>::namespaced(ctx.client.clone(), namespace)
+        .list(&ListParams::default())
+        .await
+        .map_err(internal_error)?;
+
+    let mut health = computers
+        .into_iter()
+        .filter(|computer| is_owned_by(computer, cluster))
+        .map(|computer| {
+            let status = computer.status.as_ref();
+            ComputerHealth {
+                online: status
+                    .is_some_and(|status| has_recent_heartbeat(status, max_heartbeat_age_secs)),
+                last_heartbeat_unix_sec: status.and_then(|s| s.last_heartbeat_unix_sec),
+                conditions: status.map(|s| s.conditions.clone()).unwrap_or_default(),
+                id: computer.spec.id,
+            }
+        })
+        .collect::<Vec<_>>();
+    health.sort_by(|a, b| a.id.cmp(&b.id));
+
+    Ok(Json(ClusterComputersStatus {
+        computers_online: health.iter().filter(|c| c.online).count() as u32,
+        computers_total: health.len() as u32,
+        computers: health,
+    }))
+}
+
+/// Reconcile an object now rather than at its next requeue. `kind` is the plural resource name of
+/// what this controller reconciles, `computerclusters` or `computergateways`; other kinds and
+/// missing objects 404. Only the leader runs controllers, so other replicas answer 503, and a
+/// controller with a full queue of triggered reconciles answers 429.
+#[post("/reconcile/<kind>/<namespace>/<name>")]
+async fn reconcile_now(
+    kind: &str,
+    namespace: &str,
+    name: &str,
+    token: BearerToken,
+    ctx: &State<ServerCtx>,
+) -> Result<Status, Status> {
+    authorize_operator(ctx, &token).await?;
+
+    let exists = if kind != ctx.trigger.kind() {
+        false
+    } else if kind == ComputerCluster::plural(&()) {
+        object_exists::<ComputerCluster>(&ctx.client, namespace, name).await?
+    } else {
+        object_exists::<ComputerGateway>(&ctx.client, namespace, name).await?
+    };
+    if !exists {
+        return Err(Status::NotFound);
+    }
+
+    match ctx.trigger.trigger(namespace, name) {
+        TriggerOutcome::Queued => {
+            tracing::info!("Triggered reconcile of {} {}/{}", kind, namespace, name);
+            Ok(Status::Accepted)
+        }
+        TriggerOutcome::Full => Err(Status::TooManyRequests),
+        TriggerOutcome::Unwatched => Err(Status::ServiceUnavailable),
+    }
+}
+
+async fn object_exists<K>(client: &Client, namespace: &str, name: &str) -> Result<bool, Status>
+where
+    K: Resource<Scope = NamespaceResourceScope> + Clone + DeserializeOwned + Debug,
+    K::DynamicType: Default,
+{
+    let object = Api::<K>::namespaced(client.clone(), namespace)
+        .get_opt(name)
+        .await
+        .map_err(internal_error)?;
+    Ok(object.is_some())
+}
+
+/// Find the computer with the given id that belongs to a cluster
+async fn find_computer(
+    computers: &Api<Computer>,
+    cluster: &str,
+    computer_id: &str,
+) -> Result<Option<Computer>, Status> {
+    let computers = computers
+        .list(&ListParams::default())
+        .await
+        .map_err(internal_error)?;
+
+    Ok(computers
+        .into_iter()
+        .find(|computer| computer.spec.id == computer_id && is_owned_by(computer, cluster)))
+}
+
+fn is_owned_by(computer: &Computer, cluster: &str) -> bool {
+    computer
+        .metadata
+        .owner_references
+        .as_ref()
+        .is_some_and(|owners| {
+            owners
+                .iter()
+                .any(|o| o.kind == "ComputerCluster" && o.name == cluster)
+        })
+}
+
+/// Bearer token presented in the `Authorization` header
+struct BearerToken(String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for BearerToken {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        match request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+        {
+            Some(token) => Outcome::Success(BearerToken(token.to_string())),
+            None => Outcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
+
+/// Check via a TokenReview that a token belongs to the service account minted for a cluster's computers
+async fn authorize_computer(
+    client: &Client,
+    token: &BearerToken,
+    namespace: &str,
+    cluster: &str,
+) -> Result<(), Status> {
+    let user = review_token(client, token).await?;
+
+    let expected = format!(
+        "system:serviceaccount:{namespace}:{}",
+        computer_service_account_name(cluster)
+    );
+    if user.username.as_deref() != Some(expected.as_str()) {
+        return Err(Status::Forbidden);
+    }
+
+    Ok(())
+}
+
+/// Check via a TokenReview that a token belongs to one of the configured operators, by username
+/// (e.g. `system:serviceaccount:<namespace>:<name>`) or by one of its groups
+async fn authorize_operator(ctx: &ServerCtx, token: &BearerToken) -> Result<(), Status> {
+    let user = review_token(&ctx.client, token).await?;
+
+    let is_operator = user
+        .username
+        .iter()
+        .chain(user.groups.iter().flatten())
+        .any(|name| ctx.operators.contains(name));
+    if !is_operator {
+        return Err(Status::Forbidden);
+    }
+
+    Ok(())
+}
+
+/// The user a token authenticates as, via a TokenReview
+async fn review_token(client: &Client, token: &BearerToken) -> Result<UserInfo, Status> {
+    let review = Api::<TokenReview>::all(client.clone())
+        .create(
+            &PostParams::default(),
+            &TokenReview {
+                spec: TokenReviewSpec {
+                    token: Some(token.0.clone()),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .await
+        .map_err(internal_error)?;
+
+    let status = review.status.unwrap_or_default();
+    if status.authenticated != Some(true) {
+        return Err(Status::Unauthorized);
+    }
+
+    Ok(status.user.unwrap_or_default())
+}
+
+fn internal_error(e: kube::Error) -> Status {
+    tracing::error!("Kube API call failed: {:?}", e);
+    Status::InternalServerError
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::http::{Method, StatusCode};
+    use rocket::{http::Header, local::asynchronous::Client as LocalClient};
+
+    use super::*;
+    use crate::testing::{ApiServer, mock_client, next_request};
+
+    fn cluster() -> ComputerCluster {
+        serde_json::from_value(json!({
+            "apiVersion": "smcs.dev/v1",
+            "kind": "ComputerCluster",
+            "metadata": { "name": "c", "namespace": "ns", "uid": "cluster-uid" },
+            "spec": {},
+        }))
+        .unwrap()
+    }
+
+    /// The Computer named `c-7`, with `id` and owned by the cluster with `owner_uid`
+    fn existing_computer(id: &str, owner_uid: &str) -> Computer {
+        let mut owner = cluster();
+        owner.metadata.uid = Some(owner_uid.to_string());
+        let mut computer = Computer::new(
+            "c-7",
+            ComputerSpec {
+                id: id.to_string(),
+                state: Default::default(),
+                drain_secs: None,
+            },
+        );
+        computer.metadata.namespace = Some("ns".to_string());
+        computer.metadata.owner_references = Some(vec![
+            owner_ref_from_object_ref(&owner.object_ref(&())).unwrap(),
+        ]);
+        computer
+    }
+
+    /// Answer an enrollment of id `7` up to its create, which conflicts, then its lookup of the
+    /// object in the way with `existing`
+    async fn answer_conflicting_enrollment(server: &mut ApiServer, existing: Computer) {
+        let request = next_request(server).await;
+        assert_eq!(request.path, "/apis/authentication.k8s.io/v1/tokenreviews");
+        let mut review = request.body.clone();
+        review["status"] = json!({
+            "authenticated": true,
+            "user": { "username": "system:serviceaccount:ns:computer-c" },
+        });
+        request.respond(StatusCode::CREATED, &review);
+
+        let request = next_request(server).await;
+        assert_eq!(
+            request.path,
+            "/apis/smcs.dev/v1/namespaces/ns/computerclusters/c"
+        );
+        request.respond(StatusCode::OK, &cluster());
+
+        // Not enrolled as far as the list can tell, e.g. created concurrently
+        let request = next_request(server).await;
+        assert_eq!(request.method, Method::GET);
+        assert_eq!(request.path, "/apis/smcs.dev/v1/namespaces/ns/computers");
+        request.respond(
+            StatusCode::OK,
+            &json!({
+                "apiVersion": "smcs.dev/v1",
+                "kind": "ComputerList",
+                "metadata": {},
+                "items": [],
+            }),
+        );
+
+        let request = next_request(server).await;
+        assert_eq!(request.method, Method::POST);
+        assert_eq!(request.body["metadata"]["name"], "c-7");
+        request.respond(
+            StatusCode::CONFLICT,
+            &json!({
+                "kind": "Status",
+                "apiVersion": "v1",
+                "status": "Failure",
+                "message": "computers \"c-7\" already exists",
+                "reason": "AlreadyExists",
+                "code": 409,
+            }),
+        );
+
+        let request = next_request(server).await;
+        assert_eq!(request.method, Method::GET);
+        assert_eq!(
+            request.path,
+            "/apis/smcs.dev/v1/namespaces/ns/computers/c-7"
+        );
+        request.respond(StatusCode::OK, &existing);
+    }
+
+    /// Enroll computer `7` of cluster `c` while the name it'd get is taken by `existing`
+    async fn enroll_over(existing: Computer) -> (Status, Option<Computer>) {
+        let (client, mut server) = mock_client();
+        let api_server = tokio::spawn(async move {
+            answer_conflicting_enrollment(&mut server, existing).await;
+        });
+
+        let rocket = rocket(
+            client,
+            Arc::new(Metrics::default()),
+            ReconcileTrigger::new("computerclusters".to_string()),
+            vec![],
+        );
+        let local = LocalClient::tracked(rocket).await.unwrap();
+        let response = local
+            .post("/enroll/ns/c?id=7")
+            .header(Header::new("Authorization", "Bearer token"))
+            .dispatch()
+            .await;
+        let status = response.status();
+        let body = response.into_json::<Computer>().await;
+        api_server.await.unwrap();
+        (status, body)
+    }
+
+    #[tokio::test]
+    async fn conflicting_enrollment_returns_the_same_computer() {
+        let (status, body) = enroll_over(existing_computer("7", "cluster-uid")).await;
+        assert_eq!(status, Status::Ok);
+        assert_eq!(body.unwrap().spec.id, "7");
+    }
+
+    #[tokio::test]
+    async fn conflicting_enrollment_refuses_another_id() {
+        // Created out of band under the name that enrolling `7` would give
+        let (status, _) = enroll_over(existing_computer("other", "cluster-uid")).await;
+        assert_eq!(status, Status::Conflict);
+    }
+
+    #[tokio::test]
+    async fn conflicting_enrollment_refuses_another_clusters_computer() {
+        let (status, _) = enroll_over(existing_computer("7", "other-cluster-uid")).await;
+        assert_eq!(status, Status::Conflict);
+    }
+}
@@ -0,0 +1,284 @@
+// Log lines pushed by computers, buffered for operators to tail
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use kube::Api;
+use rocket::{
+    State,
+    futures::StreamExt,
+    get,
+    http::Status,
+    response::stream::{Event, EventStream},
+};
+use rocket_ws::Message;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use super::{BearerToken, ServerCtx, authorize_computer, authorize_operator, find_computer};
+use crate::api::Computer;
+
+/// Lines kept per computer; the oldest are dropped as new ones arrive
+const LOG_BUFFER_LINES: usize = 1000;
+
+/// Longest line kept, in bytes; longer lines are cut short
+const MAX_LINE_BYTES: usize = 1024;
+
+/// How long a computer's buffer is kept after its last line while nobody is tailing it, so that
+/// computers that were deleted or went away don't hold on to their buffers forever
+const LOG_IDLE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// A computer's namespace, cluster and id, as they appear in the log routes
+type ComputerKey = (String, String, String);
+
+#[derive(Default)]
+pub(super) struct LogBuffers {
+    computers: Mutex<HashMap<ComputerKey, ComputerLog>>,
+}
+
+struct ComputerLog {
+    lines: VecDeque<String>,
+    /// Lines as they're pushed, for operators tailing the log
+    live: broadcast::Sender<String>,
+    /// When the computer last connected or pushed a line
+    last_active: Instant,
+}
+
+impl ComputerLog {
+    fn new() -> Self {
+        Self {
+            lines: VecDeque::with_capacity(LOG_BUFFER_LINES),
+            live: broadcast::channel(LOG_BUFFER_LINES).0,
+            last_active: Instant::now(),
+        }
+    }
+
+    fn is_idle(&self, now: Instant) -> bool {
+        self.live.receiver_count() == 0 && now.duration_since(self.last_active) > LOG_IDLE_TIMEOUT
+    }
+}
+
+impl LogBuffers {
+    /// Start a buffer for a computer that connected, so its log can be tailed before it logs anything.
+    /// Buffers left idle by other computers are dropped meanwhile.
+    fn open(&self, key: &ComputerKey) {
+        let now = Instant::now();
+        self.drop_idle(now);
+        let mut computers = self.computers.lock().unwrap();
+        computers
+            .entry(key.clone())
+            .or_insert_with(ComputerLog::new)
+            .last_active = now;
+    }
+
+    fn drop_idle(&self, now: Instant) {
+        let mut computers = self.computers.lock().unwrap();
+        computers.retain(|_, log| !log.is_idle(now));
+    }
+
+    fn push(&self, key: &ComputerKey, mut line: String) {
+        if line.len() > MAX_LINE_BYTES {
+            let mut end = MAX_LINE_BYTES;
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            line.truncate(end);
+        }
+
+        let mut computers = self.computers.lock().unwrap();
+        let log = computers
+            .entry(key.clone())
+            .or_insert_with(ComputerLog::new);
+        log.last_active = Instant::now();
+        if log.lines.len() == LOG_BUFFER_LINES {
+            log.lines.pop_front();
+        }
+        log.lines.push_back(line.clone());
+        // Failing only means nobody is tailing
+        let _ = log.live.send(line);
+    }
+
+    /// The buffered lines and a receiver for every line pushed after them, if the computer has connected
+    fn subscribe(&self, key: &ComputerKey) -> Option<(Vec<String>, broadcast::Receiver<String>)> {
+        let computers = self.computers.lock().unwrap();
+        let log = computers.get(key)?;
+        Some((log.lines.iter().cloned().collect(), log.live.subscribe()))
+    }
+}
+
+/// Accept log lines from a computer over a WebSocket, one per line of each text frame.
+/// Requests that aren't WebSocket upgrades fall through to [`tail`].
+#[get("/logs/<namespace>/<cluster>/<computer_id>", rank = 1)]
+pub(super) async fn push<'a>(
+    ws: rocket_ws::WebSocket,
+    namespace: &'a str,
+    cluster: &'a str,
+    computer_id: &'a str,
+    token: BearerToken,
+    ctx: &'a State<ServerCtx>,
+) -> Result<rocket_ws::Channel<'a>, Status> {
+    authorize_computer(&ctx.client, &token, namespace, cluster).await?;
+
+    let computers = Api::<Computer>::namespaced(ctx.client.clone(), namespace);
+    if find_computer(&computers, cluster, computer_id)
+        .await?
+        .is_none()
+    {
+        return Err(Status::NotFound);
+    }
+
+    let key = (
+        namespace.to_string(),
+        cluster.to_string(),
+        computer_id.to_string(),
+    );
+    ctx.logs.open(&key);
+
+    Ok(ws.channel(move |mut stream| {
+        Box::pin(async move {
+            while let Some(message) = stream.next().await {
+                match message? {
+                    Message::Text(text) => {
+                        for line in text.lines() {
+                            ctx.logs.push(&key, line.to_string());
+                        }
+                    }
+                    Message::Close(_) => break,
+                    _ => {}
+                }
+            }
+            Ok(())
+        })
+    }))
+}
+
+/// Tail a computer's log as Server-Sent Events: the buffered lines in order, then each line as it's
+/// pushed. 404s for a computer that hasn't connected since the controller started.
+#[get("/logs/<namespace>/<cluster>/<computer_id>", rank = 2)]
+pub(super) async fn tail(
+    namespace: &str,
+    cluster: &str,
+    computer_id: &str,
+    token: BearerToken,
+    ctx: &State<ServerCtx>,
+) -> Result<EventStream![], Status> {
+    authorize_operator(ctx, &token).await?;
+
+    let key = (
+        namespace.to_string(),
+        cluster.to_string(),
+        computer_id.to_string(),
+    );
+    let (buffered, mut live) = ctx.logs.subscribe(&key).ok_or(Status::NotFound)?;
+
+    Ok(EventStream! {
+        for line in buffered {
+            yield Event::data(line);
+        }
+        loop {
+            match live.recv().await {
+                Ok(line) => yield Event::data(line),
+                Err(RecvError::Lagged(skipped)) => {
+                    yield Event::comment(format!("{skipped} lines dropped"));
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(computer_id: &str) -> ComputerKey {
+        ("ns".to_string(), "c".to_string(), computer_id.to_string())
+    }
+
+    /// Drop the buffers that would be idle once the idle timeout has passed
+    fn drop_idle_later(logs: &LogBuffers) {
+        logs.drop_idle(Instant::now() + LOG_IDLE_TIMEOUT + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn subscribe_before_connecting_finds_nothing() {
+        let logs = LogBuffers::default();
+        assert!(logs.subscribe(&key("1")).is_none());
+
+        logs.open(&key("1"));
+        let (buffered, _) = logs.subscribe(&key("1")).unwrap();
+        assert!(buffered.is_empty());
+    }
+
+    #[test]
+    fn buffer_keeps_latest_lines_in_order() {
+        let logs = LogBuffers::default();
+        for i in 0..LOG_BUFFER_LINES + 10 {
+            logs.push(&key("1"), i.to_string());
+        }
+
+        let (buffered, _) = logs.subscribe(&key("1")).unwrap();
+        let expected = (10..LOG_BUFFER_LINES + 10)
+            .map(|i| i.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(buffered, expected);
+    }
+
+    #[test]
+    fn subscribers_get_lines_pushed_after_buffered_ones() {
+        let logs = LogBuffers::default();
+        logs.push(&key("1"), "before".to_string());
+
+        let (buffered, mut live) = logs.subscribe(&key("1")).unwrap();
+        logs.push(&key("1"), "after".to_string());
+
+        assert_eq!(buffered, ["before"]);
+        assert_eq!(live.try_recv().unwrap(), "after");
+        assert!(live.try_recv().is_err());
+    }
+
+    #[test]
+    fn computers_have_separate_buffers() {
+        let logs = LogBuffers::default();
+        logs.push(&key("1"), "one".to_string());
+        logs.push(&key("2"), "two".to_string());
+
+        assert_eq!(logs.subscribe(&key("1")).unwrap().0, ["one"]);
+        assert_eq!(logs.subscribe(&key("2")).unwrap().0, ["two"]);
+    }
+
+    #[test]
+    fn long_lines_are_cut_at_a_char_boundary() {
+        let logs = LogBuffers::default();
+        logs.push(&key("1"), "a".repeat(MAX_LINE_BYTES + 100));
+        // A two-byte char straddling the limit is dropped whole
+        logs.push(&key("1"), format!("{}é", "a".repeat(MAX_LINE_BYTES - 1)));
+
+        let (buffered, _) = logs.subscribe(&key("1")).unwrap();
+        assert_eq!(buffered[0].len(), MAX_LINE_BYTES);
+        assert_eq!(buffered[1], "a".repeat(MAX_LINE_BYTES - 1));
+    }
+
+    #[test]
+    fn idle_buffers_are_dropped() {
+        let logs = LogBuffers::default();
+        logs.push(&key("1"), "line".to_string());
+
+        logs.drop_idle(Instant::now());
+        assert!(logs.subscribe(&key("1")).is_some());
+        drop_idle_later(&logs);
+        assert!(logs.subscribe(&key("1")).is_none());
+    }
+
+    #[test]
+    fn tailed_buffers_are_kept_however_idle() {
+        let logs = LogBuffers::default();
+        logs.push(&key("1"), "line".to_string());
+        let _tail = logs.subscribe(&key("1")).unwrap();
+
+        drop_idle_later(&logs);
+        assert!(logs.subscribe(&key("1")).is_some());
+    }
+}
@@ -0,0 +1,93 @@
+// A mock Kubernetes API server for tests, answering a client's requests one at a time
+
+use k8s_openapi::http::{Method, Request, Response, StatusCode};
+use kube::{Client, client::Body};
+use serde::Serialize;
+use serde_json::json;
+use tower_test::mock::{self, Handle, SendResponse};
+
+use crate::reconcilers::ApiOptions;
+
+/// The test's end of a [`mock_client`], receiving the requests the client makes
+pub(crate) type ApiServer = Handle<Request<Body>, Response<Body>>;
+
+/// A client whose requests are answered by the test through the returned [`ApiServer`]
+pub(crate) fn mock_client() -> (Client, ApiServer) {
+    let (service, server) = mock::pair::<Request<Body>, Response<Body>>();
+    (Client::new(service, "default"), server)
+}
+
+/// Options for API calls in tests: no retries, and a timeout long enough to never fire
+pub(crate) fn api_options() -> ApiOptions {
+    ApiOptions {
+        observe_only: false,
+        timeout: std::time::Duration::from_secs(30),
+        retries: 0,
+    }
+}
+
+/// A request made through a [`mock_client`], waiting on its response
+pub(crate) struct ApiRequest {
+    pub(crate) method: Method,
+    pub(crate) path: String,
+    pub(crate) query: String,
+    /// The request body, or `null` if there is none
+    pub(crate) body: serde_json::Value,
+    response: SendResponse<Response<Body>>,
+}
+
+impl ApiRequest {
+    pub(crate) fn respond(self, status: StatusCode, body: &impl Serialize) {
+        let response = Response::builder()
+            .status(status)
+            .body(Body::from(serde_json::to_vec(body).unwrap()))
+            .unwrap();
+        self.response.send_response(response);
+    }
+
+    /// Answer a patch or create with the object it sent
+    pub(crate) fn respond_with_body(self) {
+        let body = self.body.clone();
+        self.respond(StatusCode::OK, &body);
+    }
+
+    pub(crate) fn respond_not_found(self) {
+        self.respond(
+            StatusCode::NOT_FOUND,
+            &json!({
+                "kind": "Status",
+                "apiVersion": "v1",
+                "status": "Failure",
+                "message": "not found",
+                "reason": "NotFound",
+                "code": 404,
+            }),
+        );
+    }
+}
+
+/// Wait for the client's next request, panicking if the client is dropped first
+pub(crate) async fn next_request(server: &mut ApiServer) -> ApiRequest {
+    let (request, response) = server
+        .next_request()
+        .await
+        .expect("client dropped before making the expected request");
+
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let query = request.uri().query().unwrap_or_default().to_string();
+    let bytes = request.into_body().collect_bytes().await.unwrap();
+    let body = if bytes.is_empty() {
+        serde_json::Value::Null
+    } else {
+        serde_json::from_slice(&bytes).unwrap()
+    };
+
+    ApiRequest {
+        method,
+        path,
+        query,
+        body,
+        response,
+    }
+}
@@ -0,0 +1,51 @@
+// Authenticating operators calling the `/admin` endpoints
+
+use rocket::{
+    Request, State,
+    http::Status,
+    request::{self, FromRequest, Outcome},
+};
+use sha2::{Digest, Sha256};
+
+use super::GatewayConfig;
+
+/// A request carrying `Authorization: Bearer <admin_token>`. Admin endpoints are reachable through
+/// the same listener as proxied traffic, so without an `admin_token` configured they're refused.
+pub(crate) struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let gateway_config = State::<GatewayConfig>::get(request.rocket()).unwrap();
+        let Some(expected) = gateway_config
+            .admin_token
+            .as_deref()
+            .filter(|token| !token.is_empty())
+        else {
+            return Outcome::Error((Status::Forbidden, ()));
+        };
+
+        let presented = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match presented {
+            Some(token) if tokens_match(token, expected) => Outcome::Success(Admin),
+            Some(_) => Outcome::Error((Status::Forbidden, ())),
+            None => Outcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
+
+/// Compare tokens in constant time, hashing them first so their lengths don't leak either
+fn tokens_match(presented: &str, expected: &str) -> bool {
+    let presented = Sha256::digest(presented.as_bytes());
+    let expected = Sha256::digest(expected.as_bytes());
+    presented
+        .iter()
+        .zip(expected.iter())
+        .fold(0, |difference, (a, b)| difference | (a ^ b))
+        == 0
+}
@@ -0,0 +1,178 @@
+// Choosing which listener serves a request
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use rand::Rng;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::ComputerId;
+
+/// How the gateway picks among the listeners eligible for a request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum LoadBalanceStrategy {
+    /// Uniformly random
+    #[default]
+    Random,
+    /// Each listener in turn, ordered by ID
+    RoundRobin,
+    /// The listener with the fewest requests and tunnels in flight
+    LeastInFlight,
+}
+
+/// Selection state shared across requests
+#[derive(Debug, Default)]
+pub(crate) struct Balancer {
+    strategy: LoadBalanceStrategy,
+    cursor: AtomicUsize,
+}
+
+impl Balancer {
+    pub(crate) fn new(strategy: LoadBalanceStrategy) -> Self {
+        Self {
+            strategy,
+            cursor: AtomicUsize::new(0),
+        }
+    }
+
+    /// Pick one of `candidates`, given as `(listener, in-flight count)` pairs.
+    /// Returns the index of the chosen candidate, or `None` if there are none.
+    pub(crate) fn pick(&self, candidates: &[(ComputerId, usize)]) -> Option<usize> {
+        if candidates.is_empty() {
+            return None;
+        }
+
+        match self.strategy {
+            LoadBalanceStrategy::Random => Some(rand::rng().random_range(0..candidates.len())),
+            LoadBalanceStrategy::RoundRobin => {
+                // Candidates come from a concurrent map, so cycle over them in a stable order
+                let mut order = (0..candidates.len()).collect::<Vec<_>>();
+                order.sort_by(|&a, &b| candidates[a].0.cmp(&candidates[b].0));
+                let next = self.cursor.fetch_add(1, Ordering::Relaxed);
+                Some(order[next % order.len()])
+            }
+            LoadBalanceStrategy::LeastInFlight => candidates
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, (_, in_flight))| *in_flight)
+                .map(|(i, _)| i),
+        }
+    }
+}
+
+/// Pick the candidate that owns `key`, using rendezvous hashing so that the same key keeps
+/// landing on the same listener, and a listener joining or leaving only remaps the keys it owns.
+/// Returns the index of the chosen candidate, or `None` if there are none.
+pub(crate) fn pick_sharded(key: &str, candidates: &[(ComputerId, usize)]) -> Option<usize> {
+    candidates
+        .iter()
+        .enumerate()
+        // Break (vanishingly unlikely) ties by ID so the choice doesn't depend on map order
+        .max_by_key(|(_, (id, _))| (shard_weight(key, id), id))
+        .map(|(i, _)| i)
+}
+
+fn shard_weight(key: &str, listener_id: &str) -> u64 {
+    // SHA-256 is fixed, unlike `DefaultHasher`, so weights hold across restarts and Rust releases.
+    // The key's length goes first, so no other key and ID pair hashes the same bytes.
+    let digest = Sha256::new()
+        .chain_update((key.len() as u64).to_be_bytes())
+        .chain_update(key)
+        .chain_update(listener_id)
+        .finalize();
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidates(listeners: &[(&str, usize)]) -> Vec<(ComputerId, usize)> {
+        listeners
+            .iter()
+            .map(|&(id, in_flight)| (id.to_string(), in_flight))
+            .collect()
+    }
+
+    #[test]
+    fn no_candidates_picks_nothing() {
+        for strategy in [
+            LoadBalanceStrategy::Random,
+            LoadBalanceStrategy::RoundRobin,
+            LoadBalanceStrategy::LeastInFlight,
+        ] {
+            assert_eq!(Balancer::new(strategy).pick(&[]), None);
+        }
+    }
+
+    #[test]
+    fn random_picks_a_candidate() {
+        let balancer = Balancer::new(LoadBalanceStrategy::Random);
+        let candidates = candidates(&[("1", 0), ("2", 0), ("3", 0)]);
+
+        for _ in 0..100 {
+            assert!(balancer.pick(&candidates).is_some_and(|i| i < 3));
+        }
+    }
+
+    #[test]
+    fn round_robin_cycles_in_id_order() {
+        let balancer = Balancer::new(LoadBalanceStrategy::RoundRobin);
+        let candidates = candidates(&[("3", 0), ("1", 0), ("2", 0)]);
+
+        let picked = (0..6)
+            .map(|_| candidates[balancer.pick(&candidates).unwrap()].0.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(picked, ["1", "2", "3", "1", "2", "3"]);
+    }
+
+    #[test]
+    fn least_in_flight_picks_the_idlest() {
+        let balancer = Balancer::new(LoadBalanceStrategy::LeastInFlight);
+        let candidates = candidates(&[("1", 4), ("2", 1), ("3", 2)]);
+
+        assert_eq!(balancer.pick(&candidates), Some(1));
+    }
+
+    #[test]
+    fn shard_weights_are_pinned() {
+        // Changing how weights are derived would move every key to a different listener on upgrade
+        assert_eq!(shard_weight("player:alice", "1"), 0xc723_b8a9_9f03_55ff);
+    }
+
+    #[test]
+    fn sharding_ignores_candidate_order_and_load() {
+        let forward = candidates(&[("1", 0), ("2", 0), ("3", 0)]);
+        let reversed = candidates(&[("3", 9), ("2", 0), ("1", 5)]);
+
+        for key in ["player:alice", "player:bob", "player:carol"] {
+            let owner = &forward[pick_sharded(key, &forward).unwrap()].0;
+            assert_eq!(&reversed[pick_sharded(key, &reversed).unwrap()].0, owner);
+        }
+    }
+
+    #[test]
+    fn only_keys_of_a_departed_listener_move() {
+        let all = candidates(&[("1", 0), ("2", 0), ("3", 0)]);
+        let without_1 = candidates(&[("2", 0), ("3", 0)]);
+
+        // `player:bob` is owned by 3, and `player:alice` by 1
+        assert_eq!(all[pick_sharded("player:bob", &all).unwrap()].0, "3");
+        assert_eq!(
+            without_1[pick_sharded("player:bob", &without_1).unwrap()].0,
+            "3"
+        );
+        assert_eq!(all[pick_sharded("player:alice", &all).unwrap()].0, "1");
+        assert_eq!(
+            without_1[pick_sharded("player:alice", &without_1).unwrap()].0,
+            "3"
+        );
+    }
+
+    #[test]
+    fn sharding_no_candidates_picks_nothing() {
+        assert_eq!(pick_sharded("player:alice", &[]), None);
+    }
+}
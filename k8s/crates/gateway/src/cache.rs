@@ -0,0 +1,295 @@
+// In-memory caching of responses to GET requests
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use super::{HttpRequest, HttpResponse};
+
+#[derive(Debug)]
+pub(crate) struct ResponseCache {
+    /// Entries kept before the least recently used is evicted, 0 disables caching
+    max_entries: usize,
+    entries: Mutex<Entries>,
+}
+
+/// Cached responses by key, indexed by expiry and by last use so that eviction needn't scan them
+#[derive(Debug, Default)]
+struct Entries {
+    responses: HashMap<String, CachedResponse>,
+    /// Keys by expiry, tie-broken by the tick they were inserted at
+    by_expiry: BTreeMap<(Instant, u64), String>,
+    /// Keys by the tick they were last used at
+    by_use: BTreeMap<u64, String>,
+    /// Incremented on every insert and hit, ordering entries without comparing timestamps
+    tick: u64,
+}
+
+#[derive(Debug)]
+struct CachedResponse {
+    response: HttpResponse,
+    expires: Instant,
+    inserted: u64,
+    last_used: u64,
+}
+
+impl Entries {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.responses.remove(key) {
+            self.by_expiry.remove(&(entry.expires, entry.inserted));
+            self.by_use.remove(&entry.last_used);
+        }
+    }
+
+    fn remove_expired(&mut self, now: Instant) {
+        while let Some(entry) = self.by_expiry.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            let key = entry.remove();
+            self.remove(&key);
+        }
+    }
+
+    fn remove_least_recently_used(&mut self) {
+        if let Some((_, key)) = self.by_use.pop_first() {
+            self.remove(&key);
+        }
+    }
+}
+
+impl ResponseCache {
+    pub(crate) fn new(max_entries: usize) -> Self {
+        Self {
+            max_entries,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<HttpResponse> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let expires = entries.responses.get(key)?.expires;
+        if expires <= now {
+            entries.remove(key);
+            return None;
+        }
+
+        let tick = entries.next_tick();
+        let entry = entries.responses.get_mut(key).unwrap();
+        let last_used = std::mem::replace(&mut entry.last_used, tick);
+        let response = entry.response.clone();
+        entries.by_use.remove(&last_used);
+        entries.by_use.insert(tick, key.to_string());
+        Some(response)
+    }
+
+    /// Cache a successful response for `ttl`, unless it's private to the client, or the backend
+    /// asked for it not to be stored
+    pub(crate) fn insert(&self, key: String, response: &HttpResponse, ttl: Duration) {
+        if self.max_entries == 0 || !response.status.class().is_success() || !is_storable(response)
+        {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove(&key);
+        if entries.responses.len() >= self.max_entries {
+            entries.remove_expired(now);
+        }
+        if entries.responses.len() >= self.max_entries {
+            entries.remove_least_recently_used();
+        }
+
+        let tick = entries.next_tick();
+        let expires = now + ttl;
+        entries.by_expiry.insert((expires, tick), key.clone());
+        entries.by_use.insert(tick, key.clone());
+        entries.responses.insert(
+            key,
+            CachedResponse {
+                response: response.clone(),
+                expires,
+                inserted: tick,
+                last_used: tick,
+            },
+        );
+    }
+}
+
+/// Whether a request could be answered from the cache, or its response cached. Requests carrying
+/// credentials may get a response meant only for that client, so they always go to the backend.
+pub(crate) fn is_cacheable_request(request: &HttpRequest) -> bool {
+    !request.headers.keys().any(|name| {
+        name.eq_ignore_ascii_case("Authorization") || name.eq_ignore_ascii_case("Cookie")
+    })
+}
+
+/// Whether a response may be shared between clients: it sets no cookie, and its `Cache-Control`
+/// has neither `no-store` nor `private`
+fn is_storable(response: &HttpResponse) -> bool {
+    let sets_cookie = response
+        .headers
+        .keys()
+        .any(|name| name.eq_ignore_ascii_case("Set-Cookie"));
+    let forbidden = response
+        .headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Cache-Control"))
+        .flat_map(|(_, values)| values)
+        .flat_map(|value| value.split(','))
+        .map(|directive| directive.trim())
+        // `private` may list fields, e.g. `private="Set-Cookie"`, which still rules out sharing
+        .any(|directive| {
+            directive.eq_ignore_ascii_case("no-store")
+                || directive
+                    .split('=')
+                    .next()
+                    .is_some_and(|name| name.trim().eq_ignore_ascii_case("private"))
+        });
+
+    !sets_cookie && !forbidden
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::{Method, Status, uri::Origin};
+
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn response(status: Status, headers: &[(&str, &str)]) -> HttpResponse {
+        HttpResponse {
+            status,
+            headers: headers
+                .iter()
+                .map(|&(name, value)| (name.to_string(), vec![value.to_string()]))
+                .collect(),
+            body: "hello".to_string(),
+            body_base64: false,
+            body_sha256: None,
+        }
+    }
+
+    fn ok() -> HttpResponse {
+        response(Status::Ok, &[])
+    }
+
+    fn request(headers: &[(&str, &str)]) -> HttpRequest {
+        HttpRequest {
+            method: Method::Get,
+            uri: Origin::parse_owned("/".to_string()).unwrap(),
+            headers: headers
+                .iter()
+                .map(|&(name, value)| (name.to_string(), vec![value.to_string()]))
+                .collect(),
+            body: String::new(),
+            body_base64: false,
+            body_sha256: None,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn serves_an_entry_until_it_expires() {
+        let cache = ResponseCache::new(10);
+        cache.insert("a".to_string(), &ok(), TTL);
+
+        assert_eq!(cache.get("a").unwrap().body, "hello");
+        assert!(cache.get("b").is_none());
+
+        tokio::time::advance(TTL).await;
+        assert!(cache.get("a").is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn zero_entries_disables_caching() {
+        let cache = ResponseCache::new(0);
+        cache.insert("a".to_string(), &ok(), TTL);
+
+        assert!(cache.get("a").is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn skips_unsuccessful_and_private_responses() {
+        let cache = ResponseCache::new(10);
+        let uncacheable = [
+            response(Status::NotFound, &[]),
+            response(Status::Ok, &[("Cache-Control", "no-store")]),
+            response(Status::Ok, &[("cache-control", "max-age=60, Private")]),
+            response(Status::Ok, &[("Cache-Control", "private=\"Set-Cookie\"")]),
+            response(Status::Ok, &[("Set-Cookie", "session=1")]),
+        ];
+        for (i, response) in uncacheable.iter().enumerate() {
+            cache.insert(i.to_string(), response, TTL);
+            assert!(cache.get(&i.to_string()).is_none(), "{response:?}");
+        }
+
+        let public = response(Status::Ok, &[("Cache-Control", "public, max-age=60")]);
+        cache.insert("public".to_string(), &public, TTL);
+        assert!(cache.get("public").is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn evicts_the_least_recently_used() {
+        let cache = ResponseCache::new(2);
+        cache.insert("a".to_string(), &ok(), TTL);
+        cache.insert("b".to_string(), &ok(), TTL);
+        cache.get("a");
+
+        cache.insert("c".to_string(), &ok(), TTL);
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("c").is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn evicts_expired_entries_before_live_ones() {
+        let cache = ResponseCache::new(2);
+        cache.insert("short".to_string(), &ok(), Duration::from_secs(1));
+        cache.insert("long".to_string(), &ok(), TTL);
+        // Make the expired entry the most recently used, so LRU alone would keep it
+        cache.get("short");
+
+        tokio::time::advance(Duration::from_secs(2)).await;
+        cache.insert("new".to_string(), &ok(), TTL);
+        assert!(cache.get("long").is_some());
+        assert!(cache.get("new").is_some());
+        assert_eq!(cache.entries.lock().unwrap().responses.len(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replacing_an_entry_evicts_nothing_else() {
+        let cache = ResponseCache::new(2);
+        cache.insert("a".to_string(), &ok(), TTL);
+        cache.insert("b".to_string(), &ok(), TTL);
+
+        cache.insert("a".to_string(), &ok(), TTL);
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_some());
+
+        let entries = cache.entries.lock().unwrap();
+        assert_eq!(entries.by_expiry.len(), 2);
+        assert_eq!(entries.by_use.len(), 2);
+    }
+
+    #[test]
+    fn requests_with_credentials_are_not_cacheable() {
+        assert!(is_cacheable_request(&request(&[("Accept", "text/html")])));
+        assert!(!is_cacheable_request(&request(&[(
+            "authorization",
+            "Bearer x"
+        )])));
+        assert!(!is_cacheable_request(&request(&[("Cookie", "session=1")])));
+    }
+}
@@ -0,0 +1,220 @@
+// Encoding the messages exchanged with listeners as WebSocket frames
+
+use anyhow::bail;
+use rocket::{
+    FromFormField, Request,
+    http::Status,
+    request::{self, FromRequest, Outcome},
+    response::Responder,
+};
+use rocket_ws::Message;
+use serde::{Serialize, de::DeserializeOwned};
+
+/// WebSocket subprotocols a listener may negotiate, each fixing the encoding of the connection
+const SUBPROTOCOLS: &[(&str, Encoding)] = &[
+    ("cc-rednet-json.v1", Encoding::Json),
+    ("cc-rednet-msgpack.v1", Encoding::MessagePack),
+];
+
+/// How the gateway encodes messages to a listener, chosen by the listener when it connects
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, FromFormField)]
+pub(crate) enum Encoding {
+    /// JSON in text frames
+    #[default]
+    Json,
+    /// MessagePack in binary frames, which is more compact for high-frequency traffic
+    #[field(value = "msgpack")]
+    MessagePack,
+}
+
+impl Encoding {
+    pub(crate) fn encode<T: Serialize>(self, message: &T) -> anyhow::Result<Message> {
+        Ok(match self {
+            Encoding::Json => Message::Text(serde_json::to_string(message)?),
+            // Named fields keep the untagged message enums distinguishable on the way back
+            Encoding::MessagePack => Message::Binary(rmp_serde::to_vec_named(message)?),
+        })
+    }
+}
+
+/// The subprotocols a client offered in `Sec-WebSocket-Protocol`, in order of preference
+pub(crate) struct OfferedSubprotocols(Vec<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for OfferedSubprotocols {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let offered = request
+            .headers()
+            .get("Sec-WebSocket-Protocol")
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|subprotocol| !subprotocol.is_empty())
+            .map(str::to_string)
+            .collect();
+        Outcome::Success(OfferedSubprotocols(offered))
+    }
+}
+
+impl OfferedSubprotocols {
+    /// The first offered subprotocol the gateway speaks, and the encoding it implies. `None` if the
+    /// client offered none, and 400 if it offered only ones the gateway doesn't know.
+    pub(crate) fn negotiate(&self) -> Result<Option<(&'static str, Encoding)>, Status> {
+        if self.0.is_empty() {
+            return Ok(None);
+        }
+
+        self.0
+            .iter()
+            .find_map(|offered| SUBPROTOCOLS.iter().find(|(name, _)| name == offered))
+            .map(|&(name, encoding)| Some((name, encoding)))
+            .ok_or(Status::BadRequest)
+    }
+}
+
+/// A WebSocket upgrade response, confirming the negotiated subprotocol if there is one
+pub(crate) struct Negotiated<R> {
+    pub(crate) response: R,
+    pub(crate) subprotocol: Option<&'static str>,
+}
+
+impl<'r, 'o: 'r, R: Responder<'r, 'o>> Responder<'r, 'o> for Negotiated<R> {
+    fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'o> {
+        let mut response = self.response.respond_to(request)?;
+        if let Some(subprotocol) = self.subprotocol {
+            response.set_raw_header("Sec-WebSocket-Protocol", subprotocol);
+        }
+        Ok(response)
+    }
+}
+
+/// Decode a message from a listener, as JSON from a text frame or MessagePack from a binary one,
+/// whichever encoding the listener asked for
+pub(crate) fn decode<T: DeserializeOwned>(frame: &Message) -> anyhow::Result<T> {
+    match frame {
+        Message::Text(text) => Ok(serde_json::from_str(text)?),
+        Message::Binary(data) => Ok(rmp_serde::from_slice(data)?),
+        _ => bail!("not a data frame"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rocket::http::Status;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        HttpResponse, ListenerReply, PROTOCOL_VERSION, RednetRpcDestination, RednetRpcMessage,
+        tunnel::TunnelFrame,
+    };
+
+    fn message<T>(payload: T) -> RednetRpcMessage<T> {
+        RednetRpcMessage {
+            v: PROTOCOL_VERSION,
+            dest: RednetRpcDestination::Anycast {
+                protocol: "shop".to_string(),
+            },
+            request_id: Uuid::new_v4(),
+            payload,
+        }
+    }
+
+    fn response() -> HttpResponse {
+        HttpResponse {
+            status: Status::Created,
+            headers: HashMap::from([("Content-Type".to_string(), vec!["text/plain".to_string()])]),
+            body: "made it".to_string(),
+            body_base64: false,
+            body_sha256: Some("abc".to_string()),
+        }
+    }
+
+    #[test]
+    fn each_encoding_uses_its_frame_type() {
+        let sent = message(response());
+
+        assert!(matches!(
+            Encoding::Json.encode(&sent).unwrap(),
+            Message::Text(_)
+        ));
+        assert!(matches!(
+            Encoding::MessagePack.encode(&sent).unwrap(),
+            Message::Binary(_)
+        ));
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        for encoding in [Encoding::Json, Encoding::MessagePack] {
+            let sent = message(response());
+            let frame = encoding.encode(&sent).unwrap();
+
+            let Ok(ListenerReply::Http(received)) = decode::<ListenerReply>(&frame) else {
+                panic!("{encoding:?} didn't decode as a response");
+            };
+            assert_eq!(received.v, PROTOCOL_VERSION);
+            assert_eq!(received.request_id, sent.request_id);
+            assert!(matches!(
+                received.dest,
+                RednetRpcDestination::Anycast { protocol } if protocol == "shop"
+            ));
+            assert_eq!(received.payload.status, Status::Created);
+            assert_eq!(received.payload.headers, sent.payload.headers);
+            assert_eq!(received.payload.body, "made it");
+            assert_eq!(received.payload.body_sha256.as_deref(), Some("abc"));
+        }
+    }
+
+    #[test]
+    fn tunnel_frames_round_trip() {
+        for encoding in [Encoding::Json, Encoding::MessagePack] {
+            let sent = message(TunnelFrame::Binary(vec![0, 1, 255]));
+            let frame = encoding.encode(&sent).unwrap();
+
+            let Ok(ListenerReply::Tunnel(received)) = decode::<ListenerReply>(&frame) else {
+                panic!("{encoding:?} didn't decode as a tunnel frame");
+            };
+            assert_eq!(received.request_id, sent.request_id);
+            assert!(matches!(received.payload, TunnelFrame::Binary(data) if data == [0, 1, 255]));
+        }
+    }
+
+    #[test]
+    fn rejects_control_frames() {
+        assert!(decode::<ListenerReply>(&Message::Ping(vec![])).is_err());
+        assert!(decode::<ListenerReply>(&Message::Close(None)).is_err());
+    }
+
+    fn offered(subprotocols: &[&str]) -> OfferedSubprotocols {
+        OfferedSubprotocols(subprotocols.iter().map(|s| s.to_string()).collect())
+    }
+
+    #[test]
+    fn negotiates_nothing_when_nothing_is_offered() {
+        assert_eq!(offered(&[]).negotiate(), Ok(None));
+    }
+
+    #[test]
+    fn negotiates_the_first_known_subprotocol() {
+        assert_eq!(
+            offered(&["chat", "cc-rednet-msgpack.v1", "cc-rednet-json.v1"]).negotiate(),
+            Ok(Some(("cc-rednet-msgpack.v1", Encoding::MessagePack)))
+        );
+        assert_eq!(
+            offered(&["cc-rednet-json.v1"]).negotiate(),
+            Ok(Some(("cc-rednet-json.v1", Encoding::Json)))
+        );
+    }
+
+    #[test]
+    fn rejects_only_unknown_subprotocols() {
+        assert_eq!(
+            offered(&["chat", "cc-rednet-json.v2"]).negotiate(),
+            Err(Status::BadRequest)
+        );
+    }
+}
@@ -0,0 +1,173 @@
+// Cross-origin resource sharing for browsers calling in-game services through the gateway
+
+use rocket::{
+    Request, Response, State,
+    fairing::{Fairing, Info, Kind},
+    http::{Method, Status},
+    options,
+    request::{self, FromRequest},
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub(crate) struct CorsConfig {
+    /// Origins allowed to call the gateway from a browser, or `*` for any origin
+    allowed_origins: Vec<String>,
+    /// Methods a preflight may approve
+    #[serde(default = "default_allowed_methods")]
+    allowed_methods: Vec<String>,
+    /// Request headers a preflight may approve, beyond the CORS-safelisted ones
+    #[serde(default)]
+    allowed_headers: Vec<String>,
+    /// Seconds a browser may cache a preflight response
+    #[serde(default = "default_max_age_secs")]
+    max_age_secs: u32,
+}
+
+fn default_allowed_methods() -> Vec<String> {
+    ["GET", "PUT", "POST", "DELETE", "PATCH"]
+        .into_iter()
+        .map(str::to_string)
+        .collect()
+}
+
+fn default_max_age_secs() -> u32 {
+    600
+}
+
+/// Request headers a browser sends cross-origin without asking, so a preflight needn't approve them
+const SAFELISTED_HEADERS: [&str; 4] = [
+    "accept",
+    "accept-language",
+    "content-language",
+    "content-type",
+];
+
+impl CorsConfig {
+    fn allows_any_origin(&self) -> bool {
+        self.allowed_origins.iter().any(|origin| origin == "*")
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        self.allows_any_origin()
+            || self
+                .allowed_origins
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+    }
+
+    fn allows_method(&self, method: &str) -> bool {
+        self.allowed_methods
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(method))
+    }
+
+    fn allows_header(&self, header: &str) -> bool {
+        SAFELISTED_HEADERS
+            .into_iter()
+            .chain(self.allowed_headers.iter().map(String::as_str))
+            .any(|allowed| allowed.eq_ignore_ascii_case(header))
+    }
+}
+
+/// Adds `Access-Control-*` headers to gateway responses for allowed origins
+pub(crate) struct Cors(pub(crate) CorsConfig);
+
+#[rocket::async_trait]
+impl Fairing for Cors {
+    fn info(&self) -> Info {
+        Info {
+            name: "CORS",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        if !request.uri().path().as_str().starts_with("/gateway") {
+            return;
+        }
+        let Some(origin) = request.headers().get_one("Origin") else {
+            return;
+        };
+        if !self.0.allows(origin) {
+            return;
+        }
+
+        // Whatever the backend said about CORS, the gateway's config is what applies
+        if self.0.allows_any_origin() {
+            response.set_raw_header("Access-Control-Allow-Origin", "*");
+        } else {
+            response.set_raw_header("Access-Control-Allow-Origin", origin.to_string());
+            response.adjoin_raw_header("Vary", "Origin");
+        }
+
+        if request.method() == Method::Options {
+            response.set_raw_header(
+                "Access-Control-Allow-Methods",
+                self.0.allowed_methods.join(", "),
+            );
+            if !self.0.allowed_headers.is_empty() {
+                response.set_raw_header(
+                    "Access-Control-Allow-Headers",
+                    self.0.allowed_headers.join(", "),
+                );
+            }
+            response.set_raw_header("Access-Control-Max-Age", self.0.max_age_secs.to_string());
+        }
+    }
+}
+
+/// What a preflight asks to be allowed to send
+pub(crate) struct Preflight {
+    /// `Access-Control-Request-Method`, absent from an `OPTIONS` request that isn't a preflight
+    method: Option<String>,
+    /// `Access-Control-Request-Headers`, split on commas
+    headers: Vec<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Preflight {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        request::Outcome::Success(Preflight {
+            method: headers
+                .get_one("Access-Control-Request-Method")
+                .map(str::to_string),
+            headers: headers
+                .get("Access-Control-Request-Headers")
+                .flat_map(|value| value.split(','))
+                .map(str::trim)
+                .filter(|header| !header.is_empty())
+                .map(str::to_string)
+                .collect(),
+        })
+    }
+}
+
+/// Answer preflights at the gateway rather than forwarding them; [`Cors`] fills in the headers.
+/// A preflight asking for a method or header the config doesn't allow is refused with 403.
+#[options("/<_..>")]
+pub(crate) fn preflight(preflight: Preflight, config: &State<CorsConfig>) -> Status {
+    let method_allowed = preflight
+        .method
+        .as_deref()
+        .is_none_or(|method| config.allows_method(method));
+    let headers_allowed = preflight
+        .headers
+        .iter()
+        .all(|header| config.allows_header(header));
+
+    if method_allowed && headers_allowed {
+        Status::NoContent
+    } else {
+        rocket::warn!(
+            "Refusing CORS preflight for method {:?} with headers {:?}",
+            preflight.method,
+            preflight.headers
+        );
+        Status::Forbidden
+    }
+}
@@ -0,0 +1,122 @@
+// Why the gateway couldn't proxy a request, and the response each reason gets
+
+use std::time::Duration;
+
+use rocket::{
+    Request, Response,
+    http::Status,
+    response::{self, Responder},
+};
+
+use super::transport::ProxyError;
+
+/// Everything that can stop [`GatewayHandler`](super::GatewayHandler) from proxying a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum GatewayError {
+    /// The rednet config couldn't be loaded
+    Config,
+    /// The request URI, path and query together, is longer than `max_uri_bytes`
+    UriTooLong,
+    /// The request path or query doesn't percent-decode, or the path doesn't normalize
+    MalformedUri,
+    /// No route matches the request, and there's no `default_backend`
+    NoRoute,
+    /// The client is over its rate limit, and may retry after this long
+    RateLimited(Duration),
+    /// A WebSocket route was sent an upgrade request the gateway couldn't accept
+    BadUpgrade,
+    /// The request body is larger than the gateway forwards
+    BodyTooLarge,
+    /// The request body isn't UTF-8, and its content type doesn't mark it as binary
+    NonUtf8Body,
+    /// Proxying to a listener failed, for a reason reported in the JSON error body
+    Proxy(ProxyError),
+    /// Building the response failed with this status
+    Response(Status),
+    /// The gateway itself failed, e.g. rewriting a path it had already matched
+    Internal,
+}
+
+impl GatewayError {
+    pub(crate) fn status(self) -> Status {
+        match self {
+            GatewayError::Config => Status::BadGateway,
+            GatewayError::UriTooLong => Status::UriTooLong,
+            GatewayError::MalformedUri | GatewayError::BadUpgrade | GatewayError::NonUtf8Body => {
+                Status::BadRequest
+            }
+            GatewayError::NoRoute => Status::NotFound,
+            GatewayError::RateLimited(_) => Status::TooManyRequests,
+            GatewayError::BodyTooLarge => Status::PayloadTooLarge,
+            GatewayError::Proxy(e) => e.status(),
+            GatewayError::Response(status) => status,
+            GatewayError::Internal => Status::InternalServerError,
+        }
+    }
+}
+
+impl From<ProxyError> for GatewayError {
+    fn from(error: ProxyError) -> Self {
+        GatewayError::Proxy(error)
+    }
+}
+
+/// Errors are left to the `/gateway` catcher, which renders the JSON error body, except for rate
+/// limiting, whose `Retry-After` header the catcher would drop
+impl<'r> Responder<'r, 'static> for GatewayError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        match self {
+            GatewayError::RateLimited(retry_after) => Response::build()
+                .status(self.status())
+                .raw_header("Retry-After", retry_after.as_secs().max(1).to_string())
+                .ok(),
+            GatewayError::Proxy(e) => {
+                request.local_cache(|| Some(e));
+                Err(self.status())
+            }
+            _ => Err(self.status()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_error_gets_its_status() {
+        let cases = [
+            (GatewayError::Config, Status::BadGateway),
+            (GatewayError::UriTooLong, Status::UriTooLong),
+            (GatewayError::MalformedUri, Status::BadRequest),
+            (GatewayError::NoRoute, Status::NotFound),
+            (
+                GatewayError::RateLimited(Duration::from_secs(1)),
+                Status::TooManyRequests,
+            ),
+            (GatewayError::BadUpgrade, Status::BadRequest),
+            (GatewayError::BodyTooLarge, Status::PayloadTooLarge),
+            (GatewayError::NonUtf8Body, Status::BadRequest),
+            (GatewayError::Response(Status::Conflict), Status::Conflict),
+            (GatewayError::Internal, Status::InternalServerError),
+        ];
+        for (error, status) in cases {
+            assert_eq!(error.status(), status, "{error:?}");
+        }
+    }
+
+    #[test]
+    fn proxy_errors_keep_their_status() {
+        let cases = [
+            (ProxyError::NoListener, Status::ServiceUnavailable),
+            (ProxyError::ListenerBusy, Status::ServiceUnavailable),
+            (ProxyError::ListenerDisconnected, Status::BadGateway),
+            (ProxyError::InvalidResponse, Status::BadGateway),
+            (ProxyError::Timeout, Status::GatewayTimeout),
+            (ProxyError::Internal, Status::InternalServerError),
+        ];
+        for (error, status) in cases {
+            assert_eq!(GatewayError::from(error).status(), status, "{error:?}");
+        }
+    }
+}
@@ -0,0 +1,184 @@
+// Probing listeners in the background, so a failing computer leaves selection before requests fail on it
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use dashmap::DashMap;
+use rocket::{
+    futures::channel::oneshot,
+    http::{Method, uri::Origin},
+};
+use tokio::time::{MissedTickBehavior, interval, timeout};
+use uuid::Uuid;
+
+use super::{
+    ComputerId, GatewayConfig, HttpRequest, HttpResponse, ListenerMessage, PROTOCOL_VERSION,
+    RednetRpcDestination, RednetRpcMessage, Server,
+};
+
+/// Probe results per listener, and the probes still waiting on a response
+#[derive(Debug, Default)]
+pub(crate) struct HealthProbes {
+    /// Consecutive probes each listener failed; listeners that passed their last probe aren't listed
+    failures: DashMap<ComputerId, u32>,
+    pending: DashMap<Uuid, oneshot::Sender<HttpResponse>>,
+}
+
+impl HealthProbes {
+    /// Whether a listener has failed fewer than `health_probe_failure_threshold` probes in a row
+    pub(crate) fn is_healthy(&self, listener_id: &str, config: &GatewayConfig) -> bool {
+        config.health_probe_failure_threshold == 0
+            || self
+                .failures
+                .get(listener_id)
+                .is_none_or(|failures| *failures < config.health_probe_failure_threshold)
+    }
+
+    /// Consecutive probes a listener has failed
+    pub(crate) fn failures(&self, listener_id: &str) -> u32 {
+        self.failures
+            .get(listener_id)
+            .map_or(0, |failures| *failures)
+    }
+
+    /// Drop a disconnected listener's record, so it starts out healthy if it reconnects
+    pub(crate) fn forget(&self, listener_id: &str) {
+        self.failures.remove(listener_id);
+    }
+
+    /// Whether `request_id` is a probe still waiting on its response
+    pub(crate) fn is_probe(&self, request_id: &Uuid) -> bool {
+        self.pending.contains_key(request_id)
+    }
+
+    /// Hand a response to the probe waiting on it. Responses to anything but a probe are given back.
+    pub(crate) fn take_response(
+        &self,
+        message: RednetRpcMessage<HttpResponse>,
+    ) -> Option<RednetRpcMessage<HttpResponse>> {
+        match self.pending.remove(&message.request_id) {
+            Some((_, sender)) => {
+                let _ = sender.send(message.payload);
+                None
+            }
+            None => Some(message),
+        }
+    }
+}
+
+/// Probe every connected listener each `health_probe_interval_secs`, unless that's zero
+pub(crate) fn spawn(server: Arc<Server>) {
+    if server.config.health_probe_interval_secs == 0 {
+        return;
+    }
+    let uri = match Origin::parse_owned(server.config.health_probe_path.clone()) {
+        Ok(uri) => uri,
+        Err(e) => {
+            rocket::error!(
+                "Not probing listeners, invalid health_probe_path {:?}: {}",
+                server.config.health_probe_path,
+                e
+            );
+            return;
+        }
+    };
+
+    tokio::spawn(async move {
+        let mut ticks = interval(Duration::from_secs(
+            server.config.health_probe_interval_secs,
+        ));
+        // A slow round shouldn't be followed by a burst of probes to catch up
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+
+            let listener_ids = server
+                .listeners
+                .iter()
+                .map(|r| r.key().clone())
+                .collect::<Vec<_>>();
+            for listener_id in listener_ids {
+                tokio::spawn(probe(Arc::clone(&server), listener_id, uri.clone()));
+            }
+        }
+    });
+}
+
+/// Send one listener a `GET` of the probe path and record whether it answered with a 2xx in time
+async fn probe(server: Arc<Server>, listener_id: ComputerId, uri: Origin<'static>) {
+    let Some(listener) = server
+        .listeners
+        .get(&listener_id)
+        .map(|r| r.value().sender.clone())
+    else {
+        return;
+    };
+
+    let request_id = Uuid::new_v4();
+    let (tx, rx) = oneshot::channel();
+    server.health.pending.insert(request_id, tx);
+
+    let message = ListenerMessage::Http(RednetRpcMessage {
+        v: PROTOCOL_VERSION,
+        dest: RednetRpcDestination::Computer {
+            id: listener_id.clone(),
+            protocol: None,
+        },
+        request_id,
+        payload: HttpRequest {
+            method: Method::Get,
+            uri,
+            headers: HashMap::new(),
+            body: String::new(),
+            body_base64: false,
+            body_sha256: None,
+        },
+    });
+    // A full queue means the listener is busy rather than unhealthy, so skip this round
+    if listener.try_send(message).is_err() {
+        server.health.pending.remove(&request_id);
+        return;
+    }
+
+    let gateway_timeout = Duration::from_secs(server.config.gateway_timeout as u64);
+    let passed = match timeout(gateway_timeout, rx).await {
+        Ok(Ok(response)) => response.status.class().is_success(),
+        Ok(Err(_)) | Err(_) => false,
+    };
+    server.health.pending.remove(&request_id);
+
+    // The listener may have disconnected meanwhile, and a reconnect starts with a clean record
+    if !server.listeners.contains_key(&listener_id) {
+        return;
+    }
+    if passed {
+        record_pass(&server, &listener_id);
+    } else {
+        record_failure(&server, &listener_id);
+    }
+}
+
+fn record_pass(server: &Server, listener_id: &str) {
+    let was_healthy = server.health.is_healthy(listener_id, &server.config);
+    server.health.failures.remove(listener_id);
+    if !was_healthy {
+        rocket::info!("Listener {} passed a health probe", listener_id);
+        // Requests may be parked waiting for an eligible listener
+        server.listener_connected.notify_waiters();
+    }
+}
+
+fn record_failure(server: &Server, listener_id: &str) {
+    let mut failures = server
+        .health
+        .failures
+        .entry(listener_id.to_string())
+        .or_default();
+    *failures += 1;
+    if *failures == server.config.health_probe_failure_threshold {
+        rocket::warn!(
+            "Listener {} failed {} health probes in a row",
+            listener_id,
+            *failures
+        );
+    }
+}
@@ -1,58 +1,302 @@
-use std::{collections::HashMap, path::PathBuf, pin::Pin, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    path::{Component, Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use anyhow::Context;
-use dashmap::DashMap;
+use admin::Admin;
+use anyhow::{Context, bail};
+use balance::{Balancer, LoadBalanceStrategy};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use cache::ResponseCache;
+use codec::{Encoding, Negotiated, OfferedSubprotocols};
+use cors::{Cors, CorsConfig};
+use dashmap::{DashMap, DashSet, mapref::entry::Entry};
+use error::GatewayError;
+use health::HealthProbes;
 use pin_project::{pin_project, pinned_drop};
 use rand::Rng;
+use ratelimit::RateLimiter;
 use rocket::{
-    Data, Request, Response, Route, State,
+    Build, Data, Request, Response, Rocket, Route, State, catch, catchers,
     data::ByteUnit,
     fairing::AdHoc,
     futures::{
-        SinkExt, StreamExt,
-        channel::{
-            mpsc,
-            oneshot::{self, Canceled},
-        },
+        StreamExt,
+        channel::{mpsc, oneshot},
     },
     get,
     http::{Method, Status, ext::IntoOwned, uri::Origin},
-    launch,
     outcome::Outcome,
+    post,
     request::{self, FromRequest},
     response::Responder,
     route::Handler,
     routes,
+    serde::json::Json,
 };
-use rocket_ws::Message;
+use rocket_ws::{
+    Message,
+    frame::{CloseCode, CloseFrame},
+};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use tokio::time::timeout;
+use sha2::{Digest, Sha256};
+use sse::EventStream;
+use tokio::{
+    sync::{Notify, mpsc::error::TrySendError},
+    time::{Instant, timeout, timeout_at},
+};
+use transport::{LoopbackTransport, ProxyError, Transport, TransportKind, WebSocketTransport};
+use tunnel::TunnelFrame;
 use uuid::Uuid;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+mod admin;
+mod balance;
+mod cache;
+mod codec;
+mod cors;
+mod error;
+mod health;
+mod ratelimit;
+mod sse;
+mod transport;
+mod tunnel;
+mod validate;
 
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 struct GatewayConfig {
     #[serde(default = "default_gateway_timeout")]
     gateway_timeout: u32,
     rednet: PathBuf,
+    /// Consecutive failures after which a listener stops being selected (0 disables the circuit breaker)
+    #[serde(default = "default_circuit_failure_threshold")]
+    circuit_failure_threshold: u32,
+    /// Window in seconds within which failures count towards opening the circuit
+    #[serde(default = "default_circuit_failure_window")]
+    circuit_failure_window: u32,
+    /// Seconds an open circuit waits before letting a probe request through
+    #[serde(default = "default_circuit_cooldown")]
+    circuit_cooldown: u32,
+    /// Milliseconds a request waits for a listener to connect when none is available (0 fails immediately)
+    #[serde(default)]
+    connect_wait_ms: u64,
+    /// Requests per second allowed per client IP (0 disables rate limiting). Requests whose client IP
+    /// isn't known, which only happens off TCP, aren't limited rather than all sharing one bucket.
+    #[serde(default)]
+    rate_limit: u32,
+    /// Requests a client may burst above `rate_limit` (defaults to `rate_limit`)
+    #[serde(default)]
+    rate_limit_burst: u32,
+    /// Rate limit each client separately per route prefix rather than across all routes
+    #[serde(default)]
+    rate_limit_per_route: bool,
+    /// How to choose among eligible listeners: `random` (default), `round_robin` or `least_in_flight`
+    #[serde(default)]
+    load_balance: LoadBalanceStrategy,
+    /// Responses kept by the cache for routes with `cache_ttl_secs` (0 disables caching)
+    #[serde(default = "default_cache_max_entries")]
+    cache_max_entries: usize,
+    /// Consecutive malformed frames tolerated from a listener before disconnecting it
+    #[serde(default = "default_listener_parse_failure_limit")]
+    listener_parse_failure_limit: u32,
+    /// Messages queued for a listener before new requests to it are rejected with 503
+    #[serde(default = "default_listener_queue_depth")]
+    listener_queue_depth: usize,
+    /// Requests and tunnels a listener may serve at once before it's passed over for another (0, the
+    /// default, is unlimited). A listener advertising a lower `max_concurrency` gets that instead.
+    #[serde(default)]
+    listener_max_concurrency: usize,
+    /// Refuse a second connection for a listener ID that's already connected with 409, instead of replacing it
+    #[serde(default)]
+    reject_duplicate_listeners: bool,
+    /// Largest response body accepted from a listener; bigger responses fail the request with 502
+    #[serde(default = "default_max_response_bytes")]
+    max_response_bytes: usize,
+    /// Largest request body accepted from a client; bigger requests are rejected with 413
+    #[serde(default = "default_max_request_bytes")]
+    max_request_bytes: usize,
+    /// Longest request URI, path and query together, accepted; longer ones are rejected with 414
+    #[serde(default = "default_max_uri_bytes")]
+    max_uri_bytes: usize,
+    /// How proxied requests reach a backend: `websocket` (default) or `loopback`, which echoes them back
+    #[serde(default)]
+    transport: TransportKind,
+    /// Let browsers on other origins call the gateway; no CORS headers are sent when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cors: Option<CorsConfig>,
+    /// Send a SHA-256 of each request body to listeners, and reject responses whose body doesn't
+    /// match the SHA-256 the listener sent with it as 502
+    #[serde(default)]
+    verify_body_integrity: bool,
+    /// Seconds a listener may go without traffic either way before it's disconnected, freeing its
+    /// slot (0, the default, never disconnects idle listeners). Health probes sent to it don't count,
+    /// so a listener left answering nothing else is still dropped. Pings and pongs don't either.
+    #[serde(default)]
+    listener_idle_timeout_secs: u64,
+    /// Tell a listener when it sends a response for a request the gateway doesn't know, e.g. one
+    /// that already timed out, with a `{"unknownRequest": "<id>"}` frame. Meant for debugging listeners.
+    #[serde(default)]
+    nack_unknown_responses: bool,
+    /// Reject listeners that don't negotiate a `Sec-WebSocket-Protocol` like `cc-rednet-json.v1`,
+    /// rather than assuming JSON or the encoding they ask for
+    #[serde(default)]
+    require_listener_subprotocol: bool,
+    /// Seconds between health probes sent to each listener (0, the default, sends none). Listeners
+    /// must answer a `GET` of `health_probe_path` with a 2xx to pass.
+    #[serde(default)]
+    health_probe_interval_secs: u64,
+    /// Path health probes request, reserved so it doesn't collide with a computer's own routes
+    #[serde(default = "default_health_probe_path")]
+    health_probe_path: String,
+    /// Consecutive failed health probes after which a listener stops being selected, until it
+    /// passes one (0 keeps probing without ever excluding listeners)
+    #[serde(default = "default_health_probe_failure_threshold")]
+    health_probe_failure_threshold: u32,
+    /// Bearer token the `/admin` endpoints require, best set through `ROCKET_ADMIN_TOKEN`. They're
+    /// refused with 403 while it's unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    admin_token: Option<String>,
 }
 
 fn default_gateway_timeout() -> u32 {
     5
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+fn default_circuit_failure_threshold() -> u32 {
+    5
+}
+
+fn default_circuit_failure_window() -> u32 {
+    60
+}
+
+fn default_circuit_cooldown() -> u32 {
+    30
+}
+
+fn default_cache_max_entries() -> usize {
+    1000
+}
+
+fn default_listener_parse_failure_limit() -> u32 {
+    5
+}
+
+fn default_listener_queue_depth() -> usize {
+    1000
+}
+
+fn default_max_response_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_max_request_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_max_uri_bytes() -> usize {
+    8 * 1024
+}
+
+fn default_health_probe_path() -> String {
+    "/__health__".to_string()
+}
+
+fn default_health_probe_failure_threshold() -> u32 {
+    3
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default, JsonSchema)]
 struct RednetConfig {
     routes: Vec<HttpOverRednetRoute>,
+    /// Backend for requests that match no route, which otherwise get a 404
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    default_backend: Option<RednetRpcDestination>,
+}
+
+#[rocket::main]
+async fn main() -> Result<(), rocket::Error> {
+    if std::env::args().any(|arg| arg == "--print-config-schema") {
+        print_config_schema();
+        return Ok(());
+    }
+
+    rocket().await.launch().await?;
+    Ok(())
+}
+
+/// Print JSON schemas for the gateway's Rocket config and the rednet route config,
+/// listing every option along with its default
+fn print_config_schema() {
+    let schemas = serde_json::json!({
+        "gateway": schemars::schema_for!(GatewayConfig),
+        "rednet": schemars::schema_for!(RednetConfig),
+    });
+    println!("{}", serde_json::to_string_pretty(&schemas).unwrap());
 }
 
-#[launch]
-async fn rocket() -> _ {
-    let server = Arc::<Server>::default();
+async fn rocket() -> Rocket<Build> {
+    gateway(rocket::build())
+}
+
+/// Mount the gateway on `rocket`, configured from its figment
+fn gateway(rocket: Rocket<Build>) -> Rocket<Build> {
+    let gateway_config = match rocket.figment().extract::<GatewayConfig>() {
+        Ok(config) => config,
+        // Extracting the config again on ignite reports the error and aborts launch
+        Err(_) => return rocket.attach(AdHoc::config::<GatewayConfig>()),
+    };
+    let rednet = gateway_config.rednet.clone();
+    let cors_config = gateway_config.cors.clone();
+    let server = Arc::new(Server::new(gateway_config));
+    let transport: Arc<dyn Transport> = match server.config.transport {
+        TransportKind::Websocket => Arc::new(WebSocketTransport {
+            server: Arc::clone(&server),
+        }),
+        TransportKind::Loopback => Arc::new(LoopbackTransport),
+    };
 
-    rocket::build()
+    let rocket = rocket
         .attach(AdHoc::config::<GatewayConfig>())
+        // The config is reloaded on every request, but catch a bad one before taking traffic
+        .attach(AdHoc::try_on_ignite("Rednet config", {
+            let server = Arc::clone(&server);
+            |rocket| async move {
+                match RednetConfig::load(&rednet).await {
+                    Ok(config) => {
+                        server.observe_rednet_config(&config);
+                        Ok(rocket)
+                    }
+                    Err(e) => {
+                        rocket::error!("Failed to load rednet config: {e:#}");
+                        Err(rocket)
+                    }
+                }
+            }
+        }))
+        .attach(AdHoc::on_liftoff("Listener health probes", {
+            let server = Arc::clone(&server);
+            move |_| Box::pin(async move { health::spawn(server) })
+        }))
+        .attach(AdHoc::on_liftoff("Rate limit sweeps", {
+            let server = Arc::clone(&server);
+            move |_| Box::pin(async move { ratelimit::spawn(server) })
+        }))
         .manage(Arc::clone(&server))
+        .mount("/", routes![status])
+        .mount(
+            "/admin",
+            routes![
+                list_listeners,
+                drain_listener,
+                undrain_listener,
+                validate::route_table
+            ],
+        )
         .mount("/link", routes![listen])
         .mount(
             "/gateway",
@@ -70,11 +314,79 @@ async fn rocket() -> _ {
                     "/<path..>?<query..>",
                     GatewayHandler {
                         server: Arc::clone(&server),
+                        transport: Arc::clone(&transport),
                     },
                 )
             })
             .collect::<Vec<_>>(),
         )
+        .register("/gateway", catchers![gateway_error]);
+
+    match cors_config {
+        Some(cors_config) => rocket
+            .attach(Cors(cors_config.clone()))
+            .manage(cors_config)
+            .mount("/gateway", routes![cors::preflight]),
+        None => rocket,
+    }
+}
+
+impl RednetConfig {
+    async fn load(path: &Path) -> anyhow::Result<Self> {
+        tokio::fs::read_to_string(path)
+            .await
+            .context("load rednet config")
+            .and_then(|data: String| {
+                serde_yaml_ng::from_str(&data).map_err(|e| {
+                    // Quote the offending line, since the config is usually edited far from the gateway
+                    let line = e
+                        .location()
+                        .and_then(|location| data.lines().nth(location.line().saturating_sub(1)));
+                    match line {
+                        Some(line) => anyhow::Error::new(e)
+                            .context(format!("Failed to parse rednet config at {line:?}")),
+                        None => anyhow::Error::new(e).context("Failed to parse rednet config"),
+                    }
+                })
+            })
+            .and_then(|config: RednetConfig| config.validate().context("Invalid rednet config"))
+    }
+
+    /// Route prefixes in match order, for comparing one load of the config against the next
+    fn prefixes(&self) -> Vec<String> {
+        self.routes
+            .iter()
+            .map(|route| route.prefix.display().to_string())
+            .collect()
+    }
+
+    fn validate(mut self) -> anyhow::Result<Self> {
+        for route in &mut self.routes {
+            route.normalize_prefix()?;
+            route.validate_rewrite_path()?;
+            route.normalize_backends()?;
+        }
+
+        // Routes are matched in order, so a trailing catch-all only sees what nothing else matched
+        if let Some(backend) = self.default_backend.take() {
+            self.routes.push(HttpOverRednetRoute {
+                prefix: PathBuf::from("/"),
+                strip_prefix: false,
+                rewrite_path: None,
+                host: None,
+                backend: None,
+                backends: vec![WeightedBackend { backend, weight: 1 }],
+                request_headers_set: HashMap::new(),
+                request_headers_remove: vec![],
+                response_headers_set: HashMap::new(),
+                websocket: false,
+                cache_ttl_secs: None,
+                mirror: None,
+            });
+        }
+
+        Ok(self)
+    }
 }
 
 // HTTP over Rednet over WebSocket
@@ -83,23 +395,22 @@ type ComputerId = String;
 
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for RednetConfig {
-    type Error = ();
+    type Error = GatewayError;
 
     async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
         let gateway_config = State::<GatewayConfig>::get(request.rocket()).unwrap();
 
-        let result = tokio::fs::read_to_string(&gateway_config.rednet)
-            .await
-            .context("load rednet config")
-            .and_then(|data: String| {
-                serde_yaml_ng::from_str(&data).context("Failed to parse rednet config")
-            });
-
-        let rednet = match result {
-            Ok(config) => config,
+        let rednet = match RednetConfig::load(&gateway_config.rednet).await {
+            Ok(config) => {
+                if let Some(server) = request.rocket().state::<Arc<Server>>() {
+                    server.observe_rednet_config(&config);
+                }
+                config
+            }
             Err(e) => {
-                rocket::error!("Failed to load rednet config: {e}");
-                return Outcome::Error((Status::BadGateway, ()));
+                rocket::error!("Failed to load rednet config: {e:#}");
+                let error = GatewayError::Config;
+                return Outcome::Error((error.status(), error));
             }
         };
 
@@ -107,14 +418,62 @@ impl<'r> FromRequest<'r> for RednetConfig {
     }
 }
 
+/// Version of the listener wire format this gateway speaks
+const PROTOCOL_VERSION: u32 = 1;
+
+fn default_protocol_version() -> u32 {
+    // Listeners predating versioning send no version, and speak the first one
+    1
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct RednetRpcMessage<T> {
+    /// Wire format version, so that listeners and the gateway can detect a mismatch
+    #[serde(default = "default_protocol_version")]
+    v: u32,
     dest: RednetRpcDestination,
     #[serde(rename = "requestID")]
     request_id: Uuid,
     payload: T,
 }
 
+/// Messages sent from the gateway to a listener
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+enum ListenerMessage {
+    Http(RednetRpcMessage<HttpRequest>),
+    Tunnel(RednetRpcMessage<TunnelFrame>),
+    UnknownRequest(UnknownRequestNack),
+}
+
+/// Queues messages for a listener's connection. Tokio's channel rather than futures', which gives every
+/// clone of a sender a slot of its own, so that the queue holds to `listener_queue_depth`.
+type ListenerSender = tokio::sync::mpsc::Sender<ListenerMessage>;
+
+/// Tells a listener the gateway has no request waiting on a response it sent
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UnknownRequestNack {
+    unknown_request: Uuid,
+}
+
+/// Messages sent from a listener to the gateway
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum ListenerReply {
+    Tunnel(RednetRpcMessage<TunnelFrame>),
+    Http(RednetRpcMessage<HttpResponse>),
+}
+
+impl ListenerReply {
+    fn version(&self) -> u32 {
+        match self {
+            ListenerReply::Tunnel(msg) => msg.v,
+            ListenerReply::Http(msg) => msg.v,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct HttpRequest {
     method: Method,
@@ -123,15 +482,40 @@ struct HttpRequest {
     headers: HashMap<String, Vec<String>>,
     #[serde(default, skip_serializing_if = "String::is_empty")]
     body: String,
+    /// Whether `body` holds base64-encoded bytes rather than text
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    body_base64: bool,
+    /// Hex SHA-256 of `body`, sent when `verify_body_integrity` is on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body_sha256: Option<String>,
 }
 
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for HttpRequest {
-    type Error = ();
+    type Error = GatewayError;
 
     async fn from_request(request: &'r Request<'_>) -> request::Outcome<HttpRequest, Self::Error> {
+        let gateway_config = State::<GatewayConfig>::get(request.rocket()).unwrap();
+
+        let uri = request.uri();
+        let uri_len =
+            uri.path().as_str().len() + uri.query().map_or(0, |query| query.as_str().len() + 1);
+        if uri_len > gateway_config.max_uri_bytes {
+            let error = GatewayError::UriTooLong;
+            return Outcome::Error((error.status(), error));
+        }
+        // Backends get the path as sent, but one that doesn't decode to UTF-8 can't name anything
+        if uri.path().raw().percent_decode().is_err()
+            || uri
+                .query()
+                .is_some_and(|query| query.raw().percent_decode().is_err())
+        {
+            let error = GatewayError::MalformedUri;
+            return Outcome::Error((error.status(), error));
+        }
+
         let method = request.method();
-        let uri = request.uri().clone().into_owned();
+        let uri = uri.clone().into_owned();
         let headers = request.headers().iter().fold(
             HashMap::<String, Vec<String>>::new(),
             |mut acc, header| {
@@ -147,6 +531,8 @@ impl<'r> FromRequest<'r> for HttpRequest {
             uri,
             headers,
             body: String::new(), // Placeholder, body will be filled in later
+            body_base64: false,
+            body_sha256: None,
         })
     }
 }
@@ -158,15 +544,33 @@ struct HttpResponse {
     headers: HashMap<String, Vec<String>>,
     #[serde(default)]
     body: String,
+    /// Whether `body` holds base64-encoded bytes rather than text, for binary responses like images
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    body_base64: bool,
+    /// Hex SHA-256 of `body`, which listeners must send when `verify_body_integrity` is on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body_sha256: Option<String>,
+}
+
+impl HttpResponse {
+    /// The body as sent to the client, decoded if the listener sent it as base64. Bodies that aren't
+    /// valid base64 are turned away in `handle_response`, so are never seen here.
+    fn body_bytes(&self) -> Vec<u8> {
+        if self.body_base64 {
+            BASE64_STANDARD.decode(&self.body).unwrap_or_default()
+        } else {
+            self.body.clone().into_bytes()
+        }
+    }
 }
 
 impl<'r, 'o: 'r> Responder<'r, 'o> for HttpResponse {
     fn respond_to(self, _request: &'r Request<'_>) -> rocket::response::Result<'o> {
+        let body = self.body_bytes();
         let mut builder = Response::build();
-        builder.status(self.status).sized_body(
-            self.body.len(),
-            std::io::Cursor::new(self.body.into_bytes()),
-        );
+        builder
+            .status(self.status)
+            .sized_body(body.len(), std::io::Cursor::new(body));
 
         for (header_name, header_values) in self.headers {
             for header_value in header_values {
@@ -178,7 +582,7 @@ impl<'r, 'o: 'r> Responder<'r, 'o> for HttpResponse {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 enum RednetRpcDestination {
     Anycast {
@@ -188,36 +592,501 @@ enum RednetRpcDestination {
         id: ComputerId,
         protocol: Option<String>,
     },
+    /// The controller's `RednetBackend` calls this variant `hostname`, so accept that spelling
+    /// in configs it renders
+    #[serde(alias = "hostname")]
     Host {
         protocol: String,
         host: String,
     },
+    /// Like `Anycast`, but requests with the same `key_header` value always go to the same listener
+    Sharded {
+        protocol: String,
+        key_header: String,
+    },
+    /// Like `Anycast`, but only to listeners advertising every one of `capabilities`
+    Capable {
+        protocol: String,
+        capabilities: Vec<String>,
+    },
 }
 
-#[derive(Debug, Default)]
+impl RednetRpcDestination {
+    fn protocol(&self) -> Option<&str> {
+        match self {
+            RednetRpcDestination::Anycast { protocol }
+            | RednetRpcDestination::Host { protocol, .. }
+            | RednetRpcDestination::Sharded { protocol, .. }
+            | RednetRpcDestination::Capable { protocol, .. } => Some(protocol),
+            RednetRpcDestination::Computer { protocol, .. } => protocol.as_deref(),
+        }
+    }
+
+    /// Capabilities a listener must advertise to serve this destination
+    fn required_capabilities(&self) -> &[String] {
+        match self {
+            RednetRpcDestination::Capable { capabilities, .. } => capabilities,
+            _ => &[],
+        }
+    }
+
+    /// The key a sharded destination routes `request` by, if it has one
+    fn shard_key<'a>(&self, request: &'a HttpRequest) -> Option<&'a str> {
+        let RednetRpcDestination::Sharded { key_header, .. } = self else {
+            return None;
+        };
+
+        request
+            .headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(key_header))
+            .and_then(|(_, values)| values.first())
+            .map(String::as_str)
+    }
+}
+
+/// A request sent to a listener, waiting for its response
+#[derive(Debug)]
+struct InFlightRequest {
+    listener_id: ComputerId,
+    sender: oneshot::Sender<Result<HttpResponse, ProxyError>>,
+}
+
+/// The queue of a connected listener, tagged with the connection that registered it
+#[derive(Debug)]
+struct ListenerConnection {
+    /// Lets a closing connection tell whether the entry is still its own or a reconnect's
+    token: Uuid,
+    sender: ListenerSender,
+    stats: ListenerStats,
+}
+
+/// What a listener has done since it connected, for diagnosing flapping or slow computers
+#[derive(Debug, Clone, Serialize)]
+struct ListenerStats {
+    connected_unix_sec: u64,
+    /// When the listener last sent the gateway a frame
+    last_frame_unix_sec: Option<u64>,
+    /// Requests the listener answered
+    requests_served: u64,
+    /// Requests and tunnels that failed on the listener, including ones it never answered
+    errors: u64,
+}
+
+#[derive(Debug)]
 struct Server {
-    listeners: DashMap<ComputerId, mpsc::Sender<RednetRpcMessage<HttpRequest>>>,
-    in_flight_requests: DashMap<Uuid, oneshot::Sender<HttpResponse>>,
+    config: GatewayConfig,
+    listeners: DashMap<ComputerId, ListenerConnection>,
+    in_flight_requests: DashMap<Uuid, InFlightRequest>,
+    circuits: DashMap<ComputerId, Circuit>,
+    health: HealthProbes,
+    tunnels: DashMap<Uuid, mpsc::Sender<TunnelFrame>>,
+    /// Event streams opened by a listener's response, waiting for the request's handler to take them
+    event_streams: DashMap<Uuid, mpsc::Receiver<TunnelFrame>>,
+    listener_connected: Notify,
+    rate_limiter: RateLimiter,
+    balancer: Balancer,
+    /// Requests and tunnels currently being served by each listener
+    listener_load: DashMap<ComputerId, usize>,
+    /// Protocols advertised by each listener; listeners that advertise none serve every protocol
+    listener_protocols: DashMap<ComputerId, HashSet<String>>,
+    /// Capabilities advertised by each listener, like `has-printer`; unlike protocols, none means none
+    listener_capabilities: DashMap<ComputerId, HashSet<String>>,
+    /// Concurrency limits advertised by listeners, never zero, capped by `listener_max_concurrency`
+    listener_concurrency: DashMap<ComputerId, usize>,
+    response_cache: ResponseCache,
+    /// Listeners an operator has taken out of selection; they stay connected to finish in-flight requests
+    drained_listeners: DashSet<ComputerId>,
+    /// Route prefixes of the last rednet config loaded, to log what each reload changed
+    rednet_prefixes: Mutex<Option<Vec<String>>>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Failure tracking for a single listener.
+///
+/// The circuit opens once `circuit_failure_threshold` consecutive failures land within
+/// `circuit_failure_window`, excluding the listener from selection. After `circuit_cooldown`
+/// it half-opens: a single probe request is let through, and its outcome either closes the
+/// circuit (success) or re-opens it for another cooldown (failure).
+#[derive(Debug, Default)]
+struct Circuit {
+    failures: u32,
+    window_start: Option<Instant>,
+    last_failure: Option<Instant>,
+    /// When the probe request was let through the half-open circuit. Nothing else is until it
+    /// settles, or until it's had the gateway timeout to, in case it was cancelled or was a tunnel.
+    probe_started: Option<Instant>,
+}
+
+impl Circuit {
+    fn is_open(&self, config: &GatewayConfig) -> bool {
+        self.failures >= config.circuit_failure_threshold
+    }
+
+    fn is_selectable(&self, config: &GatewayConfig) -> bool {
+        config.circuit_failure_threshold == 0
+            || !self.is_open(config)
+            || (self.is_cooled_down(config) && !self.is_probing(config))
+    }
+
+    fn is_cooled_down(&self, config: &GatewayConfig) -> bool {
+        self.last_failure
+            .is_some_and(|t| t.elapsed() >= Duration::from_secs(config.circuit_cooldown as u64))
+    }
+
+    fn is_probing(&self, config: &GatewayConfig) -> bool {
+        self.probe_started
+            .is_some_and(|t| t.elapsed() < Duration::from_secs(config.gateway_timeout as u64))
+    }
+
+    fn record_failure(&mut self, config: &GatewayConfig) {
+        let now = Instant::now();
+        let window = Duration::from_secs(config.circuit_failure_window as u64);
+
+        // Failures keep accumulating while open so a failed probe re-opens the circuit
+        if !self.is_open(config)
+            && self
+                .window_start
+                .is_none_or(|start| now.duration_since(start) > window)
+        {
+            self.window_start = Some(now);
+            self.failures = 0;
+        }
+
+        self.failures += 1;
+        self.last_failure = Some(now);
+        self.probe_started = None;
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 struct HttpOverRednetRoute {
     prefix: PathBuf,
+    /// Remove `prefix` from the path sent to the backend, so `/api/x` on an `/api` route arrives as `/x`
+    #[serde(default)]
+    strip_prefix: bool,
+    /// Send the backend this path instead, with `{rest}` replaced by the part of the request path
+    /// after `prefix`, e.g. `/v1/{rest}`. The query string is kept. Can't be combined with `strip_prefix`,
+    /// which is the same as `/{rest}`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rewrite_path: Option<String>,
+    /// Only match requests for this `Host`, either exact or a `*.` wildcard covering any subdomain
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    host: Option<String>,
+    /// Shorthand for a single entry in `backends`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    backend: Option<RednetRpcDestination>,
+    /// Backends to split requests between in proportion to their weights
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    backends: Vec<WeightedBackend>,
+    /// Headers to set on the request before forwarding, replacing any existing values
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    request_headers_set: HashMap<String, String>,
+    /// Headers to strip from the request before forwarding (case-insensitive)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    request_headers_remove: Vec<String>,
+    /// Headers to set on the response returned to the client, replacing any existing values
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    response_headers_set: HashMap<String, String>,
+    /// Tunnel WebSocket upgrade requests through to the backend instead of proxying them once
+    #[serde(default)]
+    websocket: bool,
+    /// Cache successful GET responses for this many seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cache_ttl_secs: Option<u64>,
+    /// Also send a copy of each request to this backend, discarding its response
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mirror: Option<RednetRpcDestination>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+struct WeightedBackend {
     backend: RednetRpcDestination,
+    weight: u32,
 }
 
 impl HttpOverRednetRoute {
+    /// Match the request path against the prefix on whole path segments,
+    /// so `/api` matches `/api` and `/api/x` but not `/apix`
     fn check(&self, req: &HttpRequest) -> bool {
-        match self.prefix.to_str() {
-            Some(prefix_str) => req.uri.path().starts_with(prefix_str),
-            None => false,
+        let Some(prefix) = self.prefix.to_str() else {
+            return false;
+        };
+
+        if self
+            .host
+            .as_deref()
+            .is_some_and(|host| !host_matches(host, req))
+        {
+            return false;
+        }
+
+        prefix == "/"
+            || req
+                .uri
+                .path()
+                .as_str()
+                .strip_prefix(prefix)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+
+    /// The path the backend should see for a request this route matched: `rewrite_path` filled in,
+    /// the path with the prefix removed if `strip_prefix` is set, or otherwise the request's own
+    fn backend_uri(&self, uri: &Origin<'_>) -> Option<Origin<'static>> {
+        let template = match (&self.rewrite_path, self.strip_prefix) {
+            (Some(template), _) => template.as_str(),
+            (None, true) => "/{rest}",
+            (None, false) => return Some(uri.clone().into_owned()),
+        };
+
+        let prefix = self.prefix.to_str()?;
+        let rest = uri
+            .path()
+            .as_str()
+            .strip_prefix(prefix)?
+            .trim_start_matches('/');
+        let path = template.replace("{rest}", rest);
+        let uri = match uri.query() {
+            Some(query) => format!("{path}?{}", query.as_str()),
+            None => path.to_string(),
+        };
+        Origin::parse_owned(uri).ok()
+    }
+
+    /// Require an absolute prefix and normalize it to `/seg/seg` form, without duplicate or trailing slashes
+    fn normalize_prefix(&mut self) -> anyhow::Result<()> {
+        if !self.prefix.has_root() {
+            bail!("route prefix {:?} must be absolute", self.prefix);
+        }
+
+        let mut normalized = PathBuf::from("/");
+        for component in self.prefix.components() {
+            match component {
+                Component::RootDir | Component::CurDir => {}
+                Component::Normal(segment) => normalized.push(segment),
+                Component::ParentDir | Component::Prefix(_) => {
+                    bail!("route prefix {:?} must not contain '..'", self.prefix);
+                }
+            }
+        }
+
+        if normalized.to_str().is_none() {
+            bail!("route prefix {:?} must be valid UTF-8", self.prefix);
+        }
+
+        self.prefix = normalized;
+        Ok(())
+    }
+
+    /// Require `rewrite_path` to be absolute and not combined with `strip_prefix`
+    fn validate_rewrite_path(&self) -> anyhow::Result<()> {
+        let Some(rewrite_path) = &self.rewrite_path else {
+            return Ok(());
+        };
+
+        if self.strip_prefix {
+            bail!(
+                "route {:?} must set only one of strip_prefix and rewrite_path",
+                self.prefix
+            );
+        }
+        // Checked with `{rest}` empty, so a bad template fails the load rather than each request
+        if !rewrite_path.starts_with('/')
+            || Origin::parse(&rewrite_path.replace("{rest}", "")).is_err()
+        {
+            bail!(
+                "route {:?} rewrite_path {:?} must be an absolute path",
+                self.prefix,
+                rewrite_path
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Fold the `backend` shorthand into `backends`, requiring exactly one of them and a nonzero total weight
+    fn normalize_backends(&mut self) -> anyhow::Result<()> {
+        match (self.backend.take(), self.backends.is_empty()) {
+            (Some(backend), true) => self.backends.push(WeightedBackend { backend, weight: 1 }),
+            (Some(_), false) => bail!(
+                "route {:?} must set only one of backend and backends",
+                self.prefix
+            ),
+            (None, true) => bail!("route {:?} has no backend", self.prefix),
+            (None, false) => {}
+        }
+
+        if self.backends.iter().all(|backend| backend.weight == 0) {
+            bail!(
+                "route {:?} has no backend with a nonzero weight",
+                self.prefix
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Choose a backend at random in proportion to the weights
+    fn pick_backend(&self) -> &RednetRpcDestination {
+        let total = self.backends.iter().map(|b| b.weight as u64).sum::<u64>();
+        let mut point = rand::rng().random_range(0..total);
+        for backend in &self.backends {
+            if point < backend.weight as u64 {
+                return &backend.backend;
+            }
+            point -= backend.weight as u64;
+        }
+
+        unreachable!("point is below the total weight")
+    }
+
+    fn rewrite_request_headers(&self, req: &mut HttpRequest) {
+        for name in &self.request_headers_remove {
+            remove_header(&mut req.headers, name);
         }
+        set_headers(&mut req.headers, &self.request_headers_set);
+    }
+}
+
+/// The request's `Host` header, lowercased and without any port
+fn request_host(req: &HttpRequest) -> Option<String> {
+    let host = req
+        .headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("Host"))
+        .and_then(|(_, values)| values.first())?;
+    let host = host
+        .rsplit_once(':')
+        .filter(|(_, port)| port.bytes().all(|b| b.is_ascii_digit()))
+        .map_or(host.as_str(), |(host, _)| host)
+        .to_ascii_lowercase();
+    Some(host)
+}
+
+/// Compare a route's host pattern against the request's `Host` header, ignoring case and any port
+fn host_matches(pattern: &str, req: &HttpRequest) -> bool {
+    let Some(host) = request_host(req) else {
+        return false;
+    };
+    let pattern = pattern.to_ascii_lowercase();
+
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host
+            .strip_suffix(domain)
+            .and_then(|subdomain| subdomain.strip_suffix('.'))
+            .is_some_and(|subdomain| !subdomain.is_empty()),
+        None => host == pattern,
+    }
+}
+
+/// W3C trace context header, forwarded to backends so they can join the caller's trace
+const TRACEPARENT: &str = "traceparent";
+
+/// Keep a valid incoming `traceparent` as-is, or start a new sampled trace if it's missing or malformed
+fn ensure_traceparent(headers: &mut HashMap<String, Vec<String>>) -> String {
+    let existing = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(TRACEPARENT))
+        .and_then(|(_, values)| values.first())
+        .filter(|value| is_valid_traceparent(value));
+    if let Some(traceparent) = existing {
+        return traceparent.clone();
+    }
+
+    let mut rng = rand::rng();
+    let traceparent = format!(
+        "00-{:032x}-{:016x}-01",
+        rng.random::<u128>().max(1),
+        rng.random::<u64>().max(1)
+    );
+    set_headers(
+        headers,
+        &[(TRACEPARENT.to_string(), traceparent.clone())].into(),
+    );
+    traceparent
+}
+
+/// Check a `traceparent` header has the `version-traceid-parentid-flags` shape, with non-zero IDs
+fn is_valid_traceparent(value: &str) -> bool {
+    let parts = value.split('-').collect::<Vec<_>>();
+    let [version, trace_id, parent_id, flags] = parts[..] else {
+        return false;
+    };
+
+    let is_hex = |s: &str, len: usize| {
+        s.len() == len && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    };
+    let is_zero = |s: &str| s.bytes().all(|b| b == b'0');
+
+    is_hex(version, 2)
+        && version != "ff"
+        && is_hex(trace_id, 32)
+        && !is_zero(trace_id)
+        && is_hex(parent_id, 16)
+        && !is_zero(parent_id)
+        && is_hex(flags, 2)
+}
+
+/// Strip the `/gateway` mount point from a request URI and normalize the path backends see, so
+/// that equivalent requests all look alike:
+///
+/// - runs of slashes collapse into one, so `/gateway//api` becomes `/api`
+/// - `/gateway` and `/gateway/` both become `/`
+/// - any other trailing slash is kept, since backends may treat `/api/` differently from `/api`
+///
+/// The query string is left byte-for-byte as the client sent it: percent-encoding, repeated keys
+/// and empty values are never decoded or reordered.
+fn strip_gateway_prefix(uri: &Origin<'_>) -> Option<Origin<'static>> {
+    let path = uri.path().as_str();
+    let path = path.strip_prefix("/gateway").unwrap_or(path);
+
+    let mut normalized = String::with_capacity(path.len() + 1);
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if normalized.is_empty() || path.ends_with('/') {
+        normalized.push('/');
+    }
+
+    let uri = match uri.query() {
+        Some(query) => format!("{normalized}?{}", query.as_str()),
+        None => normalized,
+    };
+    Origin::parse_owned(uri).ok()
+}
+
+fn is_websocket_upgrade(request: &Request<'_>) -> bool {
+    request
+        .headers()
+        .get_one("Upgrade")
+        .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+}
+
+/// Whether the request body is `multipart/*` or `application/octet-stream`, which may not be valid UTF-8
+fn has_binary_body(request: &Request<'_>) -> bool {
+    request.content_type().is_some_and(|content_type| {
+        content_type.top() == "multipart"
+            || (content_type.top() == "application" && content_type.sub() == "octet-stream")
+    })
+}
+
+/// Remove all values of a header, comparing names case-insensitively
+fn remove_header(headers: &mut HashMap<String, Vec<String>>, name: &str) {
+    headers.retain(|existing, _| !existing.eq_ignore_ascii_case(name));
+}
+
+/// Set headers, replacing any existing values regardless of name casing
+fn set_headers(headers: &mut HashMap<String, Vec<String>>, set: &HashMap<String, String>) {
+    for (name, value) in set {
+        remove_header(headers, name);
+        headers.insert(name.clone(), vec![value.clone()]);
     }
 }
 
 #[derive(Clone)]
 struct GatewayHandler {
     server: Arc<Server>,
+    transport: Arc<dyn Transport>,
 }
 
 #[rocket::async_trait]
@@ -227,156 +1096,889 @@ impl Handler for GatewayHandler {
         request: &'r Request<'_>,
         data: Data<'r>,
     ) -> rocket::route::Outcome<'r> {
+        let start = Instant::now();
+        let mut access_log = AccessLog::default();
+
+        let outcome = match self.proxy(request, data, &mut access_log).await {
+            Ok(response) => Outcome::Success(response),
+            Err(e) => Outcome::from(request, e),
+        };
+
+        let status = match &outcome {
+            Outcome::Success(response) => response.status(),
+            Outcome::Error(status) | Outcome::Forward((_, status)) => *status,
+        };
+        tracing::info!(
+            target: "gateway::access",
+            method = %request.method(),
+            path = %request.uri().path(),
+            route = access_log.route.as_deref().unwrap_or_default(),
+            backend_path = access_log.backend_path.as_deref().unwrap_or_default(),
+            computer_id = access_log.computer_id.as_deref().unwrap_or_default(),
+            traceparent = access_log.traceparent.as_deref().unwrap_or_default(),
+            status = status.code,
+            elapsed_ms = start.elapsed().as_millis() as u64,
+            "proxied request"
+        );
+
+        outcome
+    }
+}
+
+/// Details of a proxied request that are only known partway through handling it
+#[derive(Debug, Default)]
+struct AccessLog {
+    route: Option<String>,
+    /// Path forwarded to the backend, after stripping `/gateway` and normalizing
+    backend_path: Option<String>,
+    computer_id: Option<ComputerId>,
+    traceparent: Option<String>,
+}
+
+impl GatewayHandler {
+    async fn proxy<'r>(
+        &self,
+        request: &'r Request<'_>,
+        data: Data<'r>,
+        access_log: &mut AccessLog,
+    ) -> Result<Response<'r>, GatewayError> {
         let gateway_config = State::<GatewayConfig>::get(request.rocket()).unwrap();
 
+        // Neither guard ever forwards, so a forward would only mean a bug here
         let rednet = match RednetConfig::from_request(request).await {
             Outcome::Success(cfg) => cfg,
-            Outcome::Error((status, ())) => {
+            Outcome::Error((_, e)) => {
                 rocket::error!("Failed to get rednet config during request");
-                return Outcome::Error(status);
+                return Err(e);
             }
-            Outcome::Forward(status) => return Outcome::Forward((data, status)),
+            Outcome::Forward(_) => return Err(GatewayError::Internal),
         };
 
-        let mut http_request = HttpRequest::from_request(request).await.unwrap();
+        let mut http_request = match HttpRequest::from_request(request).await {
+            Outcome::Success(http_request) => http_request,
+            Outcome::Error((_, e)) => {
+                rocket::warn!("Rejecting request with malformed or oversized URI: {:?}", e);
+                return Err(e);
+            }
+            Outcome::Forward(_) => return Err(GatewayError::Internal),
+        };
+        access_log.traceparent = Some(ensure_traceparent(&mut http_request.headers));
+        // The backend receives the whole body at once, so `Expect: 100-continue` means nothing to it
+        remove_header(&mut http_request.headers, "Expect");
 
-        http_request.uri = match http_request
-            .uri
-            .map_path(|p| p.strip_prefix("/gateway").unwrap_or(p))
-        {
+        http_request.uri = match strip_gateway_prefix(&http_request.uri) {
             Some(u) => u,
             None => {
-                rocket::error!(
-                    "Unexpected error stripping /gateway prefix from path: {}",
+                rocket::warn!(
+                    "Rejecting request whose path doesn't normalize: {}",
                     http_request.uri
                 );
-                return Outcome::Error(Status::InternalServerError);
+                return Err(GatewayError::MalformedUri);
             }
         };
 
-        let dest = match rednet
+        let route = rednet
             .routes
             .iter()
-            .find_map(|route| route.check(&http_request).then_some(route.backend.clone()))
-        {
-            None => return Outcome::Error(Status::NotFound),
-            Some(dest) => dest,
-        };
+            .find(|route| route.check(&http_request))
+            .ok_or(GatewayError::NoRoute)?;
 
-        http_request.body = match data.open(ByteUnit::Mebibyte(1)).into_string().await {
-            Ok(body) if body.is_complete() => body.into_inner(),
-            _ => {
-                rocket::error!("Incomplete body from client");
-                return Outcome::Error(Status::InternalServerError);
-            }
-        };
+        access_log.route = Some(route.prefix.display().to_string());
+        http_request.uri = match route.backend_uri(&http_request.uri) {
+            Some(u) => u,
+            None => {
+                rocket::error!(
+                    "Unexpected error rewriting path {} for route prefix {}",
+                    http_request.uri,
+                    route.prefix.display()
+                );
+                return Err(GatewayError::Internal);
+            }
+        };
+        access_log.backend_path = Some(http_request.uri.path().to_string());
+        route.rewrite_request_headers(&mut http_request);
+        let backend = route.pick_backend();
 
-        let request_id = Uuid::new_v4();
+        let rate_limit_key = rate_limit_key(
+            request.client_ip(),
+            Some(route.prefix.as_path()).filter(|_| gateway_config.rate_limit_per_route),
+        );
+        match &rate_limit_key {
+            Some(key) => self
+                .server
+                .rate_limiter
+                .check(key.clone())
+                .map_err(GatewayError::RateLimited)?,
+            None => tracing::debug!("Not rate limiting a request with no client IP"),
+        }
+        // Requests turned away before a listener takes them don't count against the client
+        let refund = |error: GatewayError| {
+            let refundable = matches!(
+                error,
+                GatewayError::BodyTooLarge
+                    | GatewayError::NonUtf8Body
+                    | GatewayError::Proxy(ProxyError::NoListener | ProxyError::ListenerBusy)
+            );
+            if let Some(key) = rate_limit_key.as_ref().filter(|_| refundable) {
+                self.server.rate_limiter.refund(key);
+            }
+            error
+        };
 
-        let rx = match self
-            .server
-            .new_request(RednetRpcMessage {
-                dest,
+        if route.websocket && is_websocket_upgrade(request) {
+            let ws = match rocket_ws::WebSocket::from_request(request).await {
+                Outcome::Success(ws) => ws,
+                _ => return Err(GatewayError::BadUpgrade),
+            };
+
+            let tunnel = self
+                .transport
+                .open_tunnel(backend.clone(), http_request)
+                .await
+                .map_err(|e| refund(e.into()))?;
+            access_log.computer_id = Some(tunnel.listener_id.clone());
+
+            return ws
+                .channel(move |stream| Box::pin(tunnel.run(stream)))
+                .respond_to(request)
+                .map_err(GatewayError::Response);
+        }
+
+        // Hyper answers `Expect: 100-continue` when the body is first read, so requests rejected
+        // above (unknown route, rate limited, cached) never make the client upload the body
+        let limit = ByteUnit::from(gateway_config.max_request_bytes);
+        let body = match data.open(limit).into_bytes().await {
+            Ok(body) if body.is_complete() => body.into_inner(),
+            Ok(_) => {
+                rocket::warn!("Rejecting request body over {}", limit);
+                return Err(refund(GatewayError::BodyTooLarge));
+            }
+            Err(e) => {
+                rocket::error!("Failed to read body from client: {}", e);
+                return Err(GatewayError::Internal);
+            }
+        };
+        // Binary bodies would be corrupted by forcing them into a string, so they travel as base64
+        if has_binary_body(request) {
+            http_request.body = BASE64_STANDARD.encode(body);
+            http_request.body_base64 = true;
+        } else {
+            http_request.body = match String::from_utf8(body) {
+                Ok(body) => body,
+                Err(_) => {
+                    rocket::error!("Non-UTF-8 body from client");
+                    return Err(refund(GatewayError::NonUtf8Body));
+                }
+            };
+        }
+        if gateway_config.verify_body_integrity {
+            http_request.body_sha256 = Some(sha256_hex(&http_request.body));
+        }
+
+        // Identical GETs on a caching route are answered without involving a listener, unless
+        // they carry credentials
+        let cache = route
+            .cache_ttl_secs
+            .filter(|_| {
+                http_request.method == Method::Get && cache::is_cacheable_request(&http_request)
+            })
+            .map(|ttl| {
+                // Routes that strip their prefix can forward the same path, and host-matched routes
+                // can see the same path under different hosts, so keep their entries apart
+                let key = format!(
+                    "{} {} {} {}",
+                    http_request.method,
+                    request_host(&http_request).unwrap_or_default(),
+                    route.prefix.display(),
+                    http_request.uri
+                );
+                (key, Duration::from_secs(ttl))
+            });
+        let cached = cache
+            .as_ref()
+            .and_then(|(key, _)| self.server.response_cache.get(key));
+        if let Some(resp) = cached {
+            return resp.respond_to(request).map_err(GatewayError::Response);
+        }
+
+        if let Some(mirror) = &route.mirror {
+            self.transport.mirror(mirror.clone(), http_request.clone());
+        }
+
+        let request_id = Uuid::new_v4();
+        let (listener_id, mut resp) = self
+            .transport
+            .send(RednetRpcMessage {
+                v: PROTOCOL_VERSION,
+                dest: backend.clone(),
                 request_id,
                 payload: http_request,
             })
             .await
-        {
-            Err(status) => return Outcome::Error(status),
-            Ok(rx) => rx,
-        };
+            .map_err(|e| refund(e.into()))?;
+        access_log.computer_id = Some(listener_id.clone());
 
-        let resp = match timeout(
-            Duration::from_secs(gateway_config.gateway_timeout as u64),
-            rx,
-        )
-        .await
-        {
-            Err(_) => return Outcome::Error(Status::GatewayTimeout),
-            Ok(Err(_)) => return Outcome::Error(Status::BadGateway),
-            Ok(Ok(msg)) => msg,
-        };
+        set_headers(&mut resp.headers, &route.response_headers_set);
+
+        // Event streams stay open for the computer's later events, and are never cached
+        let events = self.server.event_streams.remove(&request_id);
+        if let Some((_, frames)) = events {
+            let stream = EventStream::new(
+                Arc::clone(&self.server),
+                listener_id,
+                backend.clone(),
+                request_id,
+                frames,
+            );
+            return stream
+                .respond(request, resp)
+                .map_err(GatewayError::Response);
+        }
+
+        if let Some((key, ttl)) = cache {
+            self.server.response_cache.insert(key, &resp, ttl);
+        }
 
-        Outcome::Success(resp.respond_to(request).unwrap())
+        resp.respond_to(request).map_err(GatewayError::Response)
     }
 }
 
 impl Server {
+    fn new(config: GatewayConfig) -> Self {
+        Self {
+            rate_limiter: RateLimiter::new(config.rate_limit, config.rate_limit_burst),
+            balancer: Balancer::new(config.load_balance),
+            response_cache: ResponseCache::new(config.cache_max_entries),
+            config,
+            listeners: DashMap::new(),
+            in_flight_requests: DashMap::new(),
+            circuits: DashMap::new(),
+            health: HealthProbes::default(),
+            tunnels: DashMap::new(),
+            event_streams: DashMap::new(),
+            listener_connected: Notify::new(),
+            listener_load: DashMap::new(),
+            listener_protocols: DashMap::new(),
+            listener_capabilities: DashMap::new(),
+            listener_concurrency: DashMap::new(),
+            drained_listeners: DashSet::new(),
+            rednet_prefixes: Mutex::new(None),
+        }
+    }
+
+    /// Log the route table whenever a load of the rednet config differs from the last one
+    fn observe_rednet_config(&self, config: &RednetConfig) {
+        let prefixes = config.prefixes();
+        let previous = self
+            .rednet_prefixes
+            .lock()
+            .unwrap()
+            .replace(prefixes.clone());
+        if previous.as_ref() == Some(&prefixes) {
+            return;
+        }
+
+        let previous = previous.unwrap_or_default();
+        let added = prefixes
+            .iter()
+            .filter(|prefix| !previous.contains(prefix))
+            .collect::<Vec<_>>();
+        let removed = previous
+            .iter()
+            .filter(|prefix| !prefixes.contains(prefix))
+            .collect::<Vec<_>>();
+        tracing::info!(
+            routes = prefixes.len(),
+            ?added,
+            ?removed,
+            "Loaded rednet config"
+        );
+    }
+
     async fn new_request(
         self: &Arc<Self>,
         message: RednetRpcMessage<HttpRequest>,
-    ) -> Result<RednetRpcReceiver, Status> {
+    ) -> Result<RednetRpcReceiver, ProxyError> {
         let (tx, rx) = oneshot::channel();
 
-        // Get a random listener
-        let mut listeners = self.listeners.iter().map(|r| r.clone()).collect::<Vec<_>>();
-        if listeners.is_empty() {
-            rocket::error!("No listeners available for rednet request");
-            return Err(Status::BadGateway);
-        }
+        let (listener_id, listener) = self
+            .wait_for_listener(&message.dest, &message.payload)
+            .await?;
 
-        let num_listeners = listeners.len();
-        let listener = listeners
-            .get_mut(rand::rng().random_range(0..num_listeners))
-            .ok_or(Status::InternalServerError)
-            .inspect_err(|_| {
-                rocket::error!("No listeners available for rednet request (listener membership changed mid-request");
-            })?;
+        // Registered before sending, so a listener answering straight away finds the request waiting
+        self.in_flight_requests.insert(
+            message.request_id,
+            InFlightRequest {
+                listener_id: listener_id.clone(),
+                sender: tx,
+            },
+        );
+        self.add_load(&listener_id);
+
+        // Rather than wait behind a backlog the listener may never clear, reject once its queue is full
+        if let Err(e) = listener.try_send(ListenerMessage::Http(message.clone())) {
+            self.in_flight_requests.remove(&message.request_id);
+            self.remove_load(&listener_id);
+
+            if matches!(e, TrySendError::Full(_)) {
+                rocket::warn!("Queue for listener {} is full", listener_id);
+                return Err(ProxyError::ListenerBusy);
+            }
 
-        if let Err(_e) = listener.send(message.clone()).await {
             rocket::error!("Failed to send message to listener (pipe closed)");
-            return Err(Status::InternalServerError);
+            self.record_failure(&listener_id);
+            return Err(ProxyError::ListenerDisconnected);
         }
 
-        self.in_flight_requests.insert(message.request_id, tx);
-
         Ok(RednetRpcReceiver {
             server: Arc::clone(self),
+            listener_id,
             request_id: message.request_id,
             receiver: rx,
         })
     }
 
-    fn cancel_request(&self, request_id: &Uuid) {
-        self.in_flight_requests.remove(request_id);
+    /// Send a copy of a request to a shadow backend in the background, ignoring the outcome
+    fn mirror_request(self: &Arc<Self>, dest: RednetRpcDestination, request: HttpRequest) {
+        let server = Arc::clone(self);
+        tokio::spawn(async move {
+            let request_id = Uuid::new_v4();
+            let rx = match server
+                .new_request(RednetRpcMessage {
+                    v: PROTOCOL_VERSION,
+                    dest,
+                    request_id,
+                    payload: request,
+                })
+                .await
+            {
+                Err(e) => {
+                    rocket::warn!("Failed to mirror request: {:?}", e);
+                    return;
+                }
+                Ok(rx) => rx,
+            };
+
+            // Dropping the receiver cancels a slow mirror without counting against the listener's circuit
+            let gateway_timeout = Duration::from_secs(server.config.gateway_timeout as u64);
+            let _ = timeout(gateway_timeout, rx).await;
+            // Nobody reads a mirror's response, so don't keep an event stream it opened
+            sse::abandon(&server, &request_id);
+        });
+    }
+
+    /// Forget what was recorded about the connection `token` of listener `id`, once it closes
+    fn disconnect(&self, id: &str, token: Uuid) {
+        // If the computer already reconnected, the entry belongs to the new connection
+        if self
+            .listeners
+            .remove_if(id, |_, connection| connection.token == token)
+            .is_some()
+        {
+            self.listener_protocols.remove(id);
+            self.listener_capabilities.remove(id);
+            self.listener_concurrency.remove(id);
+            self.health.forget(id);
+            // A drain is for the connection it was made on, so a reconnect after maintenance is selected
+            self.drained_listeners.remove(id);
+            // Fail the requests it was answering now, rather than at the gateway timeout
+            self.in_flight_requests
+                .retain(|_, request| request.listener_id != id);
+        }
+    }
+
+    /// Select a listener, parking for up to `connect_wait_ms` for one to connect if none is available
+    async fn wait_for_listener(
+        &self,
+        dest: &RednetRpcDestination,
+        request: &HttpRequest,
+    ) -> Result<(ComputerId, ListenerSender), ProxyError> {
+        let deadline = Instant::now() + Duration::from_millis(self.config.connect_wait_ms);
+
+        loop {
+            // Register for the wakeup before checking, so a listener connecting in between isn't missed
+            let connected = self.listener_connected.notified();
+
+            let error = match self.select_listener(dest, dest.shard_key(request)) {
+                Ok(listener) => return Ok(listener),
+                Err(e) if self.config.connect_wait_ms == 0 => return Err(e),
+                Err(e) => e,
+            };
+
+            if timeout_at(deadline, connected).await.is_err() {
+                rocket::error!(
+                    "No listener available within {}ms",
+                    self.config.connect_wait_ms
+                );
+                return Err(error);
+            }
+        }
+    }
+
+    /// Choose a listener for `dest`, by `shard_key` if given and otherwise by the load balancer
+    fn select_listener(
+        &self,
+        dest: &RednetRpcDestination,
+        shard_key: Option<&str>,
+    ) -> Result<(ComputerId, ListenerSender), ProxyError> {
+        // Only consider listeners that serve the protocol, whose circuit isn't open and that pass probes
+        let eligible = self
+            .listeners
+            .iter()
+            .filter(|r| self.serves(r.key(), dest) && self.is_selectable(r.key()))
+            .map(|r| (r.key().clone(), r.value().sender.clone()))
+            .collect::<Vec<_>>();
+        // Of those, pass over listeners already serving as much as they can
+        let eligible_count = eligible.len();
+        let mut listeners = eligible
+            .into_iter()
+            .filter(|(id, _)| !self.is_saturated(id))
+            .collect::<Vec<_>>();
+        if listeners.is_empty() && eligible_count > 0 {
+            rocket::warn!(
+                "All {} eligible listeners are at their concurrency limit",
+                eligible_count
+            );
+            return Err(ProxyError::ListenerBusy);
+        }
+
+        let candidates = listeners
+            .iter()
+            .map(|(id, _)| (id.clone(), self.load(id)))
+            .collect::<Vec<_>>();
+
+        let chosen = match shard_key {
+            Some(key) => balance::pick_sharded(key, &candidates),
+            // Requests without a key have no affinity, so spread them like any other
+            None => self.balancer.pick(&candidates),
+        };
+        let Some(chosen) = chosen else {
+            match dest.protocol() {
+                Some(protocol) => rocket::error!(
+                    "No listeners available for rednet request with protocol {}",
+                    protocol
+                ),
+                None => rocket::error!("No listeners available for rednet request"),
+            }
+            return Err(ProxyError::NoListener);
+        };
+
+        let (listener_id, listener) = listeners.swap_remove(chosen);
+        if !self.claim_probe(&listener_id) {
+            rocket::warn!(
+                "Another request is already probing listener {}",
+                listener_id
+            );
+            return Err(ProxyError::ListenerBusy);
+        }
+
+        Ok((listener_id, listener))
+    }
+
+    /// Mark a request as the probe through a listener's half-open circuit, so no other request is let
+    /// through until it settles. Returns false if another request claimed the probe since the
+    /// listener was found selectable.
+    fn claim_probe(&self, listener_id: &str) -> bool {
+        if self.config.circuit_failure_threshold == 0 {
+            return true;
+        }
+        let Some(mut circuit) = self.circuits.get_mut(listener_id) else {
+            return true;
+        };
+        if !circuit.is_open(&self.config) {
+            return true;
+        }
+        if !circuit.is_selectable(&self.config) {
+            return false;
+        }
+
+        circuit.probe_started = Some(Instant::now());
+        true
+    }
+
+    fn serves(&self, listener_id: &str, dest: &RednetRpcDestination) -> bool {
+        let required = dest.required_capabilities();
+        if !required.is_empty() {
+            let capable = self
+                .listener_capabilities
+                .get(listener_id)
+                .is_some_and(|capabilities| required.iter().all(|c| capabilities.contains(c)));
+            if !capable {
+                return false;
+            }
+        }
+
+        let Some(protocol) = dest.protocol() else {
+            return true;
+        };
+
+        self.listener_protocols
+            .get(listener_id)
+            .is_none_or(|protocols| protocols.contains(protocol))
     }
+
+    fn load(&self, listener_id: &str) -> usize {
+        self.listener_load.get(listener_id).map_or(0, |load| *load)
+    }
+
+    fn add_load(&self, listener_id: &str) {
+        *self
+            .listener_load
+            .entry(listener_id.to_string())
+            .or_default() += 1;
+    }
+
+    fn remove_load(&self, listener_id: &str) {
+        let was_saturated = self.is_saturated(listener_id);
+        if let Some(mut load) = self.listener_load.get_mut(listener_id) {
+            *load = load.saturating_sub(1);
+        }
+        self.listener_load
+            .remove_if(listener_id, |_, load| *load == 0);
+
+        // Requests may be parked waiting for a listener with room
+        if was_saturated {
+            self.listener_connected.notify_waiters();
+        }
+    }
+
+    /// Requests and tunnels a listener may serve at once, or `None` if it's unlimited
+    fn concurrency_limit(&self, listener_id: &str) -> Option<usize> {
+        let configured = self.config.listener_max_concurrency;
+        let limit = match self.listener_concurrency.get(listener_id) {
+            // A listener may ask for fewer than the gateway allows, but never more
+            Some(advertised) if configured > 0 => (*advertised).min(configured),
+            Some(advertised) => *advertised,
+            None => configured,
+        };
+        (limit > 0).then_some(limit)
+    }
+
+    /// Whether a listener is serving as many requests and tunnels as its concurrency limit allows.
+    /// This is only checked when selecting, so a burst of requests may briefly overshoot it.
+    fn is_saturated(&self, listener_id: &str) -> bool {
+        self.concurrency_limit(listener_id)
+            .is_some_and(|limit| self.load(listener_id) >= limit)
+    }
+
+    /// Give up on an in-flight request the listener didn't answer within the gateway timeout,
+    /// counting it against the listener's circuit
+    fn time_out_request(&self, request_id: &Uuid, listener_id: &str) {
+        if self.cancel_request(request_id) {
+            self.record_failure(listener_id);
+        }
+    }
+
+    /// Drop an in-flight request, returning whether it was still awaiting a response
+    fn cancel_request(&self, request_id: &Uuid) -> bool {
+        self.in_flight_requests.remove(request_id).is_some()
+    }
+
+    /// Answer an in-flight request with `error` rather than waiting for the listener
+    fn fail_request(&self, request_id: &Uuid, error: ProxyError) {
+        if let Some((_, request)) = self.in_flight_requests.remove(request_id) {
+            let _ = request.sender.send(Err(error));
+        }
+    }
+
+    fn is_selectable(&self, listener_id: &str) -> bool {
+        !self.drained_listeners.contains(listener_id)
+            && self.health.is_healthy(listener_id, &self.config)
+            && self
+                .circuits
+                .get(listener_id)
+                .is_none_or(|circuit| circuit.is_selectable(&self.config))
+    }
+
+    fn record_success(&self, listener_id: &str) {
+        if let Some(mut connection) = self.listeners.get_mut(listener_id) {
+            connection.stats.requests_served += 1;
+        }
+        if self.circuits.remove(listener_id).is_some() {
+            rocket::info!("Circuit closed for listener {}", listener_id);
+        }
+    }
+
+    fn record_failure(&self, listener_id: &str) {
+        if let Some(mut connection) = self.listeners.get_mut(listener_id) {
+            connection.stats.errors += 1;
+        }
+        let mut circuit = self.circuits.entry(listener_id.to_string()).or_default();
+        circuit.record_failure(&self.config);
+        if circuit.failures == self.config.circuit_failure_threshold {
+            rocket::warn!("Circuit opened for listener {}", listener_id);
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct GatewayStatus {
+    connected_listeners: usize,
+}
+
+/// Report the gateway's state, polled by the controller to fill in the ComputerGateway status
+#[get("/status")]
+fn status(server: &State<Arc<Server>>) -> Json<GatewayStatus> {
+    Json(GatewayStatus {
+        connected_listeners: server.listeners.len(),
+    })
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    status: u16,
+    error: &'static str,
+    /// Why the request couldn't be proxied, if it got as far as a backend
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<ProxyError>,
+}
+
+/// Answer gateway errors with JSON, including the reason the handler recorded when proxying failed
+#[catch(default)]
+fn gateway_error(status: Status, request: &Request<'_>) -> (Status, Json<ErrorBody>) {
+    let reason = *request.local_cache(|| None::<ProxyError>);
+    (
+        status,
+        Json(ErrorBody {
+            status: status.code,
+            error: status.reason_lossy(),
+            reason,
+        }),
+    )
+}
+
+#[derive(Debug, Serialize)]
+struct ListenerInfo {
+    id: ComputerId,
+    drained: bool,
+    /// Health probes failed in a row, which past `health_probe_failure_threshold` exclude the listener
+    failed_health_probes: u32,
+    #[serde(flatten)]
+    stats: ListenerStats,
+}
+
+/// List the connected listeners with what each has done since connecting
+#[get("/listeners")]
+fn list_listeners(_admin: Admin, server: &State<Arc<Server>>) -> Json<Vec<ListenerInfo>> {
+    let mut listeners = server
+        .listeners
+        .iter()
+        .map(|r| ListenerInfo {
+            id: r.key().clone(),
+            drained: server.drained_listeners.contains(r.key()),
+            failed_health_probes: server.health.failures(r.key()),
+            stats: r.value().stats.clone(),
+        })
+        .collect::<Vec<_>>();
+    listeners.sort_by(|a, b| a.id.cmp(&b.id));
+
+    Json(listeners)
+}
+
+/// Stop selecting a connected listener for new requests and tunnels, e.g. for maintenance.
+/// Its connection stays open, so requests already sent to it still get their responses.
+#[post("/listeners/<id>/drain")]
+fn drain_listener(_admin: Admin, id: &str, server: &State<Arc<Server>>) -> Status {
+    if !server.listeners.contains_key(id) {
+        return Status::NotFound;
+    }
+
+    rocket::info!("Draining listener {}", id);
+    server.drained_listeners.insert(id.to_string());
+    Status::NoContent
 }
 
-#[get("/<id>")]
+/// Make a drained listener eligible for selection again
+#[post("/listeners/<id>/undrain")]
+fn undrain_listener(_admin: Admin, id: &str, server: &State<Arc<Server>>) -> Status {
+    if server.drained_listeners.remove(id).is_some() {
+        rocket::info!("Undraining listener {}", id);
+        // Requests may be parked waiting for an eligible listener
+        server.listener_connected.notify_waiters();
+    }
+    Status::NoContent
+}
+
+/// Connect a listener, optionally restricted to the comma-separated rednet `protocols` it serves
+/// and advertising comma-separated `capabilities` that `capable` destinations select on.
+/// A listener may also advertise the `max_concurrency` it can handle, lowering the gateway's limit.
+/// Messages to the listener are JSON unless it asks for `encoding=msgpack`, or negotiates a
+/// `Sec-WebSocket-Protocol` like `cc-rednet-msgpack.v1`, which fixes the encoding instead.
+#[get("/<id>?<protocols>&<capabilities>&<max_concurrency>&<encoding>")]
 async fn listen<'a>(
     ws: rocket_ws::WebSocket,
     id: &'a str,
+    protocols: Option<&str>,
+    capabilities: Option<&str>,
+    max_concurrency: Option<usize>,
+    encoding: Option<Encoding>,
+    offered: OfferedSubprotocols,
     server: &'a State<Arc<Server>>,
-) -> Result<rocket_ws::Stream!['a], Status> {
-    let (tx, mut rx) = mpsc::channel(1000);
-    server.listeners.insert(id.to_string(), tx);
+) -> Result<Negotiated<rocket_ws::Stream!['a]>, Status> {
+    // Settle the subprotocol before registering, so a rejected handshake leaves no trace
+    let (subprotocol, encoding) = match offered.negotiate()? {
+        Some((subprotocol, encoding)) => (Some(subprotocol), encoding),
+        None if server.config.require_listener_subprotocol => {
+            rocket::warn!("Rejecting listener {} that offered no subprotocol", id);
+            return Err(Status::BadRequest);
+        }
+        None => (None, encoding.unwrap_or_default()),
+    };
+
+    // Bound what a listener can make the gateway buffer, rather than tungstenite's 64 MiB default
+    let frame_limit = listener_frame_limit(&server.config);
+    let ws = ws.config(rocket_ws::Config {
+        max_message_size: Some(frame_limit),
+        max_frame_size: Some(frame_limit),
+        ..Default::default()
+    });
+
+    // Tokio's channel panics given no capacity, where futures' still fit one message per sender
+    let (tx, mut rx) = tokio::sync::mpsc::channel(server.config.listener_queue_depth.max(1));
+    let connection = ListenerConnection {
+        token: Uuid::new_v4(),
+        sender: tx,
+        stats: ListenerStats {
+            connected_unix_sec: unix_now(),
+            last_frame_unix_sec: None,
+            requests_served: 0,
+            errors: 0,
+        },
+    };
+    // Checked before recording anything too, so a refused duplicate leaves the connected listener's
+    // protocols, capabilities and concurrency alone
+    if server.config.reject_duplicate_listeners && server.listeners.contains_key(id) {
+        rocket::warn!("Rejecting duplicate connection for listener {}", id);
+        return Err(Status::Conflict);
+    }
+
+    if let Some(protocols) = protocols {
+        server
+            .listener_protocols
+            .insert(id.to_string(), split_list(protocols));
+    } else {
+        server.listener_protocols.remove(id);
+    }
+    if let Some(capabilities) = capabilities {
+        server
+            .listener_capabilities
+            .insert(id.to_string(), split_list(capabilities));
+    } else {
+        server.listener_capabilities.remove(id);
+    }
+    // 0 isn't a limit a listener can ask for, rather than meaning unlimited as it does in config
+    if let Some(max_concurrency) = max_concurrency.filter(|&limit| limit > 0) {
+        server
+            .listener_concurrency
+            .insert(id.to_string(), max_concurrency);
+    } else {
+        server.listener_concurrency.remove(id);
+    }
+
+    let token = connection.token;
+    // Selection only considers listeners in `listeners`, so register last, once everything it
+    // checks about this one is in place
+    match server.listeners.entry(id.to_string()) {
+        Entry::Occupied(_) if server.config.reject_duplicate_listeners => {
+            rocket::warn!("Rejecting duplicate connection for listener {}", id);
+            return Err(Status::Conflict);
+        }
+        Entry::Occupied(mut entry) => {
+            entry.insert(connection);
+        }
+        Entry::Vacant(entry) => {
+            entry.insert(connection);
+        }
+    }
+
+    server.listener_connected.notify_waiters();
 
-    Ok(ws.stream(move |mut ws| {
+    let stream = ws.stream(move |mut ws| {
         rocket::async_stream::try_stream! {
             scopeguard::defer!(
                 rocket::info!("Listener {} disconnected", id);
-                server.listeners.remove(id);
+                server.disconnect(id, token);
             );
 
+            let mut parse_failures = 0;
+            let idle_timeout = Duration::from_secs(server.config.listener_idle_timeout_secs);
+            let mut last_active = Instant::now();
             loop {
                 tokio::select! {
-                    res = rx.next() => {
+                    res = rx.recv() => {
                         let msg = match res {
                             None => break,
                             Some(msg) => msg,
                         };
 
-                        yield Message::Text(serde_json::to_string(&msg).unwrap());
+                        if is_listener_traffic(server, &msg) {
+                            last_active = Instant::now();
+                        }
+                        match encoding.encode(&msg) {
+                            Ok(frame) => yield frame,
+                            Err(e) => {
+                                // Only this message is lost, so keep the connection and fail its request now
+                                rocket::error!("Failed to encode message for listener {}: {:#}", id, e);
+                                if let ListenerMessage::Http(request) = &msg {
+                                    server.fail_request(&request.request_id, ProxyError::Internal);
+                                }
+                            }
+                        }
+                    },
+                    _ = idle_deadline(last_active, idle_timeout) => {
+                        rocket::info!("Disconnecting listener {} after {:?} idle", id, idle_timeout);
+                        break;
                     },
                     res = ws.next() =>  match res {
-                        Some(Ok(Message::Text(text))) => {
-                            match serde_json::from_str::<RednetRpcMessage<HttpResponse>>(&text) {
-                                Ok(msg) => {
-                                    handle_response(server, msg).await;
+                        Some(Ok(frame @ (Message::Text(_) | Message::Binary(_)))) => {
+                            last_active = Instant::now();
+                            if let Some(mut connection) = server.listeners.get_mut(id) {
+                                connection.stats.last_frame_unix_sec = Some(unix_now());
+                            }
+                            match codec::decode::<ListenerReply>(&frame) {
+                                Ok(reply) if reply.version() != PROTOCOL_VERSION => {
+                                    // Guessing at a format we don't speak could misroute responses, so hang up
+                                    rocket::error!(
+                                        "Listener {} speaks protocol version {}, expected {}",
+                                        id,
+                                        reply.version(),
+                                        PROTOCOL_VERSION
+                                    );
+                                    yield Message::Close(Some(CloseFrame {
+                                        code: CloseCode::Protocol,
+                                        reason: format!(
+                                            "unsupported protocol version {}, gateway speaks {}",
+                                            reply.version(),
+                                            PROTOCOL_VERSION
+                                        )
+                                        .into(),
+                                    }));
+                                    break;
+                                }
+                                Ok(reply) => {
+                                    parse_failures = 0;
+                                    match reply {
+                                        ListenerReply::Http(msg) => {
+                                            handle_response(server, id, msg).await;
+                                        }
+                                        ListenerReply::Tunnel(msg) => {
+                                            tunnel::handle_frame(server, msg);
+                                        }
+                                    }
                                 }
                                 Err(e) => {
-                                    rocket::error!("Failed to deserialize message: {}", e);
-                                    break;
+                                    // A single bad frame shouldn't drop every request in flight on this listener
+                                    parse_failures += 1;
+                                    rocket::error!(
+                                        "Failed to deserialize message from listener {} ({}/{}): {}",
+                                        id,
+                                        parse_failures,
+                                        server.config.listener_parse_failure_limit,
+                                        e
+                                    );
+                                    if parse_failures >= server.config.listener_parse_failure_limit {
+                                        break;
+                                    }
                                 }
                             }
                         },
@@ -391,45 +1993,1086 @@ async fn listen<'a>(
                 }
             }
         }
-    }))
+    });
+
+    Ok(Negotiated {
+        response: stream,
+        subprotocol,
+    })
+}
+
+/// Largest frame accepted from a listener: a `max_response_bytes` body, which JSON may escape to six
+/// times its size (`\u0000`), plus room for the status, headers and IDs around it
+fn listener_frame_limit(config: &GatewayConfig) -> usize {
+    config
+        .max_response_bytes
+        .saturating_mul(6)
+        .saturating_add(64 * 1024)
+}
+
+/// Whether sending `message` keeps a listener from going idle. The gateway's own health probes don't,
+/// or a listener that stopped answering would never be disconnected.
+fn is_listener_traffic(server: &Server, message: &ListenerMessage) -> bool {
+    match message {
+        ListenerMessage::Http(request) => !server.health.is_probe(&request.request_id),
+        ListenerMessage::Tunnel(_) | ListenerMessage::UnknownRequest(_) => true,
+    }
+}
+
+/// The rate limiter bucket for a client, shared across routes unless `route_prefix` is given. Clients
+/// with no known IP have none, rather than all sharing one that any of them could empty for the rest.
+fn rate_limit_key(client: Option<IpAddr>, route_prefix: Option<&Path>) -> Option<String> {
+    let client = client?;
+    Some(match route_prefix {
+        Some(prefix) => format!("{client} {}", prefix.display()),
+        None => client.to_string(),
+    })
 }
 
-async fn handle_response(server: &Server, message: RednetRpcMessage<HttpResponse>) {
+/// Resolve once a listener last active at `last_active` has been idle for `timeout`, or never if it's zero
+async fn idle_deadline(last_active: Instant, timeout: Duration) {
+    if timeout.is_zero() {
+        return std::future::pending().await;
+    }
+
+    tokio::time::sleep_until(last_active + timeout).await;
+}
+
+/// Parse a comma-separated query parameter, ignoring blank entries
+fn split_list(list: &str) -> HashSet<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Seconds since the Unix epoch
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Lowercase hex SHA-256 of a body as it travels over rednet
+fn sha256_hex(body: &str) -> String {
+    format!("{:x}", Sha256::digest(body))
+}
+
+async fn handle_response(
+    server: &Server,
+    listener_id: &str,
+    message: RednetRpcMessage<HttpResponse>,
+) {
+    // Probe responses only count towards the listener's health, not its stats or circuit
+    let Some(message) = server.health.take_response(message) else {
+        return;
+    };
+
     match server.in_flight_requests.remove(&message.request_id) {
-        Some((_, tx)) => {
-            let _ = tx.send(message.payload);
+        Some((_, request)) if message.payload.body.len() > server.config.max_response_bytes => {
+            rocket::error!(
+                "Dropping {} byte response from listener {}, over the {} byte limit",
+                message.payload.body.len(),
+                listener_id,
+                server.config.max_response_bytes
+            );
+            server.record_failure(listener_id);
+            let _ = request.sender.send(Err(ProxyError::InvalidResponse));
+        }
+        Some((_, request))
+            if server.config.verify_body_integrity
+                && message.payload.body_sha256.as_deref()
+                    != Some(sha256_hex(&message.payload.body).as_str()) =>
+        {
+            rocket::error!(
+                "Dropping response from listener {} whose body doesn't match its SHA-256",
+                listener_id
+            );
+            server.record_failure(listener_id);
+            let _ = request.sender.send(Err(ProxyError::InvalidResponse));
+        }
+        Some((_, request))
+            if message.payload.body_base64
+                && BASE64_STANDARD.decode(&message.payload.body).is_err() =>
+        {
+            rocket::error!(
+                "Dropping response from listener {} whose body isn't valid base64",
+                listener_id
+            );
+            server.record_failure(listener_id);
+            let _ = request.sender.send(Err(ProxyError::InvalidResponse));
+        }
+        Some((_, request)) => {
+            server.record_success(listener_id);
+
+            // Open the stream before the handler can see the response, so no early event is lost
+            let event_stream = sse::is_event_stream(&message.payload);
+            if event_stream {
+                sse::open(server, message.request_id);
+            }
+            if request.sender.send(Ok(message.payload)).is_err() && event_stream {
+                sse::abandon(server, &message.request_id);
+            }
         }
         None => {
             rocket::warn!(
                 "Received response for unknown request ID: {}",
                 message.request_id
             );
+            if server.config.nack_unknown_responses {
+                nack_unknown_response(server, listener_id, message.request_id);
+            }
         }
     }
 }
 
+/// Let a listener know nothing is waiting on its response, so it can stop retrying or log it
+fn nack_unknown_response(server: &Server, listener_id: &str, request_id: Uuid) {
+    let nack = ListenerMessage::UnknownRequest(UnknownRequestNack {
+        unknown_request: request_id,
+    });
+    if let Some(listener) = server
+        .listeners
+        .get(listener_id)
+        .map(|r| r.value().sender.clone())
+    {
+        let _ = listener.try_send(nack);
+    }
+}
+
 #[pin_project(PinnedDrop)]
 struct RednetRpcReceiver {
     server: Arc<Server>,
+    listener_id: ComputerId,
     request_id: Uuid,
     #[pin]
-    receiver: oneshot::Receiver<HttpResponse>,
+    receiver: oneshot::Receiver<Result<HttpResponse, ProxyError>>,
 }
 
 impl Future for RednetRpcReceiver {
-    type Output = Result<HttpResponse, Canceled>;
+    type Output = Result<HttpResponse, ProxyError>;
 
     fn poll(
         self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Self::Output> {
-        self.project().receiver.poll(cx)
+        // The sender is only dropped without answering when the listener goes away
+        self.project()
+            .receiver
+            .poll(cx)
+            .map(|res| res.unwrap_or(Err(ProxyError::ListenerDisconnected)))
     }
 }
 
 #[pinned_drop]
 impl PinnedDrop for RednetRpcReceiver {
     fn drop(self: Pin<&mut Self>) {
+        // Still in flight means the client went away first, which says nothing about the listener.
+        // Timeouts are counted against it by whoever was waiting, before dropping the receiver.
         self.server.cancel_request(&self.request_id);
+        self.server.remove_load(&self.listener_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use rocket::{figment::Figment, http::Header, local::asynchronous::Client};
+
+    use super::*;
+
+    /// The gateway config with every default, reading rednet routes from `/dev/null`
+    pub(crate) fn config() -> GatewayConfig {
+        serde_json::from_value(serde_json::json!({ "rednet": "/dev/null" })).unwrap()
+    }
+
+    /// Register a listener `id` directly, returning what the gateway sends it
+    pub(crate) fn connect(
+        server: &Server,
+        id: &str,
+    ) -> tokio::sync::mpsc::Receiver<ListenerMessage> {
+        let (tx, rx) = tokio::sync::mpsc::channel(server.config.listener_queue_depth);
+        server.listeners.insert(
+            id.to_string(),
+            ListenerConnection {
+                token: Uuid::new_v4(),
+                sender: tx,
+                stats: ListenerStats {
+                    connected_unix_sec: unix_now(),
+                    last_frame_unix_sec: None,
+                    requests_served: 0,
+                    errors: 0,
+                },
+            },
+        );
+        rx
+    }
+
+    /// A `GET /` request for `dest`, with an id of its own
+    pub(crate) fn message(dest: RednetRpcDestination) -> RednetRpcMessage<HttpRequest> {
+        RednetRpcMessage {
+            v: PROTOCOL_VERSION,
+            dest,
+            request_id: Uuid::new_v4(),
+            payload: request(&[]),
+        }
+    }
+
+    fn anycast() -> RednetRpcDestination {
+        RednetRpcDestination::Anycast {
+            protocol: "http".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn full_listener_queue_is_busy_and_leaves_nothing_in_flight() {
+        let server = Arc::new(Server::new(GatewayConfig {
+            listener_queue_depth: 1,
+            ..config()
+        }));
+        let _rx = connect(&server, "1");
+
+        let first = message(anycast());
+        let _receiver = server.new_request(first.clone()).await.unwrap();
+        let error = server.new_request(message(anycast())).await.err();
+
+        assert_eq!(error, Some(ProxyError::ListenerBusy));
+        assert_eq!(server.in_flight_requests.len(), 1);
+        assert!(server.in_flight_requests.contains_key(&first.request_id));
+        assert_eq!(server.load("1"), 1);
+    }
+
+    #[tokio::test]
+    async fn closed_listener_queue_leaves_nothing_in_flight() {
+        let server = Arc::new(Server::new(config()));
+        drop(connect(&server, "1"));
+
+        let error = server.new_request(message(anycast())).await.err();
+
+        assert_eq!(error, Some(ProxyError::ListenerDisconnected));
+        assert!(server.in_flight_requests.is_empty());
+        assert_eq!(server.load("1"), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn health_probes_are_not_listener_traffic() {
+        let server = Arc::new(Server::new(GatewayConfig {
+            health_probe_interval_secs: 1,
+            ..config()
+        }));
+        let mut rx = connect(&server, "1");
+        health::spawn(Arc::clone(&server));
+
+        let probe = rx.recv().await.unwrap();
+        assert!(!is_listener_traffic(&server, &probe));
+
+        let _receiver = server.new_request(message(anycast())).await.unwrap();
+        let request = rx.recv().await.unwrap();
+        assert!(is_listener_traffic(&server, &request));
+    }
+
+    fn response(request_id: Uuid) -> RednetRpcMessage<HttpResponse> {
+        RednetRpcMessage {
+            v: PROTOCOL_VERSION,
+            dest: anycast(),
+            request_id,
+            payload: HttpResponse {
+                status: Status::Ok,
+                headers: HashMap::new(),
+                body: "done".to_string(),
+                body_base64: false,
+                body_sha256: None,
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn drained_listener_is_skipped_but_answers_in_flight_requests() {
+        let server = Arc::new(Server::new(config()));
+        let _rx = connect(&server, "1");
+        let in_flight = message(anycast());
+        let receiver = server.new_request(in_flight.clone()).await.unwrap();
+
+        server.drained_listeners.insert("1".to_string());
+        let error = server.new_request(message(anycast())).await.err();
+        assert_eq!(error, Some(ProxyError::NoListener));
+
+        handle_response(&server, "1", response(in_flight.request_id)).await;
+        let answered = receiver.await.unwrap();
+        assert_eq!(answered.status, Status::Ok);
+        assert_eq!(answered.body, "done");
+    }
+
+    #[tokio::test]
+    async fn admin_drains_and_undrains_a_listener() {
+        let rednet = rednet_file("drain", "routes: []\n");
+        let client = Client::tracked(gateway(rocket::custom(figment(serde_json::json!({
+            "rednet": rednet,
+            "admin_token": "secret",
+        })))))
+        .await
+        .unwrap();
+        let server = client.rocket().state::<Arc<Server>>().unwrap();
+        let _rx = connect(server, "1");
+        let admin = |uri: &'static str| {
+            client
+                .post(uri)
+                .header(Header::new("Authorization", "Bearer secret"))
+        };
+
+        let drained = admin("/admin/listeners/1/drain").dispatch().await;
+        assert_eq!(drained.status(), Status::NoContent);
+        assert!(server.select_listener(&anycast(), None).is_err());
+
+        let undrained = admin("/admin/listeners/1/undrain").dispatch().await;
+        assert_eq!(undrained.status(), Status::NoContent);
+        assert!(server.select_listener(&anycast(), None).is_ok());
+
+        let unknown = admin("/admin/listeners/2/drain").dispatch().await;
+        assert_eq!(unknown.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn reconnecting_listener_is_no_longer_drained() {
+        let server = Server::new(config());
+        let _rx = connect(&server, "1");
+        server.drained_listeners.insert("1".to_string());
+
+        let token = server.listeners.get("1").unwrap().token;
+        server.disconnect("1", token);
+        let _rx = connect(&server, "1");
+
+        assert!(!server.drained_listeners.contains("1"));
+        assert!(server.select_listener(&anycast(), None).is_ok());
+    }
+
+    #[test]
+    fn stale_disconnect_leaves_a_reconnected_listener_drained() {
+        let server = Server::new(config());
+        let _old = connect(&server, "1");
+        let token = server.listeners.get("1").unwrap().token;
+        let _new = connect(&server, "1");
+        server.drained_listeners.insert("1".to_string());
+
+        // The first connection closing after the computer reconnected
+        server.disconnect("1", token);
+
+        assert!(server.listeners.contains_key("1"));
+        assert!(server.drained_listeners.contains("1"));
+    }
+
+    #[tokio::test]
+    async fn loopback_transport_echoes_requests_without_listeners() {
+        let rednet = rednet_file("loopback", TEST_ROUTE);
+        let client = Client::tracked(gateway(rocket::custom(figment(serde_json::json!({
+            "rednet": rednet,
+            "transport": "loopback",
+        })))))
+        .await
+        .unwrap();
+
+        let response = client.get("/gateway/test/hello").dispatch().await;
+
+        assert_eq!(response.status(), Status::Ok);
+        let echoed = response.into_json::<serde_json::Value>().await.unwrap();
+        assert_eq!(echoed["v"], PROTOCOL_VERSION);
+        assert_eq!(echoed["dest"]["anycast"]["protocol"], "kubelet");
+    }
+
+    #[tokio::test]
+    async fn tunnel_to_a_closed_listener_is_disconnected() {
+        let server = Arc::new(Server::new(config()));
+        drop(connect(&server, "1"));
+
+        let error = server.open_tunnel(anycast(), request(&[])).await.err();
+
+        assert_eq!(error, Some(ProxyError::ListenerDisconnected));
+        assert!(server.tunnels.is_empty());
+        assert_eq!(server.load("1"), 0);
+    }
+
+    const TEST_ROUTE: &str =
+        "routes:\n- prefix: /test\n  backend:\n    anycast:\n      protocol: kubelet\n";
+
+    #[tokio::test]
+    async fn base64_response_reaches_the_client_byte_for_byte() {
+        let rednet = rednet_file("binary", TEST_ROUTE);
+        let client = Client::tracked(gateway(rocket::custom(figment(serde_json::json!({
+            "rednet": rednet,
+        })))))
+        .await
+        .unwrap();
+        let server = Arc::clone(client.rocket().state::<Arc<Server>>().unwrap());
+        let mut rx = connect(&server, "1");
+        let image = vec![0x89, b'P', b'N', b'G', 0x00, 0xff, 0xfe];
+        let listener = tokio::spawn({
+            let image = image.clone();
+            async move {
+                let Some(ListenerMessage::Http(request)) = rx.recv().await else {
+                    panic!("expected a request");
+                };
+                let mut answer = response(request.request_id);
+                answer.payload.body = BASE64_STANDARD.encode(&image);
+                answer.payload.body_base64 = true;
+                handle_response(&server, "1", answer).await;
+            }
+        });
+
+        let response = client.get("/gateway/test/logo.png").dispatch().await;
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_bytes().await, Some(image));
+        listener.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn invalid_base64_response_is_rejected() {
+        let server = Arc::new(Server::new(config()));
+        let _rx = connect(&server, "1");
+        let request = message(anycast());
+        let receiver = server.new_request(request.clone()).await.unwrap();
+
+        let mut answer = response(request.request_id);
+        answer.payload.body = "not base64!".to_string();
+        answer.payload.body_base64 = true;
+        handle_response(&server, "1", answer).await;
+
+        assert_eq!(receiver.await.err(), Some(ProxyError::InvalidResponse));
+    }
+
+    #[tokio::test]
+    async fn request_bodies_are_limited_to_max_request_bytes() {
+        let rednet = rednet_file("body-limit", TEST_ROUTE);
+        let client = Client::tracked(gateway(rocket::custom(figment(serde_json::json!({
+            "rednet": rednet,
+            "transport": "loopback",
+            "max_request_bytes": 8,
+        })))))
+        .await
+        .unwrap();
+
+        let fits = client.post("/gateway/test").body("fits").dispatch().await;
+        assert_eq!(fits.status(), Status::Ok);
+
+        let too_large = client
+            .post("/gateway/test")
+            .body("9 bytes!!")
+            .dispatch()
+            .await;
+        assert_eq!(too_large.status(), Status::PayloadTooLarge);
+    }
+
+    #[tokio::test]
+    async fn clients_are_rate_limited_by_ip() {
+        let rednet = rednet_file("rate-limit", TEST_ROUTE);
+        let client = Client::tracked(gateway(rocket::custom(figment(serde_json::json!({
+            "rednet": rednet,
+            "transport": "loopback",
+            "rate_limit": 1,
+        })))))
+        .await
+        .unwrap();
+        let from = |ip: [u8; 4]| {
+            client
+                .get("/gateway/test")
+                .remote(SocketAddr::from((ip, 8000)))
+        };
+
+        assert_eq!(from([10, 0, 0, 1]).dispatch().await.status(), Status::Ok);
+        assert_eq!(
+            from([10, 0, 0, 1]).dispatch().await.status(),
+            Status::TooManyRequests
+        );
+        assert_eq!(from([10, 0, 0, 2]).dispatch().await.status(), Status::Ok);
+    }
+
+    #[test]
+    fn only_clients_with_an_ip_get_a_rate_limit_key() {
+        let ip = Some(IpAddr::from([10, 0, 0, 1]));
+        let prefix = Path::new("/api");
+
+        assert_eq!(rate_limit_key(ip, None).as_deref(), Some("10.0.0.1"));
+        assert_eq!(
+            rate_limit_key(ip, Some(prefix)).as_deref(),
+            Some("10.0.0.1 /api")
+        );
+        assert_eq!(rate_limit_key(None, None), None);
+        assert_eq!(rate_limit_key(None, Some(prefix)), None);
+    }
+
+    #[tokio::test]
+    async fn cors_preflights_are_checked_against_the_config() {
+        let rednet = rednet_file("cors", TEST_ROUTE);
+        let client = Client::tracked(gateway(rocket::custom(figment(serde_json::json!({
+            "rednet": rednet,
+            "transport": "loopback",
+            "cors": {
+                "allowed_origins": ["https://example.com"],
+                "allowed_methods": ["GET", "PUT"],
+                "allowed_headers": ["X-Api-Key"],
+            },
+        })))))
+        .await
+        .unwrap();
+        let preflight = |method: &'static str, headers: &'static str| {
+            client
+                .options("/gateway/test")
+                .header(Header::new("Origin", "https://example.com"))
+                .header(Header::new("Access-Control-Request-Method", method))
+                .header(Header::new("Access-Control-Request-Headers", headers))
+        };
+
+        let allowed = preflight("PUT", "x-api-key, Content-Type").dispatch().await;
+        assert_eq!(allowed.status(), Status::NoContent);
+        assert_eq!(
+            allowed.headers().get_one("Access-Control-Allow-Origin"),
+            Some("https://example.com")
+        );
+        assert_eq!(
+            allowed.headers().get_one("Access-Control-Allow-Methods"),
+            Some("GET, PUT")
+        );
+
+        let method = preflight("DELETE", "").dispatch().await;
+        assert_eq!(method.status(), Status::Forbidden);
+        let header = preflight("GET", "X-Api-Key, X-Secret").dispatch().await;
+        assert_eq!(header.status(), Status::Forbidden);
+
+        let simple = client
+            .get("/gateway/test")
+            .header(Header::new("Origin", "https://example.com"))
+            .dispatch()
+            .await;
+        assert_eq!(simple.status(), Status::Ok);
+        assert_eq!(
+            simple.headers().get_one("Access-Control-Allow-Origin"),
+            Some("https://example.com")
+        );
+    }
+
+    /// A server whose circuits open on the first failure and half-open 10s later
+    fn circuit_server() -> Arc<Server> {
+        Arc::new(Server::new(GatewayConfig {
+            circuit_failure_threshold: 1,
+            circuit_cooldown: 10,
+            gateway_timeout: 30,
+            ..config()
+        }))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn half_open_circuit_lets_a_single_probe_through() {
+        let server = circuit_server();
+        let _rx = connect(&server, "1");
+        server.record_failure("1");
+        assert!(!server.is_selectable("1"));
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        let probe = message(anycast());
+        let _probing = server.new_request(probe.clone()).await.unwrap();
+
+        // Nothing else goes through until the probe settles
+        let error = server.new_request(message(anycast())).await.err();
+        assert_eq!(error, Some(ProxyError::NoListener));
+
+        handle_response(&server, "1", response(probe.request_id)).await;
+        assert!(server.circuits.get("1").is_none());
+        assert!(server.new_request(message(anycast())).await.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_probe_reopens_the_circuit() {
+        let server = circuit_server();
+        let _rx = connect(&server, "1");
+        server.record_failure("1");
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        let probe = message(anycast());
+        let _probing = server.new_request(probe.clone()).await.unwrap();
+        server.time_out_request(&probe.request_id, "1");
+
+        assert!(!server.is_selectable("1"));
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert!(server.is_selectable("1"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelled_requests_are_not_failures() {
+        let server = circuit_server();
+        let _rx = connect(&server, "1");
+
+        // The client going away while the listener works on a request
+        drop(server.new_request(message(anycast())).await.unwrap());
+
+        assert!(server.circuits.get("1").is_none());
+        assert!(server.in_flight_requests.is_empty());
+        assert_eq!(server.load("1"), 0);
+
+        // Nor is a cancelled probe, which only holds the half-open circuit until the gateway timeout
+        server.record_failure("1");
+        tokio::time::advance(Duration::from_secs(10)).await;
+        drop(server.new_request(message(anycast())).await.unwrap());
+        assert_eq!(server.circuits.get("1").unwrap().failures, 1);
+        assert!(!server.is_selectable("1"));
+
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(server.is_selectable("1"));
+    }
+
+    fn rednet_config(yaml: &str) -> anyhow::Result<RednetConfig> {
+        serde_yaml_ng::from_str::<RednetConfig>(yaml)?.validate()
+    }
+
+    fn route_with_prefix(prefix: &str) -> anyhow::Result<HttpOverRednetRoute> {
+        let yaml = format!(
+            "routes:\n- prefix: {prefix:?}\n  backend:\n    anycast:\n      protocol: api\n"
+        );
+        Ok(rednet_config(&yaml)?.routes.remove(0))
+    }
+
+    fn request_for(path: &str) -> HttpRequest {
+        HttpRequest {
+            uri: origin(path),
+            ..request(&[])
+        }
+    }
+
+    #[test]
+    fn prefixes_match_whole_path_segments() {
+        let route = route_with_prefix("/api").unwrap();
+        let cases = [
+            ("/api", true),
+            ("/api/", true),
+            ("/api/x", true),
+            ("/api/x/y?z=1", true),
+            ("/apix", false),
+            ("/apiv2/x", false),
+            ("/ap", false),
+            ("/", false),
+            ("/other/api", false),
+        ];
+        for (path, matches) in cases {
+            assert_eq!(route.check(&request_for(path)), matches, "{path}");
+        }
+    }
+
+    #[test]
+    fn root_prefix_matches_everything() {
+        let route = route_with_prefix("/").unwrap();
+        for path in ["/", "/api", "/anything/else"] {
+            assert!(route.check(&request_for(path)), "{path}");
+        }
+    }
+
+    #[test]
+    fn prefixes_are_normalized_at_load() {
+        let cases = [
+            ("/api/", "/api"),
+            ("//api//v1/", "/api/v1"),
+            ("/api/./v1", "/api/v1"),
+            ("/", "/"),
+        ];
+        for (prefix, normalized) in cases {
+            let route = route_with_prefix(prefix).unwrap();
+            assert_eq!(route.prefix, Path::new(normalized), "{prefix}");
+        }
+
+        let route = route_with_prefix("/api/v1/").unwrap();
+        assert!(route.check(&request_for("/api/v1/x")));
+        assert!(!route.check(&request_for("/api/v10")));
+    }
+
+    #[test]
+    fn relative_or_escaping_prefixes_fail_to_load() {
+        for prefix in ["api", "api/v1", "", "/api/../admin"] {
+            assert!(route_with_prefix(prefix).is_err(), "{prefix:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_response_is_a_bad_gateway() {
+        let rednet = rednet_file("oversized", TEST_ROUTE);
+        let client = Client::tracked(gateway(rocket::custom(figment(serde_json::json!({
+            "rednet": rednet,
+            "max_response_bytes": 16,
+        })))))
+        .await
+        .unwrap();
+        let server = Arc::clone(client.rocket().state::<Arc<Server>>().unwrap());
+        let mut rx = connect(&server, "1");
+        let listener = tokio::spawn({
+            let server = Arc::clone(&server);
+            async move {
+                for body in ["x".repeat(17), "x".repeat(16)] {
+                    let Some(ListenerMessage::Http(request)) = rx.recv().await else {
+                        panic!("expected a request");
+                    };
+                    let mut answer = response(request.request_id);
+                    answer.payload.body = body;
+                    handle_response(&server, "1", answer).await;
+                }
+            }
+        });
+
+        let over = client.get("/gateway/test").dispatch().await;
+        assert_eq!(over.status(), Status::BadGateway);
+        let at_limit = client.get("/gateway/test").dispatch().await;
+        assert_eq!(at_limit.status(), Status::Ok);
+        listener.await.unwrap();
+
+        assert_eq!(server.listeners.get("1").unwrap().stats.errors, 1);
+    }
+
+    #[test]
+    fn frame_limit_fits_a_fully_escaped_response_at_the_body_limit() {
+        let config = GatewayConfig {
+            max_response_bytes: 1024,
+            ..config()
+        };
+        let mut answer = response(Uuid::new_v4());
+        answer.payload.body = "\u{0}".repeat(config.max_response_bytes);
+        answer.payload.headers = HashMap::from([(
+            "Content-Type".to_string(),
+            vec!["application/octet-stream".to_string()],
+        )]);
+        answer.payload.body_sha256 = Some(sha256_hex(&answer.payload.body));
+
+        let frame = serde_json::to_string(&answer).unwrap();
+        assert!(frame.len() <= listener_frame_limit(&config));
+    }
+
+    #[test]
+    fn frame_limit_saturates_instead_of_overflowing() {
+        let config = GatewayConfig {
+            max_response_bytes: usize::MAX,
+            ..config()
+        };
+
+        assert_eq!(listener_frame_limit(&config), usize::MAX);
+    }
+
+    fn weighted_route(weights: &[(&str, u32)]) -> HttpOverRednetRoute {
+        let mut yaml = "routes:\n- prefix: /\n  backends:\n".to_string();
+        for (protocol, weight) in weights {
+            yaml.push_str(&format!(
+                "  - backend:\n      anycast:\n        protocol: {protocol}\n    weight: {weight}\n"
+            ));
+        }
+        rednet_config(&yaml).unwrap().routes.remove(0)
+    }
+
+    #[test]
+    fn weighted_backends_split_requests_by_weight() {
+        let route = weighted_route(&[("stable", 90), ("canary", 10)]);
+        let picks = 20_000;
+
+        let canary = (0..picks)
+            .filter(|_| route.pick_backend().protocol() == Some("canary"))
+            .count();
+
+        // One in ten, with a tolerance of over six standard deviations (about 0.2%)
+        let share = canary as f64 / picks as f64;
+        assert!((0.085..=0.115).contains(&share), "canary share {share}");
+    }
+
+    #[test]
+    fn single_backend_shorthand_always_wins() {
+        let route = route_with_prefix("/").unwrap();
+
+        for _ in 0..100 {
+            assert_eq!(route.pick_backend().protocol(), Some("api"));
+        }
+    }
+
+    /// Answer the next health probe sent to listener `id` with `status`
+    async fn answer_probe(
+        server: &Server,
+        id: &str,
+        rx: &mut tokio::sync::mpsc::Receiver<ListenerMessage>,
+        status: Status,
+    ) {
+        let Some(ListenerMessage::Http(probe)) = rx.recv().await else {
+            panic!("expected a probe");
+        };
+        assert!(server.health.is_probe(&probe.request_id));
+        assert_eq!(
+            probe.payload.uri.path().as_str(),
+            server.config.health_probe_path
+        );
+
+        let mut answer = response(probe.request_id);
+        answer.payload.status = status;
+        handle_response(server, id, answer).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn listeners_failing_health_probes_are_not_selected() {
+        let server = Arc::new(Server::new(GatewayConfig {
+            health_probe_interval_secs: 1,
+            health_probe_failure_threshold: 2,
+            ..config()
+        }));
+        let mut failing = connect(&server, "failing");
+        let mut passing = connect(&server, "passing");
+        health::spawn(Arc::clone(&server));
+
+        for round in 0..2 {
+            // Below the threshold, a failing listener is still selected
+            assert!(server.is_selectable("failing"), "round {round}");
+            answer_probe(
+                &server,
+                "failing",
+                &mut failing,
+                Status::InternalServerError,
+            )
+            .await;
+            answer_probe(&server, "passing", &mut passing, Status::Ok).await;
+            // Let the probes record their results, well before the next round
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        assert_eq!(server.health.failures("failing"), 2);
+        assert!(!server.is_selectable("failing"));
+        for _ in 0..20 {
+            let (chosen, _) = server.select_listener(&anycast(), None).unwrap();
+            assert_eq!(chosen, "passing");
+        }
+        // Probe responses aren't counted as requests served
+        assert_eq!(
+            server
+                .listeners
+                .get("passing")
+                .unwrap()
+                .stats
+                .requests_served,
+            0
+        );
+
+        // A single passing probe makes it eligible again
+        answer_probe(&server, "failing", &mut failing, Status::Ok).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(server.is_selectable("failing"));
+    }
+
+    #[tokio::test]
+    async fn saturated_listener_is_skipped_for_an_idle_one() {
+        let server = Arc::new(Server::new(GatewayConfig {
+            listener_max_concurrency: 1,
+            ..config()
+        }));
+        let _one = connect(&server, "1");
+        let _two = connect(&server, "2");
+
+        let first = server.new_request(message(anycast())).await.unwrap();
+        let busy = first.listener_id.clone();
+        let idle = if busy == "1" { "2" } else { "1" };
+        for _ in 0..20 {
+            let (chosen, _) = server.select_listener(&anycast(), None).unwrap();
+            assert_eq!(chosen, idle);
+        }
+
+        // With both at their limit there's nowhere left to send a request
+        let second = server.new_request(message(anycast())).await.unwrap();
+        assert_eq!(second.listener_id, idle);
+        let error = server.new_request(message(anycast())).await.err();
+        assert_eq!(error, Some(ProxyError::ListenerBusy));
+
+        // Finishing a request frees its listener
+        drop(first);
+        let (chosen, _) = server.select_listener(&anycast(), None).unwrap();
+        assert_eq!(chosen, busy);
+    }
+
+    #[tokio::test]
+    async fn advertised_concurrency_lowers_the_configured_limit() {
+        let server = Arc::new(Server::new(GatewayConfig {
+            listener_max_concurrency: 5,
+            ..config()
+        }));
+        let _rx = connect(&server, "1");
+        server.listener_concurrency.insert("1".to_string(), 2);
+
+        let _first = server.new_request(message(anycast())).await.unwrap();
+        let _second = server.new_request(message(anycast())).await.unwrap();
+        let error = server.new_request(message(anycast())).await.err();
+
+        assert_eq!(error, Some(ProxyError::ListenerBusy));
+        assert_eq!(server.concurrency_limit("1"), Some(2));
+    }
+
+    /// Rocket's own config, plus `values` for the gateway
+    fn figment(values: serde_json::Value) -> Figment {
+        let mut figment = rocket::Config::figment();
+        for (key, value) in values.as_object().unwrap() {
+            figment = figment.merge((key.as_str(), value.clone()));
+        }
+        figment
+    }
+
+    /// Write `routes` as a rednet config to a file of its own, returning its path
+    fn rednet_file(name: &str, routes: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "rednet-{name}-{}-{}.yaml",
+            std::process::id(),
+            Uuid::new_v4()
+        ));
+        std::fs::write(&path, routes).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn valid_config_ignites() {
+        let rednet = rednet_file("valid", "routes: []\n");
+        let rocket = gateway(rocket::custom(figment(serde_json::json!({
+            "rednet": rednet,
+            "gateway_timeout": 10,
+        }))));
+
+        assert!(rocket.ignite().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn invalid_gateway_config_fails_ignite() {
+        let rednet = rednet_file("invalid", "routes: []\n");
+        let invalid = [
+            serde_json::json!({ "rednet": rednet, "gateway_timeout": "soon" }),
+            // `rednet` is required
+            serde_json::json!({ "gateway_timeout": 10 }),
+        ];
+        for values in invalid {
+            let rocket = gateway(rocket::custom(figment(values.clone())));
+            let error = rocket.ignite().await.expect_err(&values.to_string());
+            assert!(
+                matches!(error.kind(), rocket::error::ErrorKind::FailedFairings(_)),
+                "{values}"
+            );
+        }
+    }
+
+    fn origin(uri: &str) -> Origin<'static> {
+        Origin::parse_owned(uri.to_string()).unwrap()
+    }
+
+    fn request(headers: &[(&str, &str)]) -> HttpRequest {
+        HttpRequest {
+            method: Method::Get,
+            uri: origin("/"),
+            headers: headers
+                .iter()
+                .map(|&(name, value)| (name.to_string(), vec![value.to_string()]))
+                .collect(),
+            body: String::new(),
+            body_base64: false,
+            body_sha256: None,
+        }
+    }
+
+    #[test]
+    fn strips_the_gateway_mount_point() {
+        let cases = [
+            ("/gateway", "/"),
+            ("/gateway/", "/"),
+            ("/gateway/api", "/api"),
+            ("/gateway/api/", "/api/"),
+            ("/gateway//api///x", "/api/x"),
+            ("/gateway/api//", "/api/"),
+        ];
+        for (uri, expected) in cases {
+            assert_eq!(
+                strip_gateway_prefix(&origin(uri)),
+                Some(origin(expected)),
+                "{uri}"
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_the_query_as_sent() {
+        let stripped = strip_gateway_prefix(&origin("/gateway//api?b=%2F&a=&b=1")).unwrap();
+
+        assert_eq!(stripped.path().as_str(), "/api");
+        assert_eq!(stripped.query().unwrap().as_str(), "b=%2F&a=&b=1");
+    }
+
+    #[test]
+    fn normalizes_the_request_host() {
+        assert_eq!(
+            request_host(&request(&[("host", "Shop.Example.com:8443")])),
+            Some("shop.example.com".to_string())
+        );
+        assert_eq!(request_host(&request(&[])), None);
+    }
+
+    #[test]
+    fn matches_exact_hosts_ignoring_case_and_port() {
+        let req = request(&[("Host", "Shop.Example.com:8443")]);
+
+        assert!(host_matches("shop.example.com", &req));
+        assert!(host_matches("SHOP.example.COM", &req));
+        assert!(!host_matches("example.com", &req));
+        assert!(!host_matches("shop.example.com", &request(&[])));
+    }
+
+    #[test]
+    fn wildcards_match_any_subdomain_but_not_the_domain() {
+        assert!(host_matches(
+            "*.example.com",
+            &request(&[("Host", "shop.example.com")])
+        ));
+        assert!(host_matches(
+            "*.example.com",
+            &request(&[("Host", "a.b.example.com")])
+        ));
+        assert!(!host_matches(
+            "*.example.com",
+            &request(&[("Host", "example.com")])
+        ));
+        assert!(!host_matches(
+            "*.example.com",
+            &request(&[("Host", "badexample.com")])
+        ));
+    }
+
+    const VALID_TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn validates_traceparent_shape() {
+        assert!(is_valid_traceparent(VALID_TRACEPARENT));
+
+        let invalid = [
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+        ];
+        for value in invalid {
+            assert!(!is_valid_traceparent(value), "{value}");
+        }
+    }
+
+    #[test]
+    fn keeps_a_valid_traceparent() {
+        let mut headers = request(&[("Traceparent", VALID_TRACEPARENT)]).headers;
+
+        assert_eq!(ensure_traceparent(&mut headers), VALID_TRACEPARENT);
+        assert_eq!(headers["Traceparent"], [VALID_TRACEPARENT]);
+    }
+
+    #[test]
+    fn replaces_a_malformed_traceparent() {
+        let mut headers = request(&[("Traceparent", "garbage")]).headers;
+
+        let traceparent = ensure_traceparent(&mut headers);
+        assert!(is_valid_traceparent(&traceparent));
+        assert!(traceparent.ends_with("-01"));
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers[TRACEPARENT], [traceparent]);
     }
 }
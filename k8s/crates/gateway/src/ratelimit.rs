@@ -0,0 +1,165 @@
+// Token-bucket rate limiting of proxied requests
+
+use std::{sync::Arc, time::Duration};
+
+use dashmap::DashMap;
+use tokio::time::{Instant, MissedTickBehavior, interval};
+
+use super::Server;
+
+/// How often buckets that have refilled, and so behave as if they'd never been used, are dropped
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    /// Tokens added per second, 0 disables limiting
+    rate: f64,
+    /// Maximum tokens a bucket can hold, i.e. the largest burst allowed
+    burst: f64,
+    buckets: DashMap<String, Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(rate: u32, burst: u32) -> Self {
+        Self {
+            rate: rate as f64,
+            burst: burst.max(rate).max(1) as f64,
+            buckets: DashMap::new(),
+        }
+    }
+
+    /// Take a token from the bucket for `key`, or return how long until one becomes available
+    pub(crate) fn check(&self, key: String) -> Result<(), Duration> {
+        if self.rate == 0.0 {
+            return Ok(());
+        }
+
+        let mut bucket = self.buckets.entry(key).or_insert_with(|| Bucket {
+            tokens: self.burst,
+            updated: Instant::now(),
+        });
+
+        if self.refill(&mut bucket) >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    /// Give back the token a request took from the bucket for `key`, for a request that failed
+    /// before a listener ever saw it
+    pub(crate) fn refund(&self, key: &str) {
+        if let Some(mut bucket) = self.buckets.get_mut(key) {
+            bucket.tokens = (bucket.tokens + 1.0).min(self.burst);
+        }
+    }
+
+    /// Drop the buckets that have refilled
+    fn sweep(&self) {
+        self.buckets
+            .retain(|_, bucket| self.refill(bucket) < self.burst);
+    }
+
+    fn refill(&self, bucket: &mut Bucket) -> f64 {
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        bucket.tokens
+    }
+}
+
+/// Sweep the rate limiter's buckets every [`SWEEP_INTERVAL`], unless rate limiting is off
+pub(crate) fn spawn(server: Arc<Server>) {
+    if server.rate_limiter.rate == 0.0 {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut ticks = interval(SWEEP_INTERVAL);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            server.rate_limiter.sweep();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn allows_a_burst_then_says_when_to_retry() {
+        let limiter = RateLimiter::new(2, 3);
+
+        for _ in 0..3 {
+            assert_eq!(limiter.check("client".to_string()), Ok(()));
+        }
+        assert_eq!(
+            limiter.check("client".to_string()),
+            Err(Duration::from_millis(500))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refills_at_the_rate() {
+        let limiter = RateLimiter::new(2, 2);
+        for _ in 0..2 {
+            limiter.check("client".to_string()).unwrap();
+        }
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert_eq!(limiter.check("client".to_string()), Ok(()));
+        assert!(limiter.check("client".to_string()).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limits_each_key_separately() {
+        let limiter = RateLimiter::new(1, 1);
+
+        assert_eq!(limiter.check("a".to_string()), Ok(()));
+        assert!(limiter.check("a".to_string()).is_err());
+        assert_eq!(limiter.check("b".to_string()), Ok(()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn zero_rate_disables_limiting() {
+        let limiter = RateLimiter::new(0, 0);
+
+        for _ in 0..100 {
+            assert_eq!(limiter.check("client".to_string()), Ok(()));
+        }
+        assert!(limiter.buckets.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refund_returns_the_token_up_to_the_burst() {
+        let limiter = RateLimiter::new(1, 1);
+        limiter.check("client".to_string()).unwrap();
+
+        limiter.refund("client");
+        limiter.refund("client");
+        assert_eq!(limiter.check("client".to_string()), Ok(()));
+        assert!(limiter.check("client".to_string()).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sweep_drops_only_refilled_buckets() {
+        let limiter = RateLimiter::new(1, 2);
+        limiter.check("idle".to_string()).unwrap();
+        tokio::time::advance(Duration::from_secs(1)).await;
+        limiter.check("busy".to_string()).unwrap();
+
+        limiter.sweep();
+        assert!(!limiter.buckets.contains_key("idle"));
+        assert!(limiter.buckets.contains_key("busy"));
+    }
+}
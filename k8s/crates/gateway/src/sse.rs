@@ -0,0 +1,149 @@
+// Streaming Server-Sent Events from a computer to the client
+
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use rocket::{
+    Request, Response,
+    futures::{Stream, StreamExt, channel::mpsc},
+    response::{Responder, stream::ByteStream},
+};
+use uuid::Uuid;
+
+use super::{
+    ComputerId, HttpResponse, ListenerMessage, PROTOCOL_VERSION, RednetRpcDestination,
+    RednetRpcMessage, Server, tunnel::TunnelFrame,
+};
+
+/// Event frames buffered per stream before the listener loop applies backpressure
+pub(crate) const EVENT_BUFFER: usize = 64;
+
+/// Whether a response opens an event stream, whose body is only the first of its events
+pub(crate) fn is_event_stream(response: &HttpResponse) -> bool {
+    response
+        .headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Content-Type"))
+        .flat_map(|(_, values)| values)
+        .any(|value| value.trim_start().starts_with("text/event-stream"))
+}
+
+/// Events a computer sends after the initial response, as tunnel frames carrying its request ID.
+/// `Text` and `Binary` frames are passed to the client as they arrive, until the computer sends
+/// `Close` or the client disconnects.
+pub(crate) struct EventStream {
+    server: Arc<Server>,
+    listener_id: ComputerId,
+    dest: RednetRpcDestination,
+    request_id: Uuid,
+    frames: mpsc::Receiver<TunnelFrame>,
+    /// Whether the computer ended the stream, so it needn't be told the client went away
+    closed: bool,
+}
+
+impl EventStream {
+    pub(crate) fn new(
+        server: Arc<Server>,
+        listener_id: ComputerId,
+        dest: RednetRpcDestination,
+        request_id: Uuid,
+        frames: mpsc::Receiver<TunnelFrame>,
+    ) -> Self {
+        Self {
+            server,
+            listener_id,
+            dest,
+            request_id,
+            frames,
+            closed: false,
+        }
+    }
+
+    /// Respond with the initial response's status and headers, then its body and each later event
+    pub(crate) fn respond<'r>(
+        self,
+        request: &'r Request<'_>,
+        initial: HttpResponse,
+    ) -> rocket::response::Result<'r> {
+        let first = rocket::futures::stream::iter(
+            Some(initial.body_bytes()).filter(|body| !body.is_empty()),
+        );
+        let mut response: Response<'r> = ByteStream(first.chain(self)).respond_to(request)?;
+
+        response.set_status(initial.status);
+        for (name, values) in initial.headers {
+            // The body is streamed, so any length the computer sent doesn't apply
+            if name.eq_ignore_ascii_case("Content-Length") {
+                continue;
+            }
+            response.remove_header(&name);
+            for value in values {
+                response.adjoin_raw_header(name.clone(), value);
+            }
+        }
+
+        Ok(response)
+    }
+}
+
+impl Stream for EventStream {
+    type Item = Vec<u8>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            return match self.frames.poll_next_unpin(cx) {
+                Poll::Pending => Poll::Pending,
+                Poll::Ready(Some(TunnelFrame::Text(text))) => Poll::Ready(Some(text.into_bytes())),
+                Poll::Ready(Some(TunnelFrame::Binary(data))) => Poll::Ready(Some(data)),
+                Poll::Ready(Some(TunnelFrame::Open(_))) => continue,
+                Poll::Ready(Some(TunnelFrame::Close)) => {
+                    self.closed = true;
+                    Poll::Ready(None)
+                }
+                // The gateway dropped the stream for falling behind, and the computer is told on drop
+                Poll::Ready(None) => Poll::Ready(None),
+            };
+        }
+    }
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        self.server.tunnels.remove(&self.request_id);
+        if self.closed {
+            return;
+        }
+
+        // The client went away first, so let the computer stop producing events
+        let close = ListenerMessage::Tunnel(RednetRpcMessage {
+            v: PROTOCOL_VERSION,
+            dest: self.dest.clone(),
+            request_id: self.request_id,
+            payload: TunnelFrame::Close,
+        });
+        if let Some(listener) = self
+            .server
+            .listeners
+            .get(&self.listener_id)
+            .map(|r| r.value().sender.clone())
+        {
+            let _ = listener.try_send(close);
+        }
+    }
+}
+
+/// Set up the channel that later events for `request_id` are routed through, for the handler to take
+pub(crate) fn open(server: &Server, request_id: Uuid) {
+    let (tx, rx) = mpsc::channel(EVENT_BUFFER);
+    server.tunnels.insert(request_id, tx);
+    server.event_streams.insert(request_id, rx);
+}
+
+/// Drop the channel of an event stream whose handler never took it
+pub(crate) fn abandon(server: &Server, request_id: &Uuid) {
+    server.tunnels.remove(request_id);
+    server.event_streams.remove(request_id);
+}
@@ -0,0 +1,163 @@
+// Delivering proxied requests to whatever serves them
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use rocket::http::Status;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::time::timeout;
+
+use super::{
+    ComputerId, HttpRequest, HttpResponse, RednetRpcDestination, RednetRpcMessage, Server,
+    tunnel::Tunnel,
+};
+
+/// Which transport the gateway proxies requests over
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TransportKind {
+    /// Rednet over the WebSocket connections of listeners linked at `/link`
+    #[default]
+    Websocket,
+    /// Echo each request back from the gateway itself, for trying out routes without any computers
+    Loopback,
+}
+
+/// Why a request couldn't be proxied, reported to the client in the JSON error body
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ProxyError {
+    /// No selectable listener serves the destination, even after waiting for one to connect
+    NoListener,
+    /// The chosen listener's queue is full, or every eligible listener is at its concurrency limit
+    ListenerBusy,
+    /// The listener disconnected before answering
+    ListenerDisconnected,
+    /// The listener answered with a response the gateway rejected, e.g. too large or failing its integrity check
+    InvalidResponse,
+    /// The listener didn't answer within the gateway timeout
+    Timeout,
+    /// The gateway itself failed
+    Internal,
+}
+
+impl ProxyError {
+    pub(crate) fn status(self) -> Status {
+        match self {
+            ProxyError::NoListener | ProxyError::ListenerBusy => Status::ServiceUnavailable,
+            ProxyError::ListenerDisconnected | ProxyError::InvalidResponse => Status::BadGateway,
+            ProxyError::Timeout => Status::GatewayTimeout,
+            ProxyError::Internal => Status::InternalServerError,
+        }
+    }
+}
+
+impl From<ProxyError> for Status {
+    fn from(error: ProxyError) -> Self {
+        error.status()
+    }
+}
+
+/// Carries a request to a backend and brings back its response
+#[rocket::async_trait]
+pub(crate) trait Transport: Send + Sync {
+    /// Deliver `message` and wait for the response, returning it with the ID of the computer that sent it.
+    /// A response the computer sends, whatever its status, is returned as is.
+    async fn send(
+        &self,
+        message: RednetRpcMessage<HttpRequest>,
+    ) -> Result<(ComputerId, HttpResponse), ProxyError>;
+
+    /// Send a copy of `request` to `dest` in the background, ignoring the outcome
+    fn mirror(&self, dest: RednetRpcDestination, request: HttpRequest);
+
+    /// Open a WebSocket tunnel to a computer serving `dest`, sending it the upgrade `request`
+    async fn open_tunnel(
+        &self,
+        dest: RednetRpcDestination,
+        request: HttpRequest,
+    ) -> Result<Tunnel, ProxyError>;
+}
+
+/// Sends requests to the listeners connected to the gateway over WebSocket
+pub(crate) struct WebSocketTransport {
+    pub(crate) server: Arc<Server>,
+}
+
+#[rocket::async_trait]
+impl Transport for WebSocketTransport {
+    async fn send(
+        &self,
+        message: RednetRpcMessage<HttpRequest>,
+    ) -> Result<(ComputerId, HttpResponse), ProxyError> {
+        let request_id = message.request_id;
+        let rx = self.server.new_request(message).await?;
+        let listener_id = rx.listener_id.clone();
+
+        let mut rx = std::pin::pin!(rx);
+        let gateway_timeout = Duration::from_secs(self.server.config.gateway_timeout as u64);
+        match timeout(gateway_timeout, rx.as_mut()).await {
+            // Still holding the receiver, so the timeout counts against the listener
+            Err(_) => {
+                self.server.time_out_request(&request_id, &listener_id);
+                Err(ProxyError::Timeout)
+            }
+            Ok(Err(e)) => Err(e),
+            Ok(Ok(resp)) => Ok((listener_id, resp)),
+        }
+    }
+
+    fn mirror(&self, dest: RednetRpcDestination, request: HttpRequest) {
+        self.server.mirror_request(dest, request);
+    }
+
+    async fn open_tunnel(
+        &self,
+        dest: RednetRpcDestination,
+        request: HttpRequest,
+    ) -> Result<Tunnel, ProxyError> {
+        self.server.open_tunnel(dest, request).await
+    }
+}
+
+/// Stands in for the computer that answers loopback requests
+const LOOPBACK_ID: &str = "loopback";
+
+/// Answers every request with a 200 whose JSON body is the message a listener would have received
+pub(crate) struct LoopbackTransport;
+
+#[rocket::async_trait]
+impl Transport for LoopbackTransport {
+    async fn send(
+        &self,
+        message: RednetRpcMessage<HttpRequest>,
+    ) -> Result<(ComputerId, HttpResponse), ProxyError> {
+        let body = serde_json::to_string(&message).map_err(|_| ProxyError::Internal)?;
+
+        Ok((
+            LOOPBACK_ID.to_string(),
+            HttpResponse {
+                status: Status::Ok,
+                headers: HashMap::from([(
+                    "Content-Type".to_string(),
+                    vec!["application/json".to_string()],
+                )]),
+                body,
+                body_base64: false,
+                body_sha256: None,
+            },
+        ))
+    }
+
+    /// Mirrors would only be echoed back to nobody
+    fn mirror(&self, _dest: RednetRpcDestination, _request: HttpRequest) {}
+
+    /// Tunnels run over a listener's connection, which loopback has none of
+    async fn open_tunnel(
+        &self,
+        _dest: RednetRpcDestination,
+        _request: HttpRequest,
+    ) -> Result<Tunnel, ProxyError> {
+        Err(ProxyError::NoListener)
+    }
+}
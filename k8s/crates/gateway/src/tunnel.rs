@@ -0,0 +1,227 @@
+// WebSocket tunnelling between a client and a computer over rednet
+
+use std::sync::Arc;
+
+use rocket::futures::{SinkExt, StreamExt, channel::mpsc};
+use rocket_ws::{Message, stream::DuplexStream};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::{
+    ComputerId, HttpRequest, ListenerMessage, ListenerSender, PROTOCOL_VERSION,
+    RednetRpcDestination, RednetRpcMessage, Server, transport::ProxyError,
+};
+
+/// Frames buffered per tunnel before a client that isn't keeping up has its tunnel closed
+const TUNNEL_BUFFER: usize = 64;
+
+/// A frame of a tunnelled WebSocket, carried in a [`RednetRpcMessage`] whose request ID identifies the tunnel
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum TunnelFrame {
+    /// Sent to the computer when a client connects, carrying the upgrade request
+    Open(HttpRequest),
+    Text(String),
+    Binary(Vec<u8>),
+    /// Sent by either side to tear down the tunnel
+    Close,
+}
+
+pub(crate) struct Tunnel {
+    server: Arc<Server>,
+    pub(crate) listener_id: ComputerId,
+    dest: RednetRpcDestination,
+    tunnel_id: Uuid,
+    listener: ListenerSender,
+    frames: mpsc::Receiver<TunnelFrame>,
+}
+
+impl Server {
+    pub(crate) async fn open_tunnel(
+        self: &Arc<Self>,
+        dest: RednetRpcDestination,
+        request: HttpRequest,
+    ) -> Result<Tunnel, ProxyError> {
+        let (listener_id, listener) = self.wait_for_listener(&dest, &request).await?;
+        let tunnel_id = Uuid::new_v4();
+
+        let (tx, rx) = mpsc::channel(TUNNEL_BUFFER);
+        self.tunnels.insert(tunnel_id, tx);
+
+        let mut tunnel = Tunnel {
+            server: Arc::clone(self),
+            listener_id,
+            dest,
+            tunnel_id,
+            listener,
+            frames: rx,
+        };
+        self.add_load(&tunnel.listener_id);
+
+        let open = tunnel.message(TunnelFrame::Open(request));
+        if let Err(_e) = tunnel.listener.send(open).await {
+            rocket::error!("Failed to open tunnel to listener (pipe closed)");
+            self.record_failure(&tunnel.listener_id);
+            return Err(ProxyError::ListenerDisconnected);
+        }
+
+        Ok(tunnel)
+    }
+}
+
+impl Tunnel {
+    fn message(&self, frame: TunnelFrame) -> ListenerMessage {
+        ListenerMessage::Tunnel(RednetRpcMessage {
+            v: PROTOCOL_VERSION,
+            dest: self.dest.clone(),
+            request_id: self.tunnel_id,
+            payload: frame,
+        })
+    }
+
+    /// Shuttle frames between the client and the computer until either side closes
+    pub(crate) async fn run(mut self, mut client: DuplexStream) -> rocket_ws::result::Result<()> {
+        loop {
+            tokio::select! {
+                frame = self.frames.next() => match frame {
+                    Some(TunnelFrame::Text(text)) => client.send(Message::Text(text)).await?,
+                    Some(TunnelFrame::Binary(data)) => client.send(Message::Binary(data)).await?,
+                    Some(TunnelFrame::Open(_)) => {}
+                    Some(TunnelFrame::Close) => {
+                        return client.send(Message::Close(None)).await;
+                    }
+                    // The gateway dropped the tunnel, so both ends need telling
+                    None => {
+                        let close = self.message(TunnelFrame::Close);
+                        let _ = self.listener.send(close).await;
+                        return client.send(Message::Close(None)).await;
+                    }
+                },
+                msg = client.next() => {
+                    let frame = match msg {
+                        Some(Ok(Message::Text(text))) => TunnelFrame::Text(text),
+                        Some(Ok(Message::Binary(data))) => TunnelFrame::Binary(data),
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                        // Pings and pongs are answered by the WebSocket layer itself
+                        Some(Ok(_)) => continue,
+                    };
+
+                    let message = self.message(frame);
+                    if self.listener.send(message).await.is_err() {
+                        rocket::warn!("Listener for tunnel {} went away", self.tunnel_id);
+                        return client.send(Message::Close(None)).await;
+                    }
+                }
+            }
+        }
+
+        let close = self.message(TunnelFrame::Close);
+        let _ = self.listener.send(close).await;
+        Ok(())
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        self.server.tunnels.remove(&self.tunnel_id);
+        self.server.remove_load(&self.listener_id);
+    }
+}
+
+/// Route a frame sent by a computer to the client end of its tunnel. Waiting on a slow client would
+/// stall every other request on the listener, so a tunnel whose buffer is full is closed instead.
+pub(crate) fn handle_frame(server: &Server, message: RednetRpcMessage<TunnelFrame>) {
+    let Some(mut tx) = server.tunnels.get_mut(&message.request_id) else {
+        rocket::warn!(
+            "Received frame for unknown tunnel ID: {}",
+            message.request_id
+        );
+        return;
+    };
+
+    // Send through the registered sender rather than a clone, since every clone of a futures sender
+    // gets a slot of its own beyond the buffer, and so would never find it full
+    let sent = tx.try_send(message.payload);
+    drop(tx);
+    if let Err(e) = sent {
+        if e.is_full() {
+            rocket::warn!(
+                "Closing tunnel {} whose client fell behind",
+                message.request_id
+            );
+        }
+        // The client's end sees the tunnel close once it has drained the frames already buffered
+        server.tunnels.remove(&message.request_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{config, connect};
+
+    async fn open(server: &Arc<Server>) -> Tunnel {
+        let dest = RednetRpcDestination::Anycast {
+            protocol: "ws".to_string(),
+        };
+        let request = HttpRequest {
+            method: rocket::http::Method::Get,
+            uri: rocket::http::uri::Origin::ROOT,
+            headers: Default::default(),
+            body: String::new(),
+            body_base64: false,
+            body_sha256: None,
+        };
+        server.open_tunnel(dest, request).await.unwrap()
+    }
+
+    fn frame(tunnel: &Tunnel) -> RednetRpcMessage<TunnelFrame> {
+        RednetRpcMessage {
+            v: PROTOCOL_VERSION,
+            dest: tunnel.dest.clone(),
+            request_id: tunnel.tunnel_id,
+            payload: TunnelFrame::Text("tick".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn client_keeping_up_keeps_its_tunnel() {
+        let server = Arc::new(Server::new(config()));
+        let _rx = connect(&server, "1");
+        let mut tunnel = open(&server).await;
+
+        for _ in 0..3 {
+            for _ in 0..TUNNEL_BUFFER {
+                handle_frame(&server, frame(&tunnel));
+            }
+            for _ in 0..TUNNEL_BUFFER {
+                assert!(tunnel.frames.try_next().unwrap().is_some());
+            }
+        }
+
+        assert!(server.tunnels.contains_key(&tunnel.tunnel_id));
+    }
+
+    #[tokio::test]
+    async fn tunnel_is_closed_once_its_client_falls_behind() {
+        let server = Arc::new(Server::new(config()));
+        let _rx = connect(&server, "1");
+        let mut tunnel = open(&server).await;
+
+        let mut sent = 0;
+        while server.tunnels.contains_key(&tunnel.tunnel_id) {
+            assert!(sent <= 2 * TUNNEL_BUFFER, "tunnel never closed");
+            handle_frame(&server, frame(&tunnel));
+            sent += 1;
+        }
+        assert!(sent > TUNNEL_BUFFER);
+
+        // The client still gets what was buffered before the tunnel closed, then the end
+        let mut buffered = 0;
+        while let Some(frame) = tunnel.frames.try_next().unwrap() {
+            assert!(matches!(frame, TunnelFrame::Text(_)));
+            buffered += 1;
+        }
+        assert!(buffered >= TUNNEL_BUFFER);
+    }
+}
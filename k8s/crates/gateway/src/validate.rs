@@ -0,0 +1,204 @@
+// Diagnosing problems in the loaded route table
+
+use std::sync::Arc;
+
+use rocket::{State, get, serde::json::Json};
+use serde::Serialize;
+
+use super::{Admin, HttpOverRednetRoute, RednetConfig, RednetRpcDestination, Server};
+
+/// Shown in place of `request_headers_set` values, which may hold credentials for the backend
+const REDACTED: &str = "<redacted>";
+
+#[derive(Debug, Serialize)]
+pub(crate) struct RouteTableReport {
+    /// The routes in matching order, including the catch-all for `default_backend`, with the values
+    /// of headers they set on requests redacted
+    routes: Vec<HttpOverRednetRoute>,
+    problems: Vec<RouteProblem>,
+}
+
+/// Something wrong with a route, referring to routes by their index in the report
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum RouteProblem {
+    /// Two routes with the same prefix and host, so only the first is ever used
+    DuplicatePrefix {
+        prefix: String,
+        first: usize,
+        duplicate: usize,
+    },
+    /// A route can never match because an earlier route matches every request it would
+    Shadowed { route: usize, by: usize },
+    /// No connected listener serves a route's backend, so requests to it wait and then fail
+    NoListener {
+        route: usize,
+        backend: RednetRpcDestination,
+    },
+}
+
+/// Report the loaded routes and any problems with them
+#[get("/routes")]
+pub(crate) fn route_table(
+    _admin: Admin,
+    rednet: RednetConfig,
+    server: &State<Arc<Server>>,
+) -> Json<RouteTableReport> {
+    let mut routes = rednet.routes;
+    let mut problems = overlaps(&routes);
+
+    for (index, route) in routes.iter().enumerate() {
+        for weighted in &route.backends {
+            let served = server
+                .listeners
+                .iter()
+                .any(|listener| server.serves(listener.key(), &weighted.backend));
+            if !served {
+                problems.push(RouteProblem::NoListener {
+                    route: index,
+                    backend: weighted.backend.clone(),
+                });
+            }
+        }
+    }
+
+    for route in &mut routes {
+        for value in route.request_headers_set.values_mut() {
+            *value = REDACTED.to_string();
+        }
+    }
+
+    Json(RouteTableReport { routes, problems })
+}
+
+/// Routes duplicating or shadowed by an earlier one. Every earlier route is checked, so a duplicate
+/// behind a catch-all is reported as both: each route gets its first duplicate and first shadower.
+fn overlaps(routes: &[HttpOverRednetRoute]) -> Vec<RouteProblem> {
+    let mut problems = vec![];
+    for (index, route) in routes.iter().enumerate() {
+        let (duplicates, shadows): (Vec<_>, Vec<_>) = routes[..index]
+            .iter()
+            .enumerate()
+            .filter(|(_, earlier)| covers(earlier, route))
+            .partition(|(_, earlier)| is_duplicate(earlier, route));
+
+        if let Some(&(first, _)) = duplicates.first() {
+            problems.push(RouteProblem::DuplicatePrefix {
+                prefix: route.prefix.display().to_string(),
+                first,
+                duplicate: index,
+            });
+        }
+        if let Some(&(by, _)) = shadows.first() {
+            problems.push(RouteProblem::Shadowed { route: index, by });
+        }
+    }
+    problems
+}
+
+/// Whether two routes have the same prefix and host, so match exactly the same requests
+fn is_duplicate(a: &HttpOverRednetRoute, b: &HttpOverRednetRoute) -> bool {
+    let same_host = match (&a.host, &b.host) {
+        (None, None) => true,
+        (Some(a), Some(b)) => a.eq_ignore_ascii_case(b),
+        _ => false,
+    };
+    same_host && a.prefix == b.prefix
+}
+
+/// Whether `outer` matches every request `inner` does. Wildcard hosts are only compared literally,
+/// so a `*.` host shadowing a more specific one goes unreported.
+fn covers(outer: &HttpOverRednetRoute, inner: &HttpOverRednetRoute) -> bool {
+    let host_covered = match (&outer.host, &inner.host) {
+        (None, _) => true,
+        (Some(outer), Some(inner)) => outer.eq_ignore_ascii_case(inner),
+        (Some(_), None) => false,
+    };
+
+    let (Some(outer), Some(inner)) = (outer.prefix.to_str(), inner.prefix.to_str()) else {
+        return false;
+    };
+    let prefix_covered = outer == "/"
+        || inner
+            .strip_prefix(outer)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'));
+
+    host_covered && prefix_covered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routes(yaml: &str) -> Vec<HttpOverRednetRoute> {
+        serde_yaml_ng::from_str::<RednetConfig>(yaml)
+            .unwrap()
+            .validate()
+            .unwrap()
+            .routes
+    }
+
+    fn problems(yaml: &str) -> serde_json::Value {
+        serde_json::to_value(overlaps(&routes(yaml))).unwrap()
+    }
+
+    const BACKEND: &str = "  backend:\n    anycast:\n      protocol: api\n";
+
+    fn config(routes: &[&str]) -> String {
+        let mut yaml = "routes:\n".to_string();
+        for route in routes {
+            yaml.push_str(&format!("- {route}\n{BACKEND}"));
+        }
+        yaml
+    }
+
+    #[test]
+    fn reports_a_duplicate_behind_a_catch_all() {
+        let yaml = config(&["prefix: /", "prefix: /api", "prefix: /api"]);
+
+        assert_eq!(
+            problems(&yaml),
+            serde_json::json!([
+                { "kind": "shadowed", "route": 1, "by": 0 },
+                { "kind": "duplicate_prefix", "prefix": "/api", "first": 1, "duplicate": 2 },
+                { "kind": "shadowed", "route": 2, "by": 0 },
+            ])
+        );
+    }
+
+    #[test]
+    fn looks_past_earlier_routes_that_dont_cover() {
+        // The host-specific route doesn't cover /api/v1 for every host, but the bare /api does
+        let yaml = config(&[
+            "prefix: /api\n  host: example.com",
+            "prefix: /api",
+            "prefix: /api/v1",
+        ]);
+
+        assert_eq!(
+            problems(&yaml),
+            serde_json::json!([
+                { "kind": "shadowed", "route": 2, "by": 1 },
+            ])
+        );
+    }
+
+    #[test]
+    fn same_prefix_on_another_host_is_shadowed_not_duplicated() {
+        let yaml = config(&["prefix: /api", "prefix: /api\n  host: example.com"]);
+
+        assert_eq!(
+            problems(&yaml),
+            serde_json::json!([
+                { "kind": "shadowed", "route": 1, "by": 0 },
+            ])
+        );
+    }
+
+    #[test]
+    fn disjoint_routes_have_no_problems() {
+        let yaml = config(&["prefix: /api", "prefix: /apis", "prefix: /web"]);
+
+        assert_eq!(problems(&yaml), serde_json::json!([]));
+    }
+}